@@ -0,0 +1,38 @@
+//! Compares instruction throughput with and without the decoded-instruction
+//! cache: `cargo bench --bench decode`.
+
+use chip8::cpu::Cpu;
+use std::time::Instant;
+
+const INSTRUCTIONS: u64 = 20_000_000;
+
+fn run(rom: &[u8], decode_cache: bool) -> f64 {
+    let mut cpu = Cpu::new();
+    cpu.load_rom(rom).unwrap();
+    // no vblank waits, so that every cycle executes an instruction
+    cpu.quirks = Default::default();
+    cpu.decode_cache = decode_cache;
+
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS {
+        cpu.emulate_cycle();
+    }
+    INSTRUCTIONS as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    for (name, rom) in &[
+        ("sierpinski.ch8", &include_bytes!("../sierpinski.ch8")[..]),
+        ("c8_test.c8", &include_bytes!("../c8_test.c8")[..]),
+    ] {
+        let uncached = run(rom, false);
+        let cached = run(rom, true);
+        println!(
+            "{:<16} decode every cycle {:>7.1} MIPS, cached {:>7.1} MIPS ({:+.0}%)",
+            name,
+            uncached / 1e6,
+            cached / 1e6,
+            (cached / uncached - 1.0) * 100.0
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Runs fuzzer-chosen ROMs and machine states on `Cpu` and on the reference
+//! model from `tests/reference`, failing on a panic or any difference.
+//!
+//! Input layout: a quirk profile byte, V0-VF, I (big endian), SP, the held
+//! keys as a 16 bit mask, then the ROM loaded at 0x200.
+
+#![no_main]
+
+#[path = "../../tests/reference/mod.rs"]
+mod reference;
+
+use chip8::cpu::Cpu;
+use chip8::quirks::{Platform, Quirks};
+use libfuzzer_sys::fuzz_target;
+use reference::{difference, step_both, Machine};
+
+const HEADER: usize = 22;
+const FRAMES: usize = 20;
+const CYCLES_PER_FRAME: usize = 50;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < HEADER {
+        return;
+    }
+    let quirks = match data[0] % 4 {
+        0 => Quirks::default(),
+        1 => Platform::CosmacVip.quirks(),
+        2 => Platform::SuperChip.quirks(),
+        _ => Platform::XoChip.quirks(),
+    };
+
+    let mut model = Machine::new(quirks);
+    model.v.copy_from_slice(&data[1..17]);
+    model.i = u16::from_be_bytes([data[17], data[18]]);
+    model.sp = data[19] % 16;
+    let keys = u16::from_be_bytes([data[20], data[21]]);
+    for (k, key) in model.keys.iter_mut().enumerate() {
+        *key = keys >> k & 1 != 0;
+    }
+    let rom = &data[HEADER..data.len().min(HEADER + 4096 - 0x200)];
+    model.memory[0x200..0x200 + rom.len()].copy_from_slice(rom);
+
+    let mut cpu = Cpu::new();
+    model.store(&mut cpu);
+    for _ in 0..FRAMES {
+        for _ in 0..CYCLES_PER_FRAME {
+            step_both(&mut cpu, &mut model);
+        }
+        cpu.vblank();
+        model.vblank();
+        if let Some(difference) = difference(&cpu, &model) {
+            panic!("{} after the vertical blank", difference);
+        }
+    }
+});
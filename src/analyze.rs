@@ -0,0 +1,299 @@
+use crate::cpu::PROGRAM_START;
+use crate::disasm::{reachable, word};
+use crate::quirks::{Platform, Quirks};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// How far past a `LD [I], Vx` or `LD Vx, [I]` to look for the next use of I.
+const I_LOOKAHEAD: usize = 8;
+
+/// What a static scan of the reachable code says about the platform a ROM
+/// was written for.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Analysis {
+    /// Number of reachable instructions.
+    pub instructions: usize,
+    /// SUPER-CHIP instructions used, with the address of first use.
+    pub schip: BTreeMap<&'static str, usize>,
+    /// XO-CHIP instructions used, with the address of first use.
+    pub xochip: BTreeMap<&'static str, usize>,
+    /// `SHR`/`SHL` with Vx != Vy, whose result depends on `shift_vy`.
+    pub shifts_with_vy: BTreeSet<usize>,
+    /// Those of `shifts_with_vy` whose Vy no reachable instruction writes,
+    /// which only make sense if Vy is ignored.
+    pub shifts_ignoring_vy: BTreeSet<usize>,
+    /// `LD [I], Vx`/`LD Vx, [I]` followed by another use of I without
+    /// reloading it, which only works with `load_store_increment`.
+    pub relies_on_i_increment: BTreeSet<usize>,
+    pub platform: Platform,
+    pub quirks: Quirks,
+}
+
+fn schip_name(opcode: u16) -> Option<&'static str> {
+    Some(match opcode {
+        0x00C0..=0x00CF => "00CN scroll down",
+        0x00FB => "00FB scroll right",
+        0x00FC => "00FC scroll left",
+        0x00FD => "00FD exit",
+        0x00FE => "00FE lores",
+        0x00FF => "00FF hires",
+        _ if opcode & 0xF00F == 0xD000 => "DXY0 16x16 sprite",
+        _ if opcode & 0xF0FF == 0xF030 => "FX30 big font",
+        _ if opcode & 0xF0FF == 0xF075 => "FX75 save flags",
+        _ if opcode & 0xF0FF == 0xF085 => "FX85 load flags",
+        _ => return None,
+    })
+}
+
+fn xochip_name(opcode: u16) -> Option<&'static str> {
+    Some(match opcode {
+        0x00D0..=0x00DF => "00DN scroll up",
+        0xF000 => "F000 long I",
+        0xF002 => "F002 audio",
+        _ if opcode & 0xF00F == 0x5002 => "5XY2 save range",
+        _ if opcode & 0xF00F == 0x5003 => "5XY3 load range",
+        _ if opcode & 0xF0FF == 0xF001 => "FN01 plane",
+        _ if opcode & 0xF0FF == 0xF03A => "FX3A pitch",
+        _ => return None,
+    })
+}
+
+enum IUse {
+    Reload,
+    Read,
+    Unrelated,
+}
+
+/// The registers `opcode` can write, bit n for Vn.
+fn written(opcode: u16) -> u16 {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let up_to = |last: u16| ((2u32 << last) - 1) as u16;
+    match (opcode & 0xF000, opcode & 0xF00F, opcode & 0xF0FF) {
+        (0x6000, ..) | (0x7000, ..) | (0xC000, ..) => 1 << x,
+        (0x8000, _, _) => match opcode & 0xF {
+            0x0..=0x3 => 1 << x,
+            0x4..=0x7 | 0xE => 1 << x | 0x8000,
+            _ => 0,
+        },
+        (0xD000, ..) => 0x8000,
+        (_, _, 0xF007) | (_, _, 0xF00A) => 1 << x,
+        (_, _, 0xF065) | (_, _, 0xF085) => up_to(x),
+        (_, 0x5003, _) => up_to(x.max(y)) & !(up_to(x.min(y)) >> 1),
+        _ => 0,
+    }
+}
+
+fn i_use(opcode: u16) -> IUse {
+    match (opcode & 0xF000, opcode & 0xF0FF) {
+        (0xA000, _) | (_, 0xF000) | (_, 0xF029) | (_, 0xF030) => IUse::Reload,
+        (0xD000, _) | (_, 0xF01E) | (_, 0xF033) | (_, 0xF055) | (_, 0xF065) => IUse::Read,
+        _ => IUse::Unrelated,
+    }
+}
+
+/// Scans the code reachable from `PROGRAM_START` and recommends a platform
+/// and quirks for running it.
+pub fn analyze(rom: &[u8]) -> Analysis {
+    let (code, labels) = reachable(rom);
+    let mut analysis = Analysis {
+        instructions: code.len(),
+        schip: BTreeMap::new(),
+        xochip: BTreeMap::new(),
+        shifts_with_vy: BTreeSet::new(),
+        shifts_ignoring_vy: BTreeSet::new(),
+        relies_on_i_increment: BTreeSet::new(),
+        platform: Platform::CosmacVip,
+        quirks: Quirks::default(),
+    };
+
+    let written = code
+        .iter()
+        .fold(0, |regs, &addr| regs | written(word(rom, addr)));
+
+    for &addr in &code {
+        let opcode = word(rom, addr);
+        if let Some(name) = schip_name(opcode) {
+            analysis.schip.entry(name).or_insert(addr);
+        }
+        if let Some(name) = xochip_name(opcode) {
+            analysis.xochip.entry(name).or_insert(addr);
+        }
+
+        let x = (opcode & 0x0F00) >> 8;
+        let y = (opcode & 0x00F0) >> 4;
+        if (opcode & 0xF00F == 0x8006 || opcode & 0xF00F == 0x800E) && x != y {
+            analysis.shifts_with_vy.insert(addr);
+            if written & 1 << y == 0 {
+                analysis.shifts_ignoring_vy.insert(addr);
+            }
+        }
+
+        if opcode & 0xF0FF == 0xF055 || opcode & 0xF0FF == 0xF065 {
+            // follow the straight-line code after the access; a label means
+            // other paths join here and I could have been set by them
+            let mut next = addr + 2;
+            for _ in 0..I_LOOKAHEAD {
+                if !code.contains(&next) || labels.contains_key(&next) {
+                    break;
+                }
+                let opcode = word(rom, next);
+                match i_use(opcode) {
+                    IUse::Reload => break,
+                    IUse::Read => {
+                        analysis.relies_on_i_increment.insert(addr);
+                        break;
+                    }
+                    IUse::Unrelated => (),
+                }
+                if opcode & 0xF000 == 0x1000 || opcode & 0xF000 == 0x2000 || opcode == 0x00EE {
+                    break;
+                }
+                next += 2;
+            }
+        }
+    }
+
+    analysis.platform = if !analysis.xochip.is_empty() {
+        Platform::XoChip
+    } else if !analysis.schip.is_empty() {
+        Platform::SuperChip
+    } else {
+        Platform::CosmacVip
+    };
+    analysis.quirks = analysis.platform.quirks();
+    if !analysis.relies_on_i_increment.is_empty() {
+        analysis.quirks.load_store_increment = true;
+    }
+    // a shift from a register nothing sets can't mean to use it; otherwise
+    // assume Vy was set for the shift
+    if !analysis.shifts_ignoring_vy.is_empty() {
+        analysis.quirks.shift_vy = false;
+    } else if !analysis.shifts_with_vy.is_empty() {
+        analysis.quirks.shift_vy = true;
+    }
+    analysis
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "reachable instructions: {}", self.instructions)?;
+        for (set, name) in &[(&self.schip, "SUPER-CHIP"), (&self.xochip, "XO-CHIP")] {
+            for (op, addr) in set.iter() {
+                writeln!(f, "{} opcode {} first used at {:#05x}", name, op, addr)?;
+            }
+        }
+        if !self.shifts_with_vy.is_empty() {
+            writeln!(
+                f,
+                "{} shifts with Vx != Vy depend on shift-vy, first at {:#05x}",
+                self.shifts_with_vy.len(),
+                self.shifts_with_vy.iter().next().unwrap()
+            )?;
+        }
+        if !self.shifts_ignoring_vy.is_empty() {
+            writeln!(
+                f,
+                "{} of those shift from a Vy nothing writes, first at {:#05x}",
+                self.shifts_ignoring_vy.len(),
+                self.shifts_ignoring_vy.iter().next().unwrap()
+            )?;
+        }
+        if !self.relies_on_i_increment.is_empty() {
+            writeln!(
+                f,
+                "{} register loads/stores rely on I being incremented, first at {:#05x}",
+                self.relies_on_i_increment.len(),
+                self.relies_on_i_increment.iter().next().unwrap()
+            )?;
+        }
+        write!(f, "recommended: --platform {}", self.platform)?;
+        let base = self.platform.quirks();
+        if self.quirks != base {
+            write!(f, " --quirks {}", self.quirks.spec(&base))?;
+        }
+        writeln!(f)?;
+        if self.instructions == 0 {
+            writeln!(f, "warning: no code reachable from {:#05x}", PROGRAM_START)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::analyze;
+    use crate::asm::assemble;
+    use crate::quirks::Platform;
+
+    #[test]
+    fn detects_extensions() {
+        // hires, then an XO-CHIP long I; the long I's address word is data
+        let rom = assemble("dw 0x00FF, 0xF000, 0x0300\n loop: JP loop")
+            .unwrap()
+            .rom;
+        let analysis = analyze(&rom);
+        assert_eq!(analysis.schip.get("00FF hires"), Some(&0x200));
+        assert_eq!(analysis.xochip.get("F000 long I"), Some(&0x202));
+        assert_eq!(analysis.instructions, 3, "the address word is skipped");
+        assert_eq!(analysis.platform, Platform::XoChip);
+    }
+
+    #[test]
+    fn detects_quirk_patterns() {
+        let rom = assemble(
+            "
+                LD I, 0x300
+                LD [I], V1
+                LD V0, [I]
+                SHR V0, V1
+                SHL V2
+            loop:
+                JP loop
+            ",
+        )
+        .unwrap()
+        .rom;
+        let analysis = analyze(&rom);
+        assert_eq!(analysis.platform, Platform::CosmacVip);
+        assert_eq!(analysis.shifts_with_vy.len(), 1, "SHL V2 shifts in place");
+        assert!(analysis.relies_on_i_increment.contains(&0x202));
+        assert!(
+            !analysis.relies_on_i_increment.contains(&0x204),
+            "I is not used after"
+        );
+        assert!(analysis.quirks.load_store_increment);
+        assert!(
+            analysis.shifts_ignoring_vy.contains(&0x206),
+            "nothing writes V1"
+        );
+        assert!(!analysis.quirks.shift_vy);
+    }
+
+    #[test]
+    fn recommends_shift_quirk() {
+        // SUPER-CHIP shifts Vx in place, so a shift from V3 that is set
+        // first needs shift-vy turned on
+        let source = "
+                dw 0x00FF
+                LD V3, 4
+                SHR V0, V3
+            loop:
+                JP loop
+            ";
+        let analysis = analyze(&assemble(source).unwrap().rom);
+        assert_eq!(analysis.platform, Platform::SuperChip);
+        assert!(analysis.shifts_ignoring_vy.is_empty());
+        assert!(analysis
+            .to_string()
+            .contains("recommended: --platform schip --quirks shift-vy\n"));
+
+        // on the VIP a shift from V7, which is never set, means in place
+        let analysis = analyze(&assemble("SHL V0, V7\n loop: JP loop").unwrap().rom);
+        assert_eq!(analysis.platform, Platform::CosmacVip);
+        assert!(analysis.shifts_ignoring_vy.contains(&0x200));
+        assert!(analysis
+            .to_string()
+            .contains("recommended: --platform vip --quirks no-shift-vy\n"));
+    }
+}
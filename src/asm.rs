@@ -0,0 +1,777 @@
+use crate::cpu::PROGRAM_START;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Highest address a ROM may occupy.
+const MEMORY_END: usize = 0x1000;
+/// Guards against include cycles.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An assembled program: the ROM bytes and a listing showing the address and
+/// bytes produced by every source line.
+#[derive(Debug)]
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    pub listing: String,
+    /// Where the source line of every statement that produced bytes starts
+    /// in memory, in address order.
+    pub lines: Vec<SourceLine>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    pub address: u16,
+    pub file: String,
+    pub line: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assembles `source`. Includes are resolved relative to the working
+/// directory.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut lines = Vec::new();
+    read_lines(source, "<input>", Path::new("."), 0, &mut lines)?;
+    Assembler::new(lines).run()
+}
+
+/// Assembles the file at `path`. Includes are resolved relative to the file
+/// that includes them.
+pub fn assemble_file(path: &Path) -> Result<Assembly, AsmError> {
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut lines = Vec::new();
+    read_lines(&source, &path.display().to_string(), dir, 0, &mut lines)?;
+    Assembler::new(lines).run()
+}
+
+struct Line {
+    file: String,
+    number: usize,
+    text: String,
+}
+
+impl Line {
+    fn error(&self, column: usize, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: self.number,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+/// Splits `source` into lines, splicing in the contents of `include`s.
+fn read_lines(
+    source: &str,
+    file: &str,
+    dir: &Path,
+    depth: usize,
+    out: &mut Vec<Line>,
+) -> Result<(), AsmError> {
+    for (i, text) in source.lines().enumerate() {
+        let line = Line {
+            file: file.to_string(),
+            number: i + 1,
+            text: text.to_string(),
+        };
+        let tokens = tokenize(&line)?;
+        match tokens.as_slice() {
+            [Token {
+                kind: Kind::Ident(word),
+                ..
+            }, rest @ ..]
+                if word.eq_ignore_ascii_case("include") =>
+            {
+                let (name, column) = match rest {
+                    [Token {
+                        kind: Kind::Str(name),
+                        column,
+                    }] => (name, *column),
+                    _ => return Err(line.error(tokens[0].column, "expected `include \"file\"`")),
+                };
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(line.error(column, "includes nested too deeply"));
+                }
+                let path: PathBuf = dir.join(name);
+                let source = fs::read_to_string(&path).map_err(|e| {
+                    line.error(column, format!("cannot include {}: {}", path.display(), e))
+                })?;
+                let dir = path.parent().unwrap_or(dir).to_path_buf();
+                read_lines(&source, &path.display().to_string(), &dir, depth + 1, out)?;
+            }
+            _ => out.push(line),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Kind {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Comma,
+    Colon,
+    Equals,
+    Plus,
+    Minus,
+    LBracket,
+    RBracket,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Token {
+    kind: Kind,
+    column: usize,
+}
+
+fn tokenize(line: &Line) -> Result<Vec<Token>, AsmError> {
+    let chars: Vec<char> = line.text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let kind = match c {
+            ',' => Kind::Comma,
+            ':' => Kind::Colon,
+            '=' => Kind::Equals,
+            '+' => Kind::Plus,
+            '-' => Kind::Minus,
+            '[' => Kind::LBracket,
+            ']' => Kind::RBracket,
+            '"' => {
+                let start = i + 1;
+                let end = (start..chars.len())
+                    .find(|&j| chars[j] == '"')
+                    .ok_or_else(|| line.error(column, "unterminated string"))?;
+                i = end + 1;
+                tokens.push(Token {
+                    kind: Kind::Str(chars[start..end].iter().collect()),
+                    column,
+                });
+                continue;
+            }
+            '#' | '$' | '%' | '0'..='9' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let value = parse_number(&text)
+                    .ok_or_else(|| line.error(column, format!("invalid number `{}`", text)))?;
+                tokens.push(Token {
+                    kind: Kind::Number(value),
+                    column,
+                });
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                tokens.push(Token {
+                    kind: Kind::Ident(chars[start..i].iter().collect()),
+                    column,
+                });
+                continue;
+            }
+            _ => return Err(line.error(column, format!("unexpected character `{}`", c))),
+        };
+        tokens.push(Token { kind, column });
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let lower = text.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        (bin, 2)
+    } else if let Some(hex) = lower.strip_prefix('#').or_else(|| lower.strip_prefix('$')) {
+        (hex, 16)
+    } else if let Some(bin) = lower.strip_prefix('%') {
+        (bin, 2)
+    } else {
+        (lower.as_str(), 10)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    i64::from_str_radix(digits, radix).ok()
+}
+
+/// A sum of numbers and symbols, e.g. `sprites + 5`.
+#[derive(Clone, Debug)]
+struct Expr {
+    terms: Vec<(bool, Term)>, // (negated, term)
+    column: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+    Number(i64),
+    Symbol(String, usize),
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    V(u16),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    B,
+    Expr(Expr),
+    Str(String),
+}
+
+fn register(name: &str) -> Option<u16> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(d), None) | (Some('V'), Some(d), None) => d.to_digit(16).map(|d| d as u16),
+        _ => None,
+    }
+}
+
+fn parse_operand(line: &Line, tokens: &[Token]) -> Result<Operand, AsmError> {
+    let column = tokens[0].column;
+    match tokens {
+        [Token {
+            kind: Kind::LBracket,
+            ..
+        }, Token {
+            kind: Kind::Ident(i),
+            ..
+        }, Token {
+            kind: Kind::RBracket,
+            ..
+        }] if i.eq_ignore_ascii_case("i") => return Ok(Operand::IndirectI),
+        [Token {
+            kind: Kind::Str(s), ..
+        }] => return Ok(Operand::Str(s.clone())),
+        [Token {
+            kind: Kind::Ident(name),
+            ..
+        }] => {
+            if let Some(x) = register(name) {
+                return Ok(Operand::V(x));
+            }
+            match name.to_ascii_uppercase().as_str() {
+                "I" => return Ok(Operand::I),
+                "DT" => return Ok(Operand::Dt),
+                "ST" => return Ok(Operand::St),
+                "K" => return Ok(Operand::K),
+                "F" => return Ok(Operand::F),
+                "B" => return Ok(Operand::B),
+                _ => (),
+            }
+        }
+        _ => (),
+    }
+
+    let mut terms = Vec::new();
+    let mut negated = false;
+    let mut expect_term = true;
+    for token in tokens {
+        match (&token.kind, expect_term) {
+            (Kind::Minus, true) => negated = !negated,
+            (Kind::Plus, true) => (),
+            (Kind::Number(n), true) => {
+                terms.push((negated, Term::Number(*n)));
+                expect_term = false;
+            }
+            (Kind::Ident(name), true) => {
+                if register(name).is_some() {
+                    return Err(line.error(token.column, "register used in an expression"));
+                }
+                terms.push((negated, Term::Symbol(name.clone(), token.column)));
+                expect_term = false;
+            }
+            (Kind::Plus, false) => {
+                negated = false;
+                expect_term = true;
+            }
+            (Kind::Minus, false) => {
+                negated = true;
+                expect_term = true;
+            }
+            _ => return Err(line.error(token.column, "invalid operand")),
+        }
+    }
+    if expect_term {
+        let last = tokens.last().map_or(column, |t| t.column);
+        return Err(line.error(last, "incomplete expression"));
+    }
+    Ok(Operand::Expr(Expr { terms, column }))
+}
+
+enum Item {
+    Instruction {
+        mnemonic: String,
+        column: usize,
+        operands: Vec<Operand>,
+    },
+    Db(Vec<Operand>),
+    Dw(Vec<Expr>),
+}
+
+struct Statement {
+    line: usize, // index into Assembler::lines
+    address: usize,
+    size: usize,
+    item: Option<Item>,
+}
+
+struct Assembler {
+    lines: Vec<Line>,
+    symbols: HashMap<String, usize>,           // labels
+    constants: HashMap<String, (Expr, usize)>, // expression and defining line
+}
+
+impl Assembler {
+    fn new(lines: Vec<Line>) -> Assembler {
+        Assembler {
+            lines,
+            symbols: HashMap::new(),
+            constants: HashMap::new(),
+        }
+    }
+
+    fn run(mut self) -> Result<Assembly, AsmError> {
+        let statements = self.first_pass()?;
+        self.second_pass(&statements)
+    }
+
+    /// Parses every line, assigns addresses and records labels and constants.
+    fn first_pass(&mut self) -> Result<Vec<Statement>, AsmError> {
+        let mut statements = Vec::new();
+        let mut address = PROGRAM_START as usize;
+
+        for index in 0..self.lines.len() {
+            let owned = tokenize(&self.lines[index])?;
+            let mut tokens = &owned[..];
+
+            if let [Token {
+                kind: Kind::Ident(name),
+                column,
+            }, Token {
+                kind: Kind::Colon, ..
+            }, rest @ ..] = tokens
+            {
+                self.define(index, name, *column)?;
+                self.symbols.insert(name.clone(), address);
+                tokens = rest;
+            }
+
+            let line = &self.lines[index];
+            let item = match tokens {
+                [] => None,
+                [Token {
+                    kind: Kind::Ident(name),
+                    column,
+                }, Token { kind, .. }, rest @ ..]
+                    if *kind == Kind::Equals
+                        || matches!(kind, Kind::Ident(equ) if equ.eq_ignore_ascii_case("equ")) =>
+                {
+                    if rest.is_empty() {
+                        return Err(line.error(*column, "constant needs a value"));
+                    }
+                    let expr = match parse_operand(line, rest)? {
+                        Operand::Expr(expr) => expr,
+                        _ => return Err(line.error(rest[0].column, "constant must be a number")),
+                    };
+                    let name = name.clone();
+                    self.define(index, &name, *column)?;
+                    self.constants.insert(name, (expr, index));
+                    None
+                }
+                [Token {
+                    kind: Kind::Ident(mnemonic),
+                    column,
+                }, rest @ ..] => {
+                    let parts = split_operands(line, rest)?;
+                    let operands = parts
+                        .iter()
+                        .map(|tokens| parse_operand(line, tokens))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Some(match mnemonic.to_ascii_uppercase().as_str() {
+                        "DB" => {
+                            for (operand, part) in operands.iter().zip(&parts) {
+                                if !matches!(operand, Operand::Expr(_) | Operand::Str(_)) {
+                                    return Err(line
+                                        .error(part[0].column, "`db` takes numbers and strings"));
+                                }
+                            }
+                            Item::Db(operands)
+                        }
+                        "DW" => Item::Dw(
+                            operands
+                                .into_iter()
+                                .zip(&parts)
+                                .map(|(operand, part)| match operand {
+                                    Operand::Expr(expr) => Ok(expr),
+                                    _ => Err(line.error(part[0].column, "`dw` takes numbers")),
+                                })
+                                .collect::<Result<_, _>>()?,
+                        ),
+                        _ => Item::Instruction {
+                            mnemonic: mnemonic.to_ascii_uppercase(),
+                            column: *column,
+                            operands,
+                        },
+                    })
+                }
+                [token, ..] => return Err(line.error(token.column, "expected a mnemonic")),
+            };
+
+            let size = match &item {
+                None => 0,
+                Some(Item::Instruction { .. }) => 2,
+                Some(Item::Dw(words)) => 2 * words.len(),
+                Some(Item::Db(bytes)) => bytes
+                    .iter()
+                    .map(|b| match b {
+                        Operand::Str(s) => s.len(),
+                        _ => 1,
+                    })
+                    .sum(),
+            };
+            if address + size > MEMORY_END {
+                return Err(line.error(1, "program does not fit in memory"));
+            }
+            statements.push(Statement {
+                line: index,
+                address,
+                size,
+                item,
+            });
+            address += size;
+        }
+
+        Ok(statements)
+    }
+
+    fn define(&self, index: usize, name: &str, column: usize) -> Result<(), AsmError> {
+        let line = &self.lines[index];
+        if register(name).is_some()
+            || ["I", "DT", "ST", "K", "F", "B"].contains(&name.to_ascii_uppercase().as_str())
+        {
+            return Err(line.error(column, format!("`{}` is a reserved name", name)));
+        }
+        if self.symbols.contains_key(name) || self.constants.contains_key(name) {
+            return Err(line.error(column, format!("`{}` is already defined", name)));
+        }
+        Ok(())
+    }
+
+    /// Encodes every statement now that all symbols are known.
+    fn second_pass(&self, statements: &[Statement]) -> Result<Assembly, AsmError> {
+        let mut rom = Vec::new();
+        let mut listing = String::new();
+        let mut lines = Vec::new();
+
+        for statement in statements {
+            let line = &self.lines[statement.line];
+            if statement.size > 0 {
+                lines.push(SourceLine {
+                    address: statement.address as u16,
+                    file: line.file.clone(),
+                    line: line.number,
+                });
+            }
+            let bytes = match &statement.item {
+                None => Vec::new(),
+                Some(Item::Instruction {
+                    mnemonic,
+                    column,
+                    operands,
+                }) => {
+                    let opcode = self.encode(line, mnemonic, *column, operands)?;
+                    vec![(opcode >> 8) as u8, opcode as u8]
+                }
+                Some(Item::Dw(words)) => {
+                    let mut bytes = Vec::new();
+                    for word in words {
+                        let value = self.eval(line, word, 0)?;
+                        if !(-0x8000..=0xFFFF).contains(&value) {
+                            return Err(line.error(word.column, "word out of range"));
+                        }
+                        bytes.push((value >> 8) as u8);
+                        bytes.push(value as u8);
+                    }
+                    bytes
+                }
+                Some(Item::Db(operands)) => {
+                    let mut bytes = Vec::new();
+                    for operand in operands {
+                        match operand {
+                            Operand::Str(s) => bytes.extend(s.bytes()),
+                            Operand::Expr(expr) => bytes.push(self.byte(line, expr)?),
+                            _ => unreachable!("checked in the first pass"),
+                        }
+                    }
+                    bytes
+                }
+            };
+            debug_assert_eq!(bytes.len(), statement.size);
+
+            let mut chunks = bytes.chunks(4);
+            let first = chunks.next().unwrap_or(&[]);
+            listing += &format!(
+                "{:03x}: {:<8}  {}\n",
+                statement.address,
+                hex(first),
+                line.text.trim_end()
+            );
+            for (i, chunk) in chunks.enumerate() {
+                listing += &format!("{:03x}: {}\n", statement.address + 4 * (i + 1), hex(chunk));
+            }
+            rom.extend(bytes);
+        }
+
+        Ok(Assembly {
+            rom,
+            listing,
+            lines,
+        })
+    }
+
+    fn eval(&self, line: &Line, expr: &Expr, depth: usize) -> Result<i64, AsmError> {
+        let mut value = 0;
+        for (negated, term) in &expr.terms {
+            let term = match term {
+                Term::Number(n) => *n,
+                Term::Symbol(name, column) => {
+                    if let Some(address) = self.symbols.get(name) {
+                        *address as i64
+                    } else if let Some((expr, index)) = self.constants.get(name) {
+                        if depth > self.constants.len() {
+                            return Err(line.error(
+                                *column,
+                                format!("`{}` is defined in terms of itself", name),
+                            ));
+                        }
+                        self.eval(&self.lines[*index], expr, depth + 1)?
+                    } else {
+                        return Err(line.error(*column, format!("undefined symbol `{}`", name)));
+                    }
+                }
+            };
+            value += if *negated { -term } else { term };
+        }
+        Ok(value)
+    }
+
+    fn byte(&self, line: &Line, expr: &Expr) -> Result<u8, AsmError> {
+        match self.eval(line, expr, 0)? {
+            value @ -0x80..=0xFF => Ok(value as u8),
+            value => Err(line.error(expr.column, format!("{} does not fit in a byte", value))),
+        }
+    }
+
+    fn addr(&self, line: &Line, expr: &Expr) -> Result<u16, AsmError> {
+        match self.eval(line, expr, 0)? {
+            value @ 0..=0xFFF => Ok(value as u16),
+            value => Err(line.error(expr.column, format!("address {:#x} out of range", value))),
+        }
+    }
+
+    fn nibble(&self, line: &Line, expr: &Expr) -> Result<u16, AsmError> {
+        match self.eval(line, expr, 0)? {
+            value @ 0..=0xF => Ok(value as u16),
+            value => Err(line.error(expr.column, format!("{} does not fit in a nibble", value))),
+        }
+    }
+
+    fn encode(
+        &self,
+        line: &Line,
+        mnemonic: &str,
+        column: usize,
+        operands: &[Operand],
+    ) -> Result<u16, AsmError> {
+        use Operand::*;
+
+        let xy = |op: u16, x: u16, y: u16| op | x << 8 | y << 4;
+
+        Ok(match (mnemonic, operands) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SYS", [Expr(a)]) => self.addr(line, a)?,
+            ("JP", [Expr(a)]) => 0x1000 | self.addr(line, a)?,
+            ("JP", [V(0), Expr(a)]) => 0xB000 | self.addr(line, a)?,
+            ("CALL", [Expr(a)]) => 0x2000 | self.addr(line, a)?,
+            ("SE", [V(x), Expr(b)]) => 0x3000 | x << 8 | self.byte(line, b)? as u16,
+            ("SNE", [V(x), Expr(b)]) => 0x4000 | x << 8 | self.byte(line, b)? as u16,
+            ("SE", [V(x), V(y)]) => xy(0x5000, *x, *y),
+            ("LD", [V(x), Expr(b)]) => 0x6000 | x << 8 | self.byte(line, b)? as u16,
+            ("ADD", [V(x), Expr(b)]) => 0x7000 | x << 8 | self.byte(line, b)? as u16,
+            ("LD", [V(x), V(y)]) => xy(0x8000, *x, *y),
+            ("OR", [V(x), V(y)]) => xy(0x8001, *x, *y),
+            ("AND", [V(x), V(y)]) => xy(0x8002, *x, *y),
+            ("XOR", [V(x), V(y)]) => xy(0x8003, *x, *y),
+            ("ADD", [V(x), V(y)]) => xy(0x8004, *x, *y),
+            ("SUB", [V(x), V(y)]) => xy(0x8005, *x, *y),
+            ("SHR", [V(x)]) => xy(0x8006, *x, *x),
+            ("SHR", [V(x), V(y)]) => xy(0x8006, *x, *y),
+            ("SUBN", [V(x), V(y)]) => xy(0x8007, *x, *y),
+            ("SHL", [V(x)]) => xy(0x800E, *x, *x),
+            ("SHL", [V(x), V(y)]) => xy(0x800E, *x, *y),
+            ("SNE", [V(x), V(y)]) => xy(0x9000, *x, *y),
+            ("LD", [I, Expr(a)]) => 0xA000 | self.addr(line, a)?,
+            ("RND", [V(x), Expr(b)]) => 0xC000 | x << 8 | self.byte(line, b)? as u16,
+            ("DRW", [V(x), V(y), Expr(n)]) => xy(0xD000, *x, *y) | self.nibble(line, n)?,
+            ("SKP", [V(x)]) => 0xE09E | x << 8,
+            ("SKNP", [V(x)]) => 0xE0A1 | x << 8,
+            ("LD", [V(x), Dt]) => 0xF007 | x << 8,
+            ("LD", [V(x), K]) => 0xF00A | x << 8,
+            ("LD", [Dt, V(x)]) => 0xF015 | x << 8,
+            ("LD", [St, V(x)]) => 0xF018 | x << 8,
+            ("ADD", [I, V(x)]) => 0xF01E | x << 8,
+            ("LD", [F, V(x)]) => 0xF029 | x << 8,
+            ("LD", [B, V(x)]) => 0xF033 | x << 8,
+            ("LD", [IndirectI, V(x)]) => 0xF055 | x << 8,
+            ("LD", [V(x), IndirectI]) => 0xF065 | x << 8,
+            _ => {
+                const KNOWN: &[&str] = &[
+                    "CLS", "RET", "SYS", "JP", "CALL", "SE", "SNE", "LD", "ADD", "OR", "AND",
+                    "XOR", "SUB", "SHR", "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP",
+                ];
+                return Err(if KNOWN.contains(&mnemonic) {
+                    line.error(column, format!("invalid operands for `{}`", mnemonic))
+                } else {
+                    line.error(column, format!("unknown mnemonic `{}`", mnemonic))
+                });
+            }
+        })
+    }
+}
+
+/// Splits the tokens after a mnemonic at top-level commas.
+fn split_operands<'a>(line: &Line, tokens: &'a [Token]) -> Result<Vec<&'a [Token]>, AsmError> {
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    let operands: Vec<&[Token]> = tokens.split(|t| t.kind == Kind::Comma).collect();
+    for (i, operand) in operands.iter().enumerate() {
+        if operand.is_empty() {
+            // point at the offending comma
+            let commas: Vec<&Token> = tokens.iter().filter(|t| t.kind == Kind::Comma).collect();
+            let column = commas[i.min(commas.len() - 1)].column;
+            return Err(line.error(column, "missing operand"));
+        }
+    }
+    Ok(operands)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, AsmError};
+    use crate::disasm::mnemonic;
+
+    #[test]
+    fn assembles_labels_and_data() {
+        let source = "
+            SPRITE_H equ 2
+            start:  LD I, sprite      ; point at the data
+                    DRW V0, V1, SPRITE_H
+                    JP start
+            sprite: db 0b11000000, $C0
+                    dw start + 2
+        ";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(
+            assembly.rom,
+            vec![0xA2, 0x06, 0xD0, 0x12, 0x12, 0x00, 0xC0, 0xC0, 0x02, 0x02]
+        );
+        let data = assembly.listing.lines().nth(5).unwrap();
+        assert!(
+            data.starts_with("206: c0c0"),
+            "listing shows the address and bytes"
+        );
+        assert!(
+            data.ends_with("sprite: db 0b11000000, $C0"),
+            "listing shows the source"
+        );
+        let line = &assembly.lines[3];
+        assert_eq!((line.address, line.line), (0x206, 6), "source lines");
+    }
+
+    #[test]
+    fn round_trips_disassembly() {
+        for opcode in 0..=0xFFFFu16 {
+            let rom = assemble(&mnemonic(opcode)).unwrap().rom;
+            assert_eq!(rom, opcode.to_be_bytes(), "{:#06x}", opcode);
+        }
+    }
+
+    #[test]
+    fn errors_carry_position() {
+        assert_eq!(
+            assemble("  CLS\n  LD V1, 300").unwrap_err(),
+            AsmError {
+                file: "<input>".to_string(),
+                line: 2,
+                column: 10,
+                message: "300 does not fit in a byte".to_string(),
+            }
+        );
+        let error = assemble("JP nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 4));
+        assert_eq!(error.message, "undefined symbol `nowhere`");
+
+        let error = assemble("  MOV V1, V2").unwrap_err();
+        assert_eq!(error.to_string(), "<input>:1:3: unknown mnemonic `MOV`");
+    }
+}
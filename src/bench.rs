@@ -0,0 +1,129 @@
+//! Headless throughput measurement for the `bench` command.
+
+use crate::cpu::Cpu;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Time spent in each part of `Cpu::emulate_cycle`, collected while
+/// `Cpu::timings` is set. Measuring costs a clock read per instruction, so
+/// the split is only meaningful relative to the other parts.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    pub decode: Duration,
+    pub draw: Duration,
+    pub execute: Duration,
+}
+
+/// How long a benchmark runs for.
+#[derive(Clone, Copy, Debug)]
+pub enum Limit {
+    Frames(u64),
+    Instructions(u64),
+}
+
+pub struct Report {
+    pub instructions: u64,
+    pub frames: u64,
+    pub elapsed: Duration,
+    /// From a second, instrumented run.
+    pub timings: Timings,
+    pub instrumented_elapsed: Duration,
+}
+
+fn run_until(cpu: &mut Cpu, cycles_per_frame: usize, limit: Limit) -> Duration {
+    let start = Instant::now();
+    loop {
+        let done = match limit {
+            Limit::Frames(frames) => cpu.frame >= frames,
+            Limit::Instructions(instructions) => cpu.cycles >= instructions,
+        };
+        if done {
+            break;
+        }
+        cpu.run_frame(cycles_per_frame);
+    }
+    start.elapsed()
+}
+
+/// Runs a machine from `machine` as fast as possible until `limit`, then
+/// runs a second one with timing instrumentation for the breakdown.
+pub fn run(machine: impl Fn() -> Cpu, cycles_per_frame: usize, limit: Limit) -> Report {
+    let mut cpu = machine();
+    let elapsed = run_until(&mut cpu, cycles_per_frame, limit);
+
+    let mut instrumented = machine();
+    instrumented.timings = Some(Timings::default());
+    let instrumented_elapsed = run_until(&mut instrumented, cycles_per_frame, limit);
+
+    Report {
+        instructions: cpu.cycles,
+        frames: cpu.frame,
+        elapsed,
+        timings: instrumented.timings.unwrap(),
+        instrumented_elapsed,
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+        writeln!(f, "instructions: {} in {:.3} s", self.instructions, seconds)?;
+        writeln!(f, "frames:       {}", self.frames)?;
+        writeln!(
+            f,
+            "speed:        {:.2} M instructions/s, {:.0} frames/s ({:.0}x real time)",
+            self.instructions as f64 / seconds / 1e6,
+            self.frames as f64 / seconds,
+            self.frames as f64 / seconds / 60.0
+        )?;
+
+        let total = self
+            .instrumented_elapsed
+            .as_secs_f64()
+            .max(f64::MIN_POSITIVE);
+        let measured = self.timings.decode + self.timings.draw + self.timings.execute;
+        let other = self.instrumented_elapsed.saturating_sub(measured);
+        writeln!(f, "time split (instrumented run):")?;
+        for (name, time) in &[
+            ("decode", self.timings.decode),
+            ("draw", self.timings.draw),
+            ("execute", self.timings.execute),
+            ("other", other),
+        ] {
+            writeln!(
+                f,
+                "  {:<8} {:5.1}%",
+                name,
+                time.as_secs_f64() / total * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Limit};
+    use crate::cpu::Cpu;
+
+    fn machine() -> Cpu {
+        let mut cpu = Cpu::new();
+        // DRW V0, V0, 5; JP 0x200
+        cpu.load_rom(&[0xD0, 0x05, 0x12, 0x00]).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn stops_at_limit() {
+        let report = run(machine, 10, Limit::Frames(3));
+        assert_eq!((report.frames, report.instructions), (3, 30));
+        assert!(report.timings.draw > Default::default());
+
+        let report = run(machine, 10, Limit::Instructions(25));
+        assert_eq!(
+            (report.frames, report.instructions),
+            (3, 30),
+            "whole frames"
+        );
+    }
+}
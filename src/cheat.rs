@@ -0,0 +1,144 @@
+//! Cheat search: narrowing memory down to the bytes that hold a game's score
+//! or lives by comparing snapshots taken as the game runs. The bytes found
+//! can then be frozen through `Cpu::freezes`.
+
+use crate::hexdump::parse_hex;
+
+/// How a byte must relate to its value in the previous snapshot to stay a
+/// candidate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compare {
+    Equal(u8),
+    NotEqual(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Compare {
+    /// Parses `= BYTE`, `!= BYTE`, `changed`, `unchanged`, `inc` or `dec`,
+    /// with `+` and `-` short for the last two.
+    pub fn parse(args: &[&str]) -> Result<Compare, String> {
+        let byte = || -> Result<u8, String> {
+            let word = args.get(1).ok_or("expected a byte")?;
+            match parse_hex(word)? {
+                n if n <= 0xFF => Ok(n as u8),
+                n => Err(format!("{:#x} does not fit in a byte", n)),
+            }
+        };
+        match args.first().copied() {
+            Some("=") | Some("==") | Some("eq") => Ok(Compare::Equal(byte()?)),
+            Some("!=") | Some("ne") => Ok(Compare::NotEqual(byte()?)),
+            Some("changed") => Ok(Compare::Changed),
+            Some("unchanged") => Ok(Compare::Unchanged),
+            Some("inc") | Some("+") => Ok(Compare::Increased),
+            Some("dec") | Some("-") => Ok(Compare::Decreased),
+            Some(word) => Err(format!("unknown comparison `{}`", word)),
+            None => Err("expected a comparison".to_string()),
+        }
+    }
+
+    fn matches(self, old: u8, new: u8) -> bool {
+        match self {
+            Compare::Equal(byte) => new == byte,
+            Compare::NotEqual(byte) => new != byte,
+            Compare::Changed => new != old,
+            Compare::Unchanged => new == old,
+            Compare::Increased => new > old,
+            Compare::Decreased => new < old,
+        }
+    }
+}
+
+/// The addresses still in the running, with memory as it was at the last
+/// snapshot and at the one before it.
+pub struct Search {
+    snapshot: Vec<u8>,
+    previous: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl Search {
+    /// Starts with every address as a candidate.
+    pub fn new(memory: &[u8]) -> Search {
+        Search {
+            snapshot: memory.to_vec(),
+            previous: memory.to_vec(),
+            candidates: (0..memory.len() as u16).collect(),
+        }
+    }
+
+    /// Drops the candidates whose byte in `memory` doesn't compare with the
+    /// snapshot, then takes a new snapshot. Returns how many are left.
+    pub fn filter(&mut self, memory: &[u8], compare: Compare) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            let addr = addr as usize;
+            compare.matches(snapshot[addr], memory[addr])
+        });
+        self.previous = std::mem::replace(&mut self.snapshot, memory.to_vec());
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    /// The candidate's byte before the last filter, the value it was
+    /// compared against.
+    pub fn previous(&self, addr: u16) -> u8 {
+        self.previous[addr as usize]
+    }
+}
+
+/// Parses a freeze given as `ADDR=BYTE`, both in hex.
+pub fn parse_freeze(spec: &str) -> Result<(u16, u8), String> {
+    let (addr, byte) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` is not ADDR=BYTE", spec))?;
+    let addr = parse_hex(addr)?;
+    if addr >= 0x1000 {
+        return Err(format!("{:#x} is outside memory", addr));
+    }
+    match parse_hex(byte)? {
+        byte if byte <= 0xFF => Ok((addr as u16, byte as u8)),
+        byte => Err(format!("{:#x} does not fit in a byte", byte)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_freeze, Compare, Search};
+
+    #[test]
+    fn narrows_down_to_a_counter() {
+        let mut memory = [7u8; 16];
+        let mut search = Search::new(&memory);
+
+        // lives at 3 go from 3 to 2 while 9 is a timer that keeps rising
+        memory[3] = 3;
+        memory[9] = 1;
+        assert_eq!(search.filter(&memory, Compare::Changed), 2);
+        memory[3] = 2;
+        memory[9] = 2;
+        assert_eq!(search.filter(&memory, Compare::Decreased), 1);
+        assert_eq!(search.candidates(), &[3]);
+        assert_eq!(search.previous(3), 3);
+
+        assert_eq!(search.filter(&memory, Compare::Equal(2)), 1);
+        assert_eq!(search.filter(&memory, Compare::NotEqual(2)), 0);
+    }
+
+    #[test]
+    fn parses_comparisons_and_freezes() {
+        assert_eq!(Compare::parse(&["=", "0x1f"]), Ok(Compare::Equal(0x1f)));
+        assert_eq!(Compare::parse(&["+"]), Ok(Compare::Increased));
+        assert!(Compare::parse(&["=", "100"]).is_err());
+        assert!(Compare::parse(&["bigger"]).is_err());
+
+        assert_eq!(parse_freeze("2f0=03"), Ok((0x2f0, 3)));
+        assert!(parse_freeze("1000=0").is_err());
+        assert!(parse_freeze("2f0").is_err());
+    }
+}
@@ -1,13 +1,129 @@
+use crate::bench::Timings;
 use crate::display::{Display, FONT_SET};
 use crate::keypad::Keypad;
-use rand::Rng;
+use crate::patch;
+use crate::profile::Profiler;
+use crate::quirks::Quirks;
+use crate::romdb::{self, RomInfo};
+use crate::timing;
+use crate::trace::Tracer;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::time::Instant;
 
 pub const PROGRAM_START: u16 = 0x200;
 
+/// Instruction kinds, named after their mnemonics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Cls,
+    Ret,
+    Jp,
+    Call,
+    SeByte,
+    SneByte,
+    SeReg,
+    LdByte,
+    AddByte,
+    LdReg,
+    Or,
+    And,
+    Xor,
+    AddReg,
+    Sub,
+    Shr,
+    Subn,
+    Shl,
+    SneReg,
+    LdI,
+    JpV0,
+    Rnd,
+    Drw,
+    Skp,
+    Sknp,
+    LdVxDt,
+    LdVxK,
+    LdDtVx,
+    LdStVx,
+    AddIVx,
+    LdFVx,
+    LdBVx,
+    Store,
+    Load,
+    Unknown,
+}
+
+/// An opcode split into its kind and operands, as cached per address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub op: Op,
+    pub x: u8,
+    pub y: u8,
+    pub n: u8,
+    pub byte: u8,
+    pub addr: u16,
+}
+
+pub fn decode(opcode: u16) -> Instruction {
+    // op_k = k highest bit
+    let op_1 = (opcode & 0xF000) >> 12;
+    let op_2 = (opcode & 0x0F00) >> 8;
+    let op_3 = (opcode & 0x00F0) >> 4;
+    let op_4 = opcode & 0x000F;
+
+    let op = match (op_1, op_2, op_3, op_4) {
+        (0x0, 0x0, 0xE, 0x0) => Op::Cls,
+        (0x0, 0x0, 0xE, 0xE) => Op::Ret,
+        (0x1, _, _, _) => Op::Jp,
+        (0x2, _, _, _) => Op::Call,
+        (0x3, _, _, _) => Op::SeByte,
+        (0x4, _, _, _) => Op::SneByte,
+        (0x5, _, _, 0x0) => Op::SeReg,
+        (0x6, _, _, _) => Op::LdByte,
+        (0x7, _, _, _) => Op::AddByte,
+        (0x8, _, _, 0x0) => Op::LdReg,
+        (0x8, _, _, 0x1) => Op::Or,
+        (0x8, _, _, 0x2) => Op::And,
+        (0x8, _, _, 0x3) => Op::Xor,
+        (0x8, _, _, 0x4) => Op::AddReg,
+        (0x8, _, _, 0x5) => Op::Sub,
+        (0x8, _, _, 0x6) => Op::Shr,
+        (0x8, _, _, 0x7) => Op::Subn,
+        (0x8, _, _, 0xE) => Op::Shl,
+        (0x9, _, _, 0x0) => Op::SneReg,
+        (0xA, _, _, _) => Op::LdI,
+        (0xB, _, _, _) => Op::JpV0,
+        (0xC, _, _, _) => Op::Rnd,
+        (0xD, _, _, _) => Op::Drw,
+        (0xE, _, 0x9, 0xE) => Op::Skp,
+        (0xE, _, 0xA, 0x1) => Op::Sknp,
+        (0xF, _, 0x0, 0x7) => Op::LdVxDt,
+        (0xF, _, 0x0, 0xA) => Op::LdVxK,
+        (0xF, _, 0x1, 0x5) => Op::LdDtVx,
+        (0xF, _, 0x1, 0x8) => Op::LdStVx,
+        (0xF, _, 0x1, 0xE) => Op::AddIVx,
+        (0xF, _, 0x2, 0x9) => Op::LdFVx,
+        (0xF, _, 0x3, 0x3) => Op::LdBVx,
+        (0xF, _, 0x5, 0x5) => Op::Store,
+        (0xF, _, 0x6, 0x5) => Op::Load,
+        (_, _, _, _) => Op::Unknown,
+    };
+
+    Instruction {
+        op,
+        x: op_2 as u8,
+        y: op_3 as u8,
+        n: op_4 as u8,
+        byte: (opcode & 0x0FF) as u8,
+        addr: opcode & 0xFFF,
+    }
+}
+
 pub struct Cpu {
     pub i: u16,             // index register
     pub pc: u16,            // program counter
-    pub memory: [u8; 4096], // 4096 bytes of memory.
+    pub memory: [u8; 4096], // 4096 bytes of memory. call `flush_decoded` after writing code here
     pub v: [u8; 16],        // 16 registers
     pub stack: [u16; 16],
     pub sp: u8, // stack pointer
@@ -15,10 +131,35 @@ pub struct Cpu {
     pub keypad: Keypad,
     pub dt: u8, // delay timer
     pub st: u8, // sound timer
+    pub quirks: Quirks,
+    pub waiting_for_vblank: bool,
+    pub rng: StdRng, // source for RND, seed it for reproducible runs
+    pub tracer: Option<Tracer>,
+    pub cycles: u64,        // instructions executed
+    pub frame: u64,         // vertical blanks seen
+    pub decode_cache: bool, // reuse decoded instructions, see `emulate_cycle`
+    decoded: Vec<Option<Instruction>>,
+    pub vip_timing: bool, // budget frames in VIP machine cycles, see `run_frame`
+    cycle_budget: i32,
+    pub timings: Option<Timings>, // where emulate_cycle spends its time
+    pub profiler: Option<Profiler>,
+    pub freezes: BTreeMap<u16, u8>, // bytes written back every vblank, see `cheat`
 }
 
 impl Cpu {
-    pub fn load_rom(&mut self, rom: &Vec<u8>) {
+    /// Loads the font and `rom` into memory. ROMs found in the ROM database
+    /// get their recommended quirks applied, and their entry is returned.
+    /// Fails, leaving memory as it was, if `rom` doesn't fit.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<Option<&'static RomInfo>, String> {
+        let room = self.memory.len() - PROGRAM_START as usize;
+        if rom.len() > room {
+            return Err(format!(
+                "ROM is too large: {} bytes, memory holds {}",
+                rom.len(),
+                room
+            ));
+        }
+
         let mut count = 0;
         for sprite in FONT_SET {
             for byte in sprite {
@@ -30,6 +171,72 @@ impl Cpu {
         for (i, byte) in rom.iter().enumerate() {
             self.memory[PROGRAM_START as usize + i] = *byte;
         }
+
+        self.flush_decoded();
+
+        let info = romdb::lookup(rom);
+        if let Some(info) = info {
+            self.quirks = info.quirks();
+        }
+        Ok(info)
+    }
+
+    /// Applies `patches` to `rom` in order, see `patch`, and loads the result
+    /// as `load_rom` does. A patched ROM that isn't in the ROM database gets
+    /// the entry of the ROM it was made from, so hacks of a known game keep
+    /// its quirks.
+    pub fn load_patched_rom(
+        &mut self,
+        rom: &[u8],
+        patches: &[Vec<u8>],
+    ) -> Result<Option<&'static RomInfo>, String> {
+        let mut patched = rom.to_vec();
+        for data in patches {
+            patched = patch::apply(&patched, data)?;
+        }
+        let info = self.load_rom(&patched)?.or_else(|| romdb::lookup(rom));
+        if let Some(info) = info {
+            self.quirks = info.quirks();
+        }
+        Ok(info)
+    }
+}
+
+/// Copies the machine state, for snapshots and parallel runs. The clone has
+/// no tracer, since there is one trace output.
+impl Clone for Cpu {
+    fn clone(&self) -> Cpu {
+        Cpu {
+            i: self.i,
+            pc: self.pc,
+            memory: self.memory,
+            v: self.v,
+            stack: self.stack,
+            sp: self.sp,
+            display: self.display.clone(),
+            keypad: self.keypad.clone(),
+            dt: self.dt,
+            st: self.st,
+            quirks: self.quirks,
+            waiting_for_vblank: self.waiting_for_vblank,
+            rng: self.rng.clone(),
+            tracer: None,
+            cycles: self.cycles,
+            frame: self.frame,
+            decode_cache: self.decode_cache,
+            decoded: self.decoded.clone(),
+            vip_timing: self.vip_timing,
+            cycle_budget: self.cycle_budget,
+            timings: self.timings,
+            profiler: self.profiler.clone(),
+            freezes: self.freezes.clone(),
+        }
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
     }
 }
 
@@ -46,277 +253,410 @@ impl Cpu {
             keypad: Keypad::new(),
             dt: 0,
             st: 0,
+            quirks: Quirks::default(),
+            waiting_for_vblank: false,
+            rng: StdRng::from_entropy(),
+            tracer: None,
+            cycles: 0,
+            frame: 0,
+            decode_cache: true,
+            decoded: vec![None; 4096],
+            vip_timing: false,
+            cycle_budget: 0,
+            timings: None,
+            profiler: None,
+            freezes: BTreeMap::new(),
         }
     }
 
     fn read_opcode(&self) -> u16 {
         // read a 16 bit word from ram
-        let opcode: u16 = (self.memory[self.pc as usize] as u16) << 8
-            | (self.memory[self.pc as usize + 1] as u16);
+        let opcode: u16 = (self.memory[self.pc as usize & 0xFFF] as u16) << 8
+            | (self.memory[(self.pc as usize + 1) & 0xFFF] as u16);
         opcode
     }
 
-    pub fn emulate_cycle(&mut self) {
-        // read op code
-        let opcode = self.read_opcode();
-        println!("executing opcode {:#06x}", opcode);
+    /// Forgets all cached decoded instructions. Needed after writing to
+    /// `memory` directly; the CPU's own stores keep the cache up to date.
+    pub fn flush_decoded(&mut self) {
+        for entry in self.decoded.iter_mut() {
+            *entry = None;
+        }
+    }
 
-        self.execute_opcode(opcode);
+    fn write(&mut self, addr: usize, byte: u8) {
+        let addr = addr & 0xFFF;
+        self.memory[addr] = byte;
+        // the byte belongs to the instructions starting here and just before
+        self.decoded[addr] = None;
+        self.decoded[addr.wrapping_sub(1) & 0xFFF] = None;
+    }
 
+    /// Runs one instruction and returns whether the framebuffer has changed
+    /// since it was last presented with `Display::take_dirty`.
+    ///
+    /// With `decode_cache` set, each address is decoded once and reused until
+    /// an `LD B, Vx` or `LD [I], Vx` writes over it.
+    pub fn emulate_cycle(&mut self) -> bool {
+        if self.waiting_for_vblank {
+            return self.display.is_dirty();
+        }
+
+        if let Some(mut tracer) = self.tracer.take() {
+            match tracer.record(self, self.read_opcode()) {
+                Ok(()) => self.tracer = Some(tracer),
+                Err(e) => eprintln!("tracing stopped: {}", e),
+            }
+        }
+
+        let start = self.timings.map(|_| Instant::now());
+        let pc = self.pc as usize & 0xFFF;
+        let instruction = match self.decoded[pc] {
+            Some(instruction) if self.decode_cache => instruction,
+            _ => {
+                // read op code
+                let instruction = decode(self.read_opcode());
+                if self.decode_cache {
+                    self.decoded[pc] = Some(instruction);
+                }
+                instruction
+            }
+        };
+
+        if self.vip_timing {
+            self.cycle_budget -= timing::vip_cycles(self, instruction) as i32;
+        }
+        if let Some(mut profiler) = self.profiler.take() {
+            profiler.record(self, instruction);
+            self.profiler = Some(profiler);
+        }
+
+        let decoded = start.map(|_| Instant::now());
+        self.execute(instruction);
+        self.cycles += 1;
+
+        if let (Some(timings), Some(start), Some(decoded)) = (&mut self.timings, start, decoded) {
+            let now = Instant::now();
+            timings.decode += decoded - start;
+            match instruction.op {
+                Op::Drw | Op::Cls => timings.draw += now - decoded,
+                _ => timings.execute += now - decoded,
+            }
+        }
+
+        self.display.is_dirty()
+    }
+
+    /// Signals the 60 Hz frame boundary: counts the timers down, writes the
+    /// `freezes` back and releases a `DRW` that is waiting for the vertical
+    /// blank.
+    pub fn vblank(&mut self) {
         if self.dt > 0 { self.dt -= 1; }
-        if self.st == 1 { println!("BEEP!"); }
+        // stderr, as stdout may be carrying a protocol or the final screen
+        if self.st == 1 { eprintln!("BEEP!"); }
         if self.st > 0 { self.st -= 1; }
+
+        // frozen bytes win over whatever the game stored during the frame
+        for (&addr, &byte) in self.freezes.iter() {
+            let addr = addr as usize & 0xFFF;
+            if self.memory[addr] != byte {
+                self.memory[addr] = byte;
+                self.decoded[addr] = None;
+                self.decoded[addr.wrapping_sub(1) & 0xFFF] = None;
+            }
+        }
+
+        self.waiting_for_vblank = false;
+        self.frame += 1;
+    }
+
+    /// Runs one 60 Hz frame followed by the vertical blank, returning whether
+    /// the framebuffer changed. The frame is `cycles` instructions, or with
+    /// `vip_timing` as many as the VIP would get through in a frame, see
+    /// `timing`.
+    pub fn run_frame(&mut self, cycles: usize) -> bool {
+        if self.vip_timing {
+            self.cycle_budget += timing::VIP_CYCLES_PER_FRAME;
+            while self.cycle_budget > 0 && !self.waiting_for_vblank {
+                self.emulate_cycle();
+            }
+            // an overrun is paid off next frame, time spent waiting is lost
+            self.cycle_budget = self.cycle_budget.min(0);
+        } else {
+            for _ in 0..cycles {
+                self.emulate_cycle();
+            }
+        }
+        self.vblank();
+
+        self.display.is_dirty()
     }
 
+    #[cfg(test)]
     fn execute_opcode(&mut self, opcode: u16) {
+        self.execute(decode(opcode));
+    }
+
+    fn execute(&mut self, instruction: Instruction) {
         // opcode parameters
-        let addr = opcode & 0xFFF; // lowest 12 bits
-        let byte = (opcode & 0x0FF) as u8; // lowest 8 bits
-        let n = opcode & 0x00F; // lowest 4 bits (nibble)
-        let x = ((opcode & 0x0F00) >> 8) as usize; // lower 4 bits of the high byte
-        let y = ((opcode & 0x00F0) >> 4) as usize; // higher 4 bits of the low byte
-
-        // op_k = k highest bit
-        let op_1 = (opcode & 0xF000) >> 12;
-        let op_2 = (opcode & 0x0F00) >> 8;
-        let op_3 = (opcode & 0x00F0) >> 4;
-        let op_4 = opcode & 0x000F;
-
-        self.pc += 2;
-
-        match (op_1, op_2, op_3, op_4) {
-            (0x0, 0x0, 0xE, 0x0) => {
+        let addr = instruction.addr;
+        let byte = instruction.byte;
+        let n = instruction.n as u16;
+        let x = instruction.x as usize;
+        let y = instruction.y as usize;
+
+        // addresses wrap around the 4K address space
+        self.pc = (self.pc + 2) & 0xFFF;
+
+        match instruction.op {
+            Op::Cls => {
                 // CLS
                 self.display.cls();
             }
 
-            (0x0, 0x0, 0xE, 0xE) => {
+            Op::Ret => {
                 // RET
-                self.sp -= 1;
+                self.sp = self.sp.wrapping_sub(1) & 0xF;
                 self.pc = self.stack[self.sp as usize];
             }
 
-            (0x1, _, _, _) => {
+            Op::Jp => {
                 // JP addr
                 self.pc = addr;
             }
 
-            (0x2, _, _, _) => {
+            Op::Call => {
                 // CALL addr
                 /*
                    1. increment stack pointer
                    2. put current pc on top of the stack
                    3. set pc to addr
                 */
-                self.stack[self.sp as usize] = self.pc;
-                self.sp += 1;
+                self.stack[self.sp as usize & 0xF] = self.pc;
+                self.sp = (self.sp + 1) & 0xF;
                 self.pc = addr;
             }
 
-            (0x3, _, _, _) => {
+            Op::SeByte => {
                 // SE Vx byte
                 self.pc += if self.v[x] == byte { 2 } else { 0 };
             }
 
-            (0x4, _, _, _) => {
+            Op::SneByte => {
                 // SNE Vx, byte
                 self.pc += if self.v[x] != byte { 2 } else { 0 };
             }
 
-            (0x5, _, _, 0x0) => {
+            Op::SeReg => {
                 // SE Vx, Vy
                 self.pc += if self.v[x] == self.v[y] { 2 } else { 0 };
             }
 
-            (0x6, _, _, _) => {
+            Op::LdByte => {
                 // LD Vx, byte
                 self.v[x] = byte;
             }
 
-            (0x7, _, _, _) => {
-                // ADD Vx, byte
-                let (sum, overflow) = self.v[x].overflowing_add(byte);
-                match overflow {
-                    true => self.v[0xF] = 1,
-                    false => self.v[0xF] = 0,
-                }
-                self.v[x] = sum;
+            Op::AddByte => {
+                // ADD Vx, byte (VF is not affected)
+                self.v[x] = self.v[x].wrapping_add(byte);
             }
 
-            (0x8, _, _, 0x0) => {
+            Op::LdReg => {
                 // LD Vx, Vy
                 self.v[x] = self.v[y];
             }
 
-            (0x8, _, _, 0x1) => {
+            Op::Or => {
                 // OR Vx, Vy
                 self.v[x] |= self.v[y];
             }
 
-            (0x8, _, _, 0x2) => {
+            Op::And => {
                 // AND Vx, Vy
                 self.v[x] &= self.v[y];
             }
 
-            (0x8, _, _, 0x3) => {
+            Op::Xor => {
                 // XOR Vx, Vy
                 self.v[x] ^= self.v[y];
             }
 
-            (0x8, _, _, 0x4) => {
+            Op::AddReg => {
                 // ADD Vx, Vy
                 let (sum, overflow) = self.v[x].overflowing_add(self.v[y]);
+                self.v[x] = sum;
                 match overflow {
                     true => self.v[0xF] = 1,
                     false => self.v[0xF] = 0,
                 }
-                self.v[x] = sum;
             }
 
-            (0x8, _, _, 0x5) => {
+            Op::Sub => {
                 // SUB Vx, Vy
                 let (diff, overflow) = self.v[x].overflowing_sub(self.v[y]);
+                self.v[x] = diff;
                 match overflow {
                     true => self.v[0xF] = 0,
                     false => self.v[0xF] = 1,
                 }
-                self.v[x] = diff;
             }
 
-            (0x8, _, _, 0x6) => {
+            Op::Shr => {
                 // SHR Vx {, Vy}
-                if self.v[x] & 1 == 1 {
-                    self.v[0xF] = 1;
+                let src = if self.quirks.shift_vy {
+                    self.v[y]
                 } else {
-                    self.v[x] /= 2;
-                }
+                    self.v[x]
+                };
+                self.v[x] = src >> 1;
+                self.v[0xF] = src & 1;
             }
 
-            (0x8, _, _, 0x7) => {
+            Op::Subn => {
                 // SUBN Vx, Vy
                 let (res, overflow) = self.v[y].overflowing_sub(self.v[x]);
+                self.v[x] = res;
                 match overflow {
                     true => self.v[0xF] = 0,
                     false => self.v[0xF] = 1,
                 }
-                self.v[x] = res;
             }
 
-            (0x8, _, _, 0xE) => {
+            Op::Shl => {
                 // SHL Vx {, Vy}
-                self.v[0xF] = self.v[x] & 0x80;
-                self.v[x] <<= 1;
+                let src = if self.quirks.shift_vy {
+                    self.v[y]
+                } else {
+                    self.v[x]
+                };
+                self.v[x] = src << 1;
+                self.v[0xF] = src >> 7;
             }
 
-            (0x9, _, _, 0x0) => {
+            Op::SneReg => {
                 // SNE Vx, Vy
                 self.pc += if self.v[x] != self.v[y] { 2 } else { 0 };
             }
 
-            (0xA, _, _, _) => {
+            Op::LdI => {
                 // LD I, addr
                 self.i = addr;
             }
 
-            (0xB, _, _, _) => {
+            Op::JpV0 => {
                 // JP V0, addr
                 self.pc = addr + (self.v[0] as u16);
             }
 
-            (0xC, _, _, _) => {
+            Op::Rnd => {
                 // RND Vx, byte
-                let mut rng = rand::thread_rng();
-
-                self.v[x] = byte & (rng.gen_range(0, 256) as u8);
+                self.v[x] = byte & (self.rng.gen_range(0, 256) as u8);
             }
 
-            (0xD, _, _, _) => {
+            Op::Drw => {
                 // DRW Vx, Vy, nibble
+                let mut sprite = [0; 15];
+                for (k, row) in sprite.iter_mut().enumerate() {
+                    *row = self.memory[(self.i as usize + k) & 0xFFF];
+                }
                 let collision = self.display.draw(
                     self.v[x] as usize,
                     self.v[y] as usize,
-                    &self.memory[self.i as usize..(self.i + n) as u16 as usize],
+                    &sprite[..n as usize],
+                    self.quirks.clip_sprites,
                 );
                 self.v[0xF] = if collision { 1 } else { 0 };
+                self.waiting_for_vblank = self.quirks.vblank_wait;
             }
 
-            (0xE, _, 0x9, 0xE) => {
+            Op::Skp => {
                 // SKP Vx
-                self.pc += if self.keypad.is_pressed(self.v[x]) {
+                self.pc += if self.keypad.is_pressed(self.v[x] & 0xF) {
                     2
                 } else {
                     0
                 };
             }
 
-            (0xE, _, 0xA, 0x1) => {
+            Op::Sknp => {
                 // SKNP Vx
-                self.pc += if !self.keypad.is_pressed(self.v[x]) {
+                self.pc += if !self.keypad.is_pressed(self.v[x] & 0xF) {
                     2
                 } else {
                     0
                 };
             }
 
-            (0xF, _, 0x0, 0x7) => {
+            Op::LdVxDt => {
                 // LD Vx, DT
                 self.v[x] = self.dt;
             }
 
-            (0xF, _, 0x0, 0xA) => {
-                // LD Vx, K
-                self.pc -= 2;
+            Op::LdVxK => {
+                // LD Vx, K (the highest held key wins)
+                let mut pressed = false;
                 for (i, key) in self.keypad.keys.iter().enumerate() {
                     if *key {
                         self.v[x] = i as u8;
-                        self.pc += 2;
+                        pressed = true;
                     }
                 }
+                if !pressed {
+                    self.pc = self.pc.wrapping_sub(2);
+                }
             }
 
-            (0xF, _, 0x1, 0x5) => {
+            Op::LdDtVx => {
                 // LD DT, Vx
                 self.dt = self.v[x];
             }
 
-            (0xF, _, 0x1, 0x8) => {
+            Op::LdStVx => {
                 // LD ST, Vx
                 self.st = self.v[x];
             }
 
-            (0xF, _, 0x1, 0xE) => {
+            Op::AddIVx => {
                 // ADD I, Vx
-                self.i += self.v[x] as u16;
+                self.i = self.i.wrapping_add(self.v[x] as u16);
             }
 
-            (0xF, _, 0x2, 0x9) => {
+            Op::LdFVx => {
                 // LD F, Vx
-                self.i = self.v[x] as u16 * 5;
+                self.i = (self.v[x] & 0xF) as u16 * 5;
             }
 
-            (0xF, _, 0x3, 0x3) => {
+            Op::LdBVx => {
                 // LD B, Vx
-                self.memory[self.i as usize] = self.v[x] / 100;
-                self.memory[self.i as usize + 1] = (self.v[x] / 10) % 10;
-                self.memory[self.i as usize + 2] = self.v[x] % 10;
+                self.write(self.i as usize, self.v[x] / 100);
+                self.write(self.i as usize + 1, (self.v[x] / 10) % 10);
+                self.write(self.i as usize + 2, self.v[x] % 10);
             }
 
-            (0xF, _, 0x5, 0x5) => {
+            Op::Store => {
                 // LD [I], Vx
                 for i in 0..x + 1 {
-                    self.memory[self.i as usize + i] = self.v[i];
+                    self.write(self.i as usize + i, self.v[i]);
+                }
+                if self.quirks.load_store_increment {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
                 }
             }
 
-            (0xF, _, 0x6, 0x5) => {
+            Op::Load => {
                 // LD Vx, [I]
                 for i in 0..x + 1 {
-                    self.v[i] = self.memory[self.i as usize + i];
+                    self.v[i] = self.memory[(self.i as usize + i) & 0xFFF];
+                }
+                if self.quirks.load_store_increment {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
                 }
             }
 
-            (_, _, _, _) => (),
+            Op::Unknown => (),
         }
+        self.pc &= 0xFFF;
     }
 }
 
@@ -324,6 +664,7 @@ impl Cpu {
 mod tests {
     use super::Cpu;
     use super::PROGRAM_START;
+    use crate::quirks::Platform;
 
     #[test]
     fn opcode_jp() {
@@ -521,6 +862,74 @@ mod tests {
         assert_eq!(cpu.memory[cpu.i as usize + 3], 0, "i + 3 was not loaded");
     }
 
+    #[test]
+    fn opcode_shr_shl() {
+        let mut cpu = Cpu::new();
+        cpu.v[1] = 0b1000_0011;
+
+        cpu.execute_opcode(0x8126);
+        assert_eq!(cpu.v[1], 0b0100_0001, "Vx was shifted right");
+        assert_eq!(cpu.v[0xF], 1, "VF holds the bit shifted out");
+
+        cpu.execute_opcode(0x812E);
+        assert_eq!(cpu.v[1], 0b1000_0010, "Vx was shifted left");
+        assert_eq!(cpu.v[0xF], 0, "VF holds the bit shifted out");
+
+        cpu.quirks.shift_vy = true;
+        cpu.v[2] = 0b1000_0000;
+        cpu.execute_opcode(0x812E);
+        assert_eq!(cpu.v[1], 0, "Vx was loaded with Vy shifted left");
+        assert_eq!(cpu.v[0xF], 1, "VF holds the bit shifted out of Vy");
+    }
+
+    #[test]
+    fn opcode_ld_i_vx_increments_i() {
+        let mut cpu = Cpu::new();
+        cpu.quirks.load_store_increment = true;
+        cpu.i = 0x300;
+
+        cpu.execute_opcode(0xF255);
+        assert_eq!(cpu.i, 0x303, "I was moved past the stored registers");
+        cpu.execute_opcode(0xF065);
+        assert_eq!(cpu.i, 0x304, "I was moved past the loaded register");
+    }
+
+    #[test]
+    fn stores_invalidate_decoded_instructions() {
+        let mut cpu = Cpu::new();
+        // LD V1, 0x01; LD V0, 0x61; ADD V1, 0x01; LD I, 0x200;
+        // LD [I], V1; JP 0x200 rewrites the first instruction to LD V1, 0x02
+        cpu.load_rom(&[
+            0x61, 0x01, 0x60, 0x61, 0x71, 0x01, 0xA2, 0x00, 0xF1, 0x55, 0x12, 0x00,
+        ]).unwrap();
+        for _ in 0..7 {
+            cpu.emulate_cycle();
+        }
+        assert_eq!(cpu.memory[0x201], 0x02);
+        assert_eq!(
+            cpu.v[1], 0x02,
+            "the rewritten instruction was decoded again"
+        );
+    }
+
+    #[test]
+    fn vip_timing_budgets_machine_cycles() {
+        let mut cpu = Cpu::new();
+        cpu.vip_timing = true;
+        // LD V0, 0x01; JP 0x200
+        cpu.load_rom(&[0x60, 0x01, 0x12, 0x00]).unwrap();
+        cpu.run_frame(1000);
+        let fast = cpu.cycles;
+        assert!(fast > 10 && fast < 1000, "{} instructions", fast);
+
+        let mut cpu = Cpu::new();
+        cpu.vip_timing = true;
+        // DRW V0, V0, 15; JP 0x200
+        cpu.load_rom(&[0xD0, 0x0F, 0x12, 0x00]).unwrap();
+        cpu.run_frame(1000);
+        assert!(cpu.cycles < fast / 4, "sprites are slow");
+    }
+
     #[test]
     fn opcode_ld_b_vx() {
         let mut cpu = Cpu::new();
@@ -610,4 +1019,46 @@ mod tests {
             "the program counter is advanced two bytes"
         );
     }
+
+    #[test]
+    fn opcode_drw_waits_for_vblank() {
+        let mut cpu = Cpu::new();
+        cpu.quirks = Platform::CosmacVip.quirks();
+        // DRW V0, V0, 1 ; LD V1, 0x01
+        cpu.load_rom(&[0xD0, 0x01, 0x61, 0x01]).unwrap();
+
+        cpu.emulate_cycle();
+        cpu.emulate_cycle();
+        assert_eq!(cpu.pc, PROGRAM_START + 2, "the cpu is halted after DRW");
+        assert_eq!(cpu.v[1], 0, "the next instruction has not run");
+
+        cpu.vblank();
+        cpu.emulate_cycle();
+        assert_eq!(cpu.v[1], 1, "the cpu resumes after the vertical blank");
+    }
+
+    #[test]
+    fn load_patched_rom() {
+        let mut cpu = Cpu::new();
+        // LD V0, 0x01 becomes LD V0, 0x07
+        let patch = b"PATCH\x00\x00\x01\x00\x01\x07EOF".to_vec();
+        assert!(cpu.load_patched_rom(&[0x60, 0x01], &[patch]).is_ok());
+        cpu.emulate_cycle();
+        assert_eq!(cpu.v[0], 7, "the patched instruction runs");
+
+        let grow = b"PATCH\x00\x10\x00\x00\x01\xFFEOF".to_vec();
+        assert!(
+            cpu.load_patched_rom(&[0x60, 0x01], &[grow]).is_err(),
+            "too large for memory"
+        );
+    }
+
+    #[test]
+    fn load_rom_rejects_oversized_roms() {
+        let mut cpu = Cpu::new();
+        assert!(cpu.load_rom(&[0xAA; 0x1000 - 0x200]).is_ok(), "fills memory");
+        let error = cpu.load_rom(&[0x55; 0x1000 - 0x1FF]).unwrap_err();
+        assert!(error.contains("too large"), "{}", error);
+        assert_eq!(cpu.memory[0xFFF], 0xAA, "memory is left as it was");
+    }
 }
@@ -0,0 +1,833 @@
+//! A Debug Adapter Protocol server, so that editors can debug ROMs and their
+//! sources.
+//!
+//! `launch` takes `program` (a ROM, `.asm` or `.8o` file) and optionally
+//! `stopOnEntry`, `platform`, `quirks` and `ips`. Assembler and Octo sources
+//! get breakpoints and stepping by source line through their source maps;
+//! ROMs are shown as a disassembly with one word per line. There is one
+//! thread, stack frames come from `Cpu::stack`, and the variables are the
+//! registers and timers. The debug console takes `debugger` commands such as
+//! `x` and `screen`.
+
+use crate::asm::{self, SourceLine};
+use crate::cpu::{decode, Cpu, PROGRAM_START};
+use crate::debugger::{Debugger, Stop};
+use crate::disasm;
+use crate::octo;
+use crate::quirks::Platform;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+const THREAD: i64 = 1;
+/// `variablesReference`s of the scopes.
+const REGISTERS: i64 = 1;
+const TIMERS: i64 = 2;
+/// `sourceReference` of the generated disassembly.
+const DISASSEMBLY: i64 = 1;
+/// Frames run between checks for requests while running.
+const SLICE_FRAMES: u64 = 1;
+
+/// Reads one message: headers, a blank line, and a JSON body of the
+/// `Content-Length` they give. Returns `None` at the end of the input.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| invalid("missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid(&e.to_string()))
+}
+
+pub fn write_message(out: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Where the program's code comes from, for mapping addresses to lines.
+struct Program {
+    /// Source lines in address order.
+    lines: Vec<SourceLine>,
+    /// Text of the generated disassembly, when there is no source.
+    disassembly: Option<String>,
+    name: String,
+}
+
+impl Program {
+    fn load(path: &Path) -> Result<(Program, Vec<u8>), String> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        if extension == Some("asm") {
+            let path = fs::canonicalize(path).map_err(|e| e.to_string())?;
+            let assembly = asm::assemble_file(&path).map_err(|e| e.to_string())?;
+            let program = Program {
+                lines: assembly.lines,
+                disassembly: None,
+                name,
+            };
+            return Ok((program, assembly.rom));
+        }
+        if extension == Some("8o") {
+            let path = fs::canonicalize(path).map_err(|e| e.to_string())?;
+            let (rom, lines) = octo::compile_file_with_lines(&path).map_err(|e| e.to_string())?;
+            let program = Program {
+                lines,
+                disassembly: None,
+                name,
+            };
+            return Ok((program, rom));
+        }
+
+        let rom =
+            fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let disassembly = disasm::disassemble(&rom);
+        let name = format!("{}.dis", name);
+        let lines = (0..disassembly.lines().count())
+            .map(|i| SourceLine {
+                address: PROGRAM_START + 2 * i as u16,
+                file: name.clone(),
+                line: i + 1,
+            })
+            .collect();
+        let program = Program {
+            lines,
+            disassembly: Some(disassembly),
+            name,
+        };
+        Ok((program, rom))
+    }
+
+    fn source(&self, file: &str) -> Value {
+        if self.disassembly.is_some() {
+            json!({ "name": self.name, "sourceReference": DISASSEMBLY })
+        } else {
+            let name = Path::new(file)
+                .file_name()
+                .map_or(file.into(), |name| name.to_string_lossy());
+            json!({ "name": name, "path": file })
+        }
+    }
+
+    /// The file a `Source` from the client refers to.
+    fn file(&self, source: &Value) -> Option<String> {
+        if source["sourceReference"].as_i64() == Some(DISASSEMBLY) {
+            return Some(self.name.clone());
+        }
+        let path = Path::new(source["path"].as_str()?);
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        Some(path.display().to_string())
+    }
+
+    /// The index of the line whose code contains `addr`.
+    fn line_index(&self, addr: u16) -> Option<usize> {
+        let index = self.lines.partition_point(|line| line.address <= addr);
+        let next = self.lines.get(index).map_or(0x1000, |next| next.address);
+        // a data line may be followed by a gap, which is nobody's
+        index.checked_sub(1).filter(|_| addr < next)
+    }
+
+    /// The line whose code contains `addr`.
+    fn line_of(&self, addr: u16) -> Option<&SourceLine> {
+        self.line_index(addr).map(|index| &self.lines[index])
+    }
+
+    /// The first line with code at or after `line` in `file`.
+    fn line_at_or_after(&self, file: &str, line: usize) -> Option<&SourceLine> {
+        self.lines
+            .iter()
+            .filter(|l| l.file == file && l.line >= line)
+            .min_by_key(|l| l.line)
+    }
+}
+
+/// The index of register `name`, `V0` to `VF` in upper case.
+fn v_index(name: &str) -> Option<usize> {
+    let digit = name.strip_prefix('V').filter(|digit| digit.len() == 1)?;
+    usize::from_str_radix(digit, 16).ok()
+}
+
+/// What a running machine is waiting for.
+#[derive(Clone, Copy, Debug)]
+enum Goal {
+    Continue,
+    /// Leaving the source line at `Program::lines[from]`, or coming back to
+    /// its start. With `over`, lines run by deeper calls don't count.
+    Line {
+        from: usize,
+        sp: u8,
+        over: bool,
+    },
+    /// Returning to the caller of the current subroutine.
+    Out {
+        sp: u8,
+    },
+}
+
+struct Session {
+    cpu: Cpu,
+    debugger: Debugger,
+    program: Program,
+    stop_on_entry: bool,
+    /// Breakpoint addresses by file, as the client sets them per file.
+    breakpoints: HashMap<String, Vec<u16>>,
+    goal: Option<Goal>,
+}
+
+impl Session {
+    fn launch(args: &Value) -> Result<Session, String> {
+        let program = args["program"].as_str().ok_or("launch needs a `program`")?;
+        let (program, rom) = Program::load(Path::new(program))?;
+
+        let mut cpu = Cpu::new();
+        let info = cpu.load_rom(&rom)?;
+        if let Some(platform) = args["platform"].as_str() {
+            cpu.quirks = platform.parse::<Platform>()?.quirks();
+        }
+        if let Some(spec) = args["quirks"].as_str() {
+            cpu.quirks.apply(spec)?;
+        }
+        let ips = args["ips"]
+            .as_u64()
+            .or_else(|| info.and_then(|info| info.ips).map(u64::from))
+            .unwrap_or(500);
+
+        Ok(Session {
+            cpu,
+            debugger: Debugger::new((ips / 60).max(1) as usize),
+            program,
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            breakpoints: HashMap::new(),
+            goal: None,
+        })
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let file = self.program.file(&args["source"]).ok_or("unknown source")?;
+        for addr in self.breakpoints.remove(&file).unwrap_or_default() {
+            self.debugger.breakpoints.remove(&addr);
+        }
+
+        let mut addresses = Vec::new();
+        let mut breakpoints = Vec::new();
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        for breakpoint in requested {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            breakpoints.push(match self.program.line_at_or_after(&file, line) {
+                Some(found) => {
+                    addresses.push(found.address);
+                    json!({
+                        "verified": true,
+                        "line": found.line,
+                        "source": self.program.source(&found.file),
+                    })
+                }
+                None => json!({ "verified": false, "message": "no code here" }),
+            });
+        }
+        self.debugger.breakpoints.extend(&addresses);
+        self.breakpoints.insert(file, addresses);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// The subroutine entered at each call depth, outermost first.
+    fn entries(&self) -> Vec<u16> {
+        let cpu = &self.cpu;
+        let mut entries = vec![PROGRAM_START];
+        for &ret in &cpu.stack[..cpu.sp as usize] {
+            let call = ret.wrapping_sub(2) as usize & 0xFFF;
+            let word = (cpu.memory[call] as u16) << 8 | cpu.memory[(call + 1) & 0xFFF] as u16;
+            entries.push(decode(word).addr);
+        }
+        entries
+    }
+
+    fn stack_trace(&self) -> Value {
+        let cpu = &self.cpu;
+        let entries = self.entries();
+        let depth = cpu.sp as usize;
+        let frames: Vec<Value> = (0..=depth)
+            .rev()
+            .map(|level| {
+                let addr = if level == depth {
+                    cpu.pc
+                } else {
+                    cpu.stack[level].wrapping_sub(2) & 0xFFF
+                };
+                let name = match entries[level] {
+                    PROGRAM_START => "(entry)".to_string(),
+                    entry => format!("sub_{:03x}", entry),
+                };
+                let mut frame = json!({
+                    "id": depth - level,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("{:#05x}", addr),
+                });
+                if let Some(line) = self.program.line_of(addr) {
+                    frame["line"] = json!(line.line);
+                    frame["column"] = json!(1);
+                    frame["source"] = self.program.source(&line.file);
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": depth + 1 })
+    }
+
+    fn variables(&self, reference: i64) -> Value {
+        let cpu = &self.cpu;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<Value> = if reference == REGISTERS {
+            let mut variables = vec![
+                variable("PC".to_string(), format!("{:#05x}", cpu.pc)),
+                variable("I".to_string(), format!("{:#05x}", cpu.i)),
+                variable("SP".to_string(), cpu.sp.to_string()),
+            ];
+            variables.extend(
+                (0..16).map(|x| variable(format!("V{:X}", x), format!("{:#04x}", cpu.v[x]))),
+            );
+            variables
+        } else if reference == TIMERS {
+            vec![
+                variable("DT".to_string(), cpu.dt.to_string()),
+                variable("ST".to_string(), cpu.st.to_string()),
+            ]
+        } else {
+            Vec::new()
+        };
+        json!({ "variables": variables })
+    }
+
+    /// Reads the register or timer called `name`.
+    fn register(&self, name: &str) -> Option<u16> {
+        let cpu = &self.cpu;
+        Some(match name.to_ascii_uppercase().as_str() {
+            "PC" => cpu.pc,
+            "I" => cpu.i,
+            "SP" => cpu.sp as u16,
+            "DT" => cpu.dt as u16,
+            "ST" => cpu.st as u16,
+            name => cpu.v[v_index(name)?] as u16,
+        })
+    }
+
+    fn set_register(&mut self, name: &str, value: &str) -> Result<Value, String> {
+        let parsed = match value.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => value.parse(),
+        }
+        .map_err(|_| format!("`{}` is not a number", value))?;
+        let cpu = &mut self.cpu;
+        match name.to_ascii_uppercase().as_str() {
+            "PC" => cpu.pc = parsed & 0xFFF,
+            "I" => cpu.i = parsed,
+            "SP" => cpu.sp = parsed as u8 & 0xF,
+            "DT" => cpu.dt = parsed as u8,
+            "ST" => cpu.st = parsed as u8,
+            other => match v_index(other) {
+                Some(x) => cpu.v[x] = parsed as u8,
+                None => return Err(format!("no variable `{}`", name)),
+            },
+        }
+        let value = self.register(name).unwrap_or_default();
+        Ok(json!({ "value": value.to_string() }))
+    }
+}
+
+/// Protocol state: the session once launched, and the message sequence.
+pub struct Adapter<W: Write> {
+    out: W,
+    seq: i64,
+    session: Option<Session>,
+}
+
+impl<W: Write> Adapter<W> {
+    pub fn new(out: W) -> Adapter<W> {
+        Adapter {
+            out,
+            seq: 0,
+            session: None,
+        }
+    }
+
+    /// Whether the machine runs between requests.
+    pub fn running(&self) -> bool {
+        self.session
+            .as_ref()
+            .is_some_and(|session| session.goal.is_some())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.out, &message)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true }),
+        )
+    }
+
+    /// Handles a request. Returns `false` once the client disconnects.
+    pub fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        let result = self.dispatch(command, args);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match &result {
+            Ok(Value::Null) => (),
+            Ok(body) => response["body"] = body.clone(),
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)?;
+
+        match (command, result.is_ok()) {
+            ("launch", true) => self.event("initialized", json!({}))?,
+            ("disconnect", _) => return Ok(false),
+            _ => (),
+        }
+        self.after(command)?;
+        Ok(true)
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| "no program launched".to_string())
+    }
+
+    fn dispatch(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        Ok(match command {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsSetVariable": true,
+                "supportsEvaluateForHovers": true,
+            }),
+            "launch" => {
+                self.session = Some(Session::launch(args)?);
+                Value::Null
+            }
+            "setBreakpoints" => self.session()?.set_breakpoints(args)?,
+            "setExceptionBreakpoints" => json!({ "breakpoints": [] }),
+            "configurationDone" | "disconnect" => Value::Null,
+            "threads" => json!({ "threads": [{ "id": THREAD, "name": "chip8" }] }),
+            "stackTrace" => self.session()?.stack_trace(),
+            "scopes" => json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS, "expensive": false },
+            ] }),
+            "variables" => {
+                let reference = args["variablesReference"].as_i64().unwrap_or(0);
+                self.session()?.variables(reference)
+            }
+            "setVariable" => {
+                let name = args["name"].as_str().unwrap_or_default();
+                let value = args["value"].as_str().unwrap_or_default();
+                self.session()?.set_register(name, value)?
+            }
+            "source" => match self.session()?.program.disassembly.clone() {
+                Some(content) => json!({ "content": content }),
+                None => return Err("no such source".to_string()),
+            },
+            "evaluate" => {
+                let session = self.session()?;
+                let expression = args["expression"].as_str().unwrap_or_default();
+                let result = match session.register(expression) {
+                    Some(value) => format!("{:#x}", value),
+                    None => session
+                        .debugger
+                        .command(&mut session.cpu, expression)
+                        .unwrap_or_default(),
+                };
+                json!({ "result": result.trim_end(), "variablesReference": 0 })
+            }
+            "continue" | "next" | "stepIn" | "stepOut" | "pause" => {
+                self.session()?;
+                json!({ "allThreadsContinued": true })
+            }
+            _ => return Err(format!("`{}` is not supported", command)),
+        })
+    }
+
+    /// Starts or stops the machine for run control requests, once their
+    /// response is out. Running machines stop in `tick`.
+    fn after(&mut self, command: &str) -> io::Result<()> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let cpu = &mut session.cpu;
+        let sp = cpu.sp;
+        let line = session.program.line_index(cpu.pc);
+        match (command, line) {
+            ("configurationDone", _) if session.stop_on_entry => return self.stopped("entry"),
+            ("configurationDone", _) | ("continue", _) => {
+                session.debugger.step(cpu);
+                session.goal = Some(Goal::Continue);
+            }
+            ("next", Some(from)) | ("stepIn", Some(from)) => {
+                session.debugger.step(cpu);
+                session.goal = Some(Goal::Line {
+                    from,
+                    sp,
+                    over: command == "next",
+                });
+            }
+            ("stepOut", _) if sp > 0 => {
+                session.debugger.step(cpu);
+                session.goal = Some(Goal::Out { sp: sp - 1 });
+            }
+            ("stepOut", _) => {
+                session.debugger.step(cpu);
+                session.goal = Some(Goal::Continue);
+            }
+            // in a gap between lines
+            ("next", None) | ("stepIn", None) => {
+                session.debugger.step(cpu);
+                return self.stopped("step");
+            }
+            ("pause", _) => {
+                session.goal = None;
+                return self.stopped("pause");
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Runs the machine for a slice if it is running, and reports why it
+    /// stopped.
+    pub fn tick(&mut self) -> io::Result<()> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let goal = match session.goal {
+            Some(goal) => goal,
+            None => return Ok(()),
+        };
+        let program = &session.program;
+        let stop = session
+            .debugger
+            .run_until(&mut session.cpu, SLICE_FRAMES, |cpu| match goal {
+                Goal::Continue => false,
+                Goal::Line { from, sp, over } => {
+                    let from = &program.lines[from];
+                    let moved = program
+                        .line_of(cpu.pc)
+                        .is_none_or(|line| (&line.file, line.line) != (&from.file, from.line));
+                    (!over || cpu.sp <= sp) && (moved || cpu.pc == from.address)
+                }
+                Goal::Out { sp } => cpu.sp == sp,
+            });
+        let reason = match stop {
+            Stop::Breakpoint(_) => "breakpoint",
+            Stop::Condition => "step",
+            Stop::Limit => return Ok(()),
+        };
+        session.goal = None;
+        self.stopped(reason)
+    }
+}
+
+/// Serves one client, reading requests from `input` on another thread so
+/// that `pause` arrives while the machine runs.
+pub fn serve(input: impl Read + Send + 'static, output: impl Write) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut adapter = Adapter::new(output);
+    loop {
+        let request = if adapter.running() {
+            match receiver.try_recv() {
+                Ok(request) => Some(request),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(request) => Some(request),
+                Err(_) => break,
+            }
+        };
+        if let Some(request) = request {
+            if !adapter.handle(&request)? {
+                break;
+            }
+        }
+        adapter.tick()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_message, Adapter};
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::io::BufReader;
+
+    struct Client {
+        adapter: Adapter<Vec<u8>>,
+        seq: i64,
+    }
+
+    impl Client {
+        /// Sends a request and returns the messages it produced, running the
+        /// machine until it stops if the request started it.
+        fn request(&mut self, command: &str, arguments: Value) -> Vec<Value> {
+            self.seq += 1;
+            let request = json!({
+                "seq": self.seq,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            });
+            assert!(self.adapter.handle(&request).unwrap() || command == "disconnect");
+            for _ in 0..100 {
+                self.adapter.tick().unwrap();
+            }
+            let out = std::mem::take(&mut self.adapter.out);
+            let mut reader = BufReader::new(&out[..]);
+            let mut messages = Vec::new();
+            while let Some(message) = read_message(&mut reader).unwrap() {
+                messages.push(message);
+            }
+            assert_eq!(messages[0]["command"], command);
+            messages
+        }
+
+        fn body(&mut self, command: &str, arguments: Value) -> Value {
+            let response = self.request(command, arguments).remove(0);
+            assert_eq!(response["success"], true, "{}", response);
+            response["body"].clone()
+        }
+
+        fn stopped(&mut self, command: &str) -> String {
+            let messages = self.request(command, json!({ "threadId": 1 }));
+            let event = messages.last().unwrap();
+            assert_eq!(event["event"], "stopped");
+            event["body"]["reason"].as_str().unwrap().to_string()
+        }
+
+        fn line(&mut self) -> Value {
+            self.body("stackTrace", json!({ "threadId": 1 }))["stackFrames"][0]["line"].clone()
+        }
+    }
+
+    #[test]
+    fn source_level_session() {
+        let dir = std::env::temp_dir().join(format!("chip8-dap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("game.asm");
+        fs::write(
+            &source,
+            "\
+start:  LD V0, 1
+loop:   CALL bump
+        ADD V1, 1
+        JP loop
+bump:   ADD V0, 1
+        RET
+",
+        )
+        .unwrap();
+
+        let mut client = Client {
+            adapter: Adapter::new(Vec::new()),
+            seq: 0,
+        };
+        client.body("initialize", json!({ "adapterID": "chip8" }));
+        let messages = client.request(
+            "launch",
+            json!({ "program": source, "stopOnEntry": true, "platform": "schip" }),
+        );
+        assert_eq!(messages[1]["event"], "initialized");
+
+        let breakpoints = client.body(
+            "setBreakpoints",
+            json!({ "source": { "path": source }, "breakpoints": [{ "line": 5 }, { "line": 7 }] }),
+        );
+        assert_eq!(breakpoints["breakpoints"][0]["verified"], true);
+        assert_eq!(breakpoints["breakpoints"][1]["verified"], false);
+        assert_eq!(client.stopped("configurationDone"), "entry");
+
+        assert_eq!(client.stopped("continue"), "breakpoint");
+        let trace = client.body("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["totalFrames"], 2);
+        let frames = &trace["stackFrames"];
+        assert_eq!(
+            (&frames[0]["name"], &frames[0]["line"]),
+            (&json!("sub_208"), &json!(5))
+        );
+        assert_eq!(
+            (&frames[1]["name"], &frames[1]["line"]),
+            (&json!("(entry)"), &json!(2))
+        );
+
+        assert_eq!(client.stopped("stepOut"), "step");
+        assert_eq!(client.line(), 3, "back after the CALL");
+        client.body(
+            "setBreakpoints",
+            json!({ "source": { "path": source }, "breakpoints": [] }),
+        );
+        assert_eq!(client.stopped("next"), "step");
+        assert_eq!(client.stopped("next"), "step");
+        assert_eq!(client.line(), 2);
+        assert_eq!(client.stopped("next"), "step", "over the CALL");
+        assert_eq!(client.line(), 3);
+
+        let registers = client.body("variables", json!({ "variablesReference": 1 }));
+        assert_eq!(
+            registers["variables"][3],
+            json!({ "name": "V0", "value": "0x03", "variablesReference": 0 })
+        );
+        client.body(
+            "setVariable",
+            json!({ "variablesReference": 1, "name": "V1", "value": "0x10" }),
+        );
+        assert_eq!(
+            client.body("evaluate", json!({ "expression": "v1" }))["result"],
+            "0x10"
+        );
+        assert_eq!(
+            client.body("evaluate", json!({ "expression": "x 200 2" }))["result"],
+            "200: 60 01                    |`.|"
+        );
+
+        assert_eq!(
+            client.request("continue", json!({})).len(),
+            1,
+            "still running"
+        );
+        assert_eq!(client.stopped("pause"), "pause");
+        client.request("disconnect", json!({}));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn octo_steps_whole_lines() {
+        let dir = std::env::temp_dir().join(format!("chip8-dap-octo-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("game.8o");
+        fs::write(
+            &source,
+            "\
+: main
+  v0 := 1  v1 := 2
+  bump  v1 += 1
+  loop again
+: bump  v0 += 1  return
+",
+        )
+        .unwrap();
+
+        let mut client = Client {
+            adapter: Adapter::new(Vec::new()),
+            seq: 0,
+        };
+        client.request("launch", json!({ "program": source, "stopOnEntry": true }));
+        assert_eq!(client.stopped("configurationDone"), "entry");
+        assert_eq!(client.line(), 2);
+        assert_eq!(client.stopped("next"), "step");
+        assert_eq!(client.line(), 3, "both assignments");
+        assert_eq!(client.stopped("stepIn"), "step");
+        assert_eq!(client.line(), 5);
+        assert_eq!(client.stopped("stepIn"), "step");
+        assert_eq!(client.line(), 3, "back after the call");
+        assert_eq!(client.stopped("next"), "step");
+        assert_eq!(client.line(), 4);
+        assert_eq!(
+            client.stopped("next"),
+            "step",
+            "back at the start of the line"
+        );
+        assert_eq!(client.line(), 4);
+        assert_eq!(
+            client.body("evaluate", json!({ "expression": "v1" }))["result"],
+            "0x3"
+        );
+        client.request("disconnect", json!({}));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn roms_are_disassembled() {
+        let dir = std::env::temp_dir().join(format!("chip8-dap-rom-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("loop.ch8");
+        // LD V0, 1; JP 0x202
+        fs::write(&rom, [0x60, 0x01, 0x12, 0x02]).unwrap();
+
+        let mut client = Client {
+            adapter: Adapter::new(Vec::new()),
+            seq: 0,
+        };
+        let too_big = dir.join("big.ch8");
+        fs::write(&too_big, vec![0; 0x1000]).unwrap();
+        let response = client
+            .request("launch", json!({ "program": too_big }))
+            .remove(0);
+        assert_eq!(response["success"], false, "ROM doesn't fit in memory");
+        client.request("launch", json!({ "program": rom }));
+        let source = json!({ "name": "loop.ch8.dis", "sourceReference": 1 });
+        let breakpoints = client.body(
+            "setBreakpoints",
+            json!({ "source": source, "breakpoints": [{ "line": 2 }] }),
+        );
+        assert_eq!(breakpoints["breakpoints"][0]["source"], source);
+        assert_eq!(client.stopped("configurationDone"), "breakpoint");
+        let content = client.body("source", json!({ "sourceReference": 1 }))["content"].clone();
+        assert_eq!(content, "200: 6001  LD V0, 0x01\n202: 1202  JP 0x202\n");
+        let response = client.request("bogus", json!({})).remove(0);
+        assert_eq!(response["success"], false);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
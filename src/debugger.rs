@@ -0,0 +1,458 @@
+//! Debugger core: breakpoints and stepping with the machine's frame pacing,
+//! plus the command language of the `debug` subcommand's prompt.
+//!
+//! Addresses, lengths and bytes in commands are hex, with or without `0x`;
+//! counts of steps and frames are decimal.
+
+use crate::cheat::{Compare, Search};
+use crate::cpu::{decode, Cpu, Op};
+use crate::disasm::mnemonic;
+use crate::hexdump::{self, parse_hex, Style};
+use crate::trace;
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+/// How many frames `continue` runs at most before giving control back.
+pub const CONTINUE_FRAMES: u64 = 60 * 60;
+
+/// How many cheat search candidates `cand` lists.
+const CANDIDATES_SHOWN: usize = 32;
+
+pub const HELP: &str = "\
+s [n]                 step n instructions (default 1)
+c [frames]            continue until a breakpoint, at most frames (default 3600)
+frame [n]             run n whole frames, ignoring breakpoints (default 1)
+b ADDR | bd ADDR | bl set, delete or list breakpoints
+r                     show registers
+keys [K...]           hold these keys (hex), none to release all
+screen                show the screen
+x [ADDR [LEN]]        hex dump (default: from I)
+xs [ADDR [LEN]]       one byte per line with sprite pixels (default: from I)
+w ADDR BYTES...       write bytes, \"text\" for ASCII
+fill ADDR LEN BYTE    fill a range
+copy SRC DST LEN      copy a range, overlaps allowed
+find PATTERN...       search memory, ?? matches any byte
+search [CMP]          start a cheat search, or keep the bytes that compare
+                      with the last search: = BYTE, != BYTE, changed,
+                      unchanged, inc or dec
+cand                  list the cheat search's candidates
+freeze [ADDR [BYTE]]  hold a byte every frame (default: its value now), or list
+unfreeze ADDR         stop holding a byte
+q                     quit";
+
+/// Why `resume` gave control back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u16),
+    /// The condition given to `run_until` holds.
+    Condition,
+    /// The frame limit was reached.
+    Limit,
+}
+
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    pub cycles_per_frame: usize,
+    /// How highlighted memory is shown.
+    pub style: Style,
+    /// Cycles run in the current frame.
+    frame_cycles: usize,
+    /// The cheat search in progress, see `cheat`.
+    pub search: Option<Search>,
+}
+
+impl Debugger {
+    pub fn new(cycles_per_frame: usize) -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            cycles_per_frame: cycles_per_frame.max(1),
+            style: Style::Plain,
+            frame_cycles: 0,
+            search: None,
+        }
+    }
+
+    /// Runs one cycle, with the vertical blank after every
+    /// `cycles_per_frame` of them as in `Cpu::run_frame`.
+    fn cycle(&mut self, cpu: &mut Cpu) {
+        cpu.emulate_cycle();
+        self.frame_cycles += 1;
+        if self.frame_cycles >= self.cycles_per_frame {
+            cpu.vblank();
+            self.frame_cycles = 0;
+        }
+    }
+
+    /// Executes one instruction, running through any wait for the vertical
+    /// blank first.
+    pub fn step(&mut self, cpu: &mut Cpu) {
+        let executed = cpu.cycles;
+        while cpu.cycles == executed {
+            self.cycle(cpu);
+        }
+    }
+
+    /// Runs until the next instruction is at a breakpoint, or for at most
+    /// `frames` frames. The instruction at the current PC always executes, so
+    /// resuming from a breakpoint moves past it.
+    pub fn resume(&mut self, cpu: &mut Cpu, frames: u64) -> Stop {
+        let end = cpu.frame + frames;
+        self.step(cpu);
+        self.run(cpu, end.saturating_sub(cpu.frame))
+    }
+
+    /// Like `resume`, but stops before the instruction at the current PC if
+    /// it is at a breakpoint. Continuing in slices of frames uses this after
+    /// the first slice.
+    pub fn run(&mut self, cpu: &mut Cpu, frames: u64) -> Stop {
+        self.run_until(cpu, frames, |_| false)
+    }
+
+    /// Like `run`, but also stops before an instruction when `done` holds,
+    /// for stepping over and out of subroutines.
+    pub fn run_until(&mut self, cpu: &mut Cpu, frames: u64, done: impl Fn(&Cpu) -> bool) -> Stop {
+        let end = cpu.frame + frames;
+        while cpu.frame < end {
+            if !cpu.waiting_for_vblank {
+                if self.breakpoints.contains(&cpu.pc) {
+                    return Stop::Breakpoint(cpu.pc);
+                }
+                if done(cpu) {
+                    return Stop::Condition;
+                }
+            }
+            self.cycle(cpu);
+        }
+        Stop::Limit
+    }
+
+    /// Runs until the end of the current frame and then `frames - 1` more.
+    pub fn run_frames(&mut self, cpu: &mut Cpu, frames: u64) {
+        let end = cpu.frame + frames;
+        while cpu.frame < end {
+            self.cycle(cpu);
+        }
+    }
+
+    /// The bytes the next instruction reads or writes through I, or the
+    /// byte at I for other instructions.
+    pub fn i_region(cpu: &Cpu) -> Range<usize> {
+        let pc = cpu.pc as usize & 0xFFF;
+        let instruction =
+            decode((cpu.memory[pc] as u16) << 8 | cpu.memory[(pc + 1) & 0xFFF] as u16);
+        let len = match instruction.op {
+            Op::Drw => instruction.n as usize,
+            Op::Store | Op::Load => instruction.x as usize + 1,
+            Op::LdBVx => 3,
+            _ => 1,
+        };
+        let start = cpu.i as usize & 0xFFF;
+        start..start + len
+    }
+
+    /// Describes where the machine stopped: the registers and the next
+    /// instruction.
+    pub fn status(cpu: &Cpu) -> String {
+        let pc = cpu.pc as usize & 0xFFF;
+        trace::line(
+            cpu,
+            (cpu.memory[pc] as u16) << 8 | cpu.memory[(pc + 1) & 0xFFF] as u16,
+        )
+    }
+
+    /// Runs one prompt command and returns its output, or `None` for `q`.
+    pub fn command(&mut self, cpu: &mut Cpu, line: &str) -> Option<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Some(String::new()),
+        };
+        Some(match self.run_command(cpu, name, args) {
+            Ok(out) => out,
+            Err(message) => format!("error: {}\n", message),
+        })
+        .filter(|_| name != "q" && name != "quit")
+    }
+
+    fn run_command(&mut self, cpu: &mut Cpu, name: &str, args: &[&str]) -> Result<String, String> {
+        let hex = |i: usize| -> Result<Option<usize>, String> {
+            args.get(i)
+                .map(|word| parse_hex(word).map(|n| n as usize))
+                .transpose()
+        };
+        let count = |default: u64| -> Result<u64, String> {
+            match args.first() {
+                Some(word) => word
+                    .parse()
+                    .map_err(|_| format!("`{}` is not a count", word)),
+                None => Ok(default),
+            }
+        };
+        let address = |i: usize| -> Result<u16, String> {
+            match hex(i)? {
+                Some(addr) if addr < 0x1000 => Ok(addr as u16),
+                Some(addr) => Err(format!("{:#x} is outside memory", addr)),
+                None => Err("expected an address".to_string()),
+            }
+        };
+
+        Ok(match name {
+            "help" | "h" | "?" => format!("{}\n", HELP),
+            "q" | "quit" => String::new(),
+            "s" | "step" => {
+                for _ in 0..count(1)? {
+                    self.step(cpu);
+                }
+                format!("{}\n", Debugger::status(cpu))
+            }
+            "c" | "continue" => match self.resume(cpu, count(CONTINUE_FRAMES)?) {
+                Stop::Breakpoint(addr) => {
+                    format!("breakpoint at {:03x}\n{}\n", addr, Debugger::status(cpu))
+                }
+                Stop::Condition | Stop::Limit => {
+                    format!("stopped after the frame limit\n{}\n", Debugger::status(cpu))
+                }
+            },
+            "frame" => {
+                self.run_frames(cpu, count(1)?);
+                format!("{}\n", Debugger::status(cpu))
+            }
+            "b" | "break" => {
+                let addr = address(0)?;
+                self.breakpoints.insert(addr);
+                format!("breakpoint at {:03x}\n", addr)
+            }
+            "bd" | "delete" => {
+                let addr = address(0)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {:03x}", addr));
+                }
+                String::new()
+            }
+            "bl" => self
+                .breakpoints
+                .iter()
+                .map(|&addr| {
+                    let word = (cpu.memory[addr as usize] as u16) << 8
+                        | cpu.memory[(addr as usize + 1) & 0xFFF] as u16;
+                    format!("{:03x}  {}\n", addr, mnemonic(word))
+                })
+                .collect(),
+            "r" | "regs" => format!("{}\n", Debugger::status(cpu)),
+            "keys" => {
+                let mut keys = [false; 16];
+                for (i, _) in args.iter().enumerate() {
+                    match hex(i)? {
+                        Some(key) if key < 16 => keys[key] = true,
+                        _ => return Err("keys are 0 to f".to_string()),
+                    }
+                }
+                cpu.keypad.keys = keys;
+                String::new()
+            }
+            "screen" => cpu.display.to_ascii(),
+            "x" | "xs" => {
+                let start = hex(0)?.unwrap_or(cpu.i as usize) & 0xFFF;
+                let highlight = Debugger::i_region(cpu);
+                if name == "x" {
+                    let len = hex(1)?.unwrap_or(64).min(cpu.memory.len());
+                    hexdump::dump(&cpu.memory, start, len, &highlight, self.style)
+                } else {
+                    let len = hex(1)?.unwrap_or(highlight.len().max(8));
+                    hexdump::dump_sprites(
+                        &cpu.memory,
+                        start,
+                        len.min(cpu.memory.len()),
+                        &highlight,
+                        self.style,
+                    )
+                }
+            }
+            "w" | "write" => {
+                let addr = address(0)? as usize;
+                let bytes = hexdump::parse_bytes(args.get(1..).unwrap_or(&[]))?;
+                if bytes.is_empty() {
+                    return Err("expected bytes to write".to_string());
+                }
+                for (i, byte) in bytes.iter().enumerate() {
+                    cpu.memory[(addr + i) & 0xFFF] = *byte;
+                }
+                cpu.flush_decoded();
+                hexdump::dump(&cpu.memory, addr, bytes.len(), &(0..0), self.style)
+            }
+            "fill" => {
+                let addr = address(0)? as usize;
+                let len = hex(1)?.ok_or("expected a length")?;
+                let byte = hexdump::parse_bytes(args.get(2..3).ok_or("expected a byte")?)?[0];
+                for i in 0..len.min(0x1000) {
+                    cpu.memory[(addr + i) & 0xFFF] = byte;
+                }
+                cpu.flush_decoded();
+                String::new()
+            }
+            "copy" => {
+                let (src, dst) = (address(0)? as usize, address(1)? as usize);
+                let len = hex(2)?.ok_or("expected a length")?;
+                if src + len > 0x1000 || dst + len > 0x1000 {
+                    return Err("range runs past the end of memory".to_string());
+                }
+                cpu.memory.copy_within(src..src + len, dst);
+                cpu.flush_decoded();
+                String::new()
+            }
+            "find" => {
+                let pattern = hexdump::parse_pattern(args)?;
+                let found = hexdump::find(&cpu.memory, &pattern);
+                if found.is_empty() {
+                    "not found\n".to_string()
+                } else {
+                    let list: Vec<String> =
+                        found.iter().map(|addr| format!("{:03x}", addr)).collect();
+                    format!("{}\n", list.join(" "))
+                }
+            }
+            "search" => {
+                let left = match &mut self.search {
+                    Some(search) if !args.is_empty() => {
+                        search.filter(&cpu.memory, Compare::parse(args)?)
+                    }
+                    _ if !args.is_empty() => return Err("start a search first".to_string()),
+                    search => search.insert(Search::new(&cpu.memory)).candidates().len(),
+                };
+                format!("candidates: {}\n", left)
+            }
+            "cand" => {
+                let search = self.search.as_ref().ok_or("no search in progress")?;
+                let candidates = search.candidates();
+                let mut out: String = candidates
+                    .iter()
+                    .take(CANDIDATES_SHOWN)
+                    .map(|&addr| {
+                        format!(
+                            "{:03x}  {:02x} (was {:02x})\n",
+                            addr,
+                            cpu.memory[addr as usize],
+                            search.previous(addr)
+                        )
+                    })
+                    .collect();
+                if candidates.len() > CANDIDATES_SHOWN {
+                    out += &format!("... {} more\n", candidates.len() - CANDIDATES_SHOWN);
+                }
+                out
+            }
+            "freeze" if args.is_empty() => cpu
+                .freezes
+                .iter()
+                .map(|(addr, byte)| format!("{:03x}  {:02x}\n", addr, byte))
+                .collect(),
+            "freeze" => {
+                let addr = address(0)?;
+                let byte = match args.get(1..2) {
+                    Some(word) => hexdump::parse_bytes(word)?[0],
+                    None => cpu.memory[addr as usize],
+                };
+                cpu.freezes.insert(addr, byte);
+                format!("{:03x} frozen at {:02x}\n", addr, byte)
+            }
+            "unfreeze" => {
+                let addr = address(0)?;
+                if cpu.freezes.remove(&addr).is_none() {
+                    return Err(format!("{:03x} is not frozen", addr));
+                }
+                String::new()
+            }
+            _ => return Err(format!("unknown command `{}`, try `help`", name)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Debugger, Stop};
+    use crate::asm::assemble;
+    use crate::cpu::Cpu;
+
+    fn machine() -> Cpu {
+        let rom = assemble(
+            "
+                LD I, sprite
+            loop:
+                ADD V0, 1
+                DRW V0, V0, 2
+                JP loop
+            sprite:
+                db 0x3c, 0x42
+            ",
+        )
+        .unwrap()
+        .rom;
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn breakpoints_and_steps() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new(10);
+        debugger.breakpoints.insert(0x204);
+        assert_eq!(debugger.resume(&mut cpu, 10), Stop::Breakpoint(0x204));
+        assert_eq!(cpu.v[0], 1);
+        assert_eq!(
+            debugger.resume(&mut cpu, 10),
+            Stop::Breakpoint(0x204),
+            "moves past it"
+        );
+        assert_eq!(cpu.v[0], 2);
+        debugger.step(&mut cpu);
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn memory_commands() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new(10);
+        let mut run = |line: &str| debugger.command(&mut cpu, line).unwrap();
+
+        run("s 2");
+        assert_eq!(
+            run("x 208 2"),
+            "208:[3c 42]                   |<B|\n",
+            "I is marked"
+        );
+        assert!(run("xs").contains("208: 3c < ..####..  <- I"));
+        assert_eq!(run("find 3c ??"), "208\n");
+        run("w 300 de ad \"ok\"");
+        run("copy 300 310 4");
+        assert_eq!(run("find de ad 6f 6b"), "300 310\n");
+        run("fill 300 4 0");
+        assert_eq!(run("find de ad"), "310\n");
+        assert!(run("w 1000 00").starts_with("error:"));
+        assert_eq!(debugger.command(&mut cpu, "q"), None);
+    }
+
+    #[test]
+    fn cheat_commands() {
+        let mut cpu = machine();
+        let mut debugger = Debugger::new(10);
+        let mut run = |line: &str| debugger.command(&mut cpu, line).unwrap();
+
+        assert!(run("search =").starts_with("error:"), "no search yet");
+        assert_eq!(run("search"), "candidates: 4096\n");
+        run("w 300 05 09");
+        assert_eq!(run("search changed"), "candidates: 2\n");
+        run("w 300 04 0a");
+        assert_eq!(run("search dec"), "candidates: 1\n");
+        assert_eq!(run("cand"), "300  04 (was 05)\n");
+
+        assert_eq!(run("freeze 300 03"), "300 frozen at 03\n");
+        run("freeze 301");
+        run("w 300 00 00");
+        run("frame");
+        assert_eq!(run("find 03 0a"), "300\n", "frozen bytes are restored");
+        assert_eq!(run("freeze"), "300  03\n301  0a\n");
+        run("unfreeze 300");
+        assert!(run("unfreeze 300").starts_with("error:"));
+    }
+}
@@ -0,0 +1,260 @@
+use crate::cpu::PROGRAM_START;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Returns the mnemonic for `opcode`, in the notation used by the comments in
+/// `Cpu::execute_opcode`. Words that are not instructions come back as `DW`.
+pub fn mnemonic(opcode: u16) -> String {
+    let addr = opcode & 0xFFF;
+    let byte = opcode & 0x0FF;
+    let n = opcode & 0x00F;
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+
+    let op_1 = (opcode & 0xF000) >> 12;
+    let op_2 = (opcode & 0x0F00) >> 8;
+    let op_3 = (opcode & 0x00F0) >> 4;
+    let op_4 = opcode & 0x000F;
+
+    match (op_1, op_2, op_3, op_4) {
+        (0x0, 0x0, 0xE, 0x0) => "CLS".to_string(),
+        (0x0, 0x0, 0xE, 0xE) => "RET".to_string(),
+        (0x0, _, _, _) => format!("SYS {:#05x}", addr),
+        (0x1, _, _, _) => format!("JP {:#05x}", addr),
+        (0x2, _, _, _) => format!("CALL {:#05x}", addr),
+        (0x3, _, _, _) => format!("SE V{:X}, {:#04x}", x, byte),
+        (0x4, _, _, _) => format!("SNE V{:X}, {:#04x}", x, byte),
+        (0x5, _, _, 0x0) => format!("SE V{:X}, V{:X}", x, y),
+        (0x6, _, _, _) => format!("LD V{:X}, {:#04x}", x, byte),
+        (0x7, _, _, _) => format!("ADD V{:X}, {:#04x}", x, byte),
+        (0x8, _, _, 0x0) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x6) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0xE) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9, _, _, 0x0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, _, _, _) => format!("LD I, {:#05x}", addr),
+        (0xB, _, _, _) => format!("JP V0, {:#05x}", addr),
+        (0xC, _, _, _) => format!("RND V{:X}, {:#04x}", x, byte),
+        (0xD, _, _, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _, 0x9, 0xE) => format!("SKP V{:X}", x),
+        (0xE, _, 0xA, 0x1) => format!("SKNP V{:X}", x),
+        (0xF, _, 0x0, 0x7) => format!("LD V{:X}, DT", x),
+        (0xF, _, 0x0, 0xA) => format!("LD V{:X}, K", x),
+        (0xF, _, 0x1, 0x5) => format!("LD DT, V{:X}", x),
+        (0xF, _, 0x1, 0x8) => format!("LD ST, V{:X}", x),
+        (0xF, _, 0x1, 0xE) => format!("ADD I, V{:X}", x),
+        (0xF, _, 0x2, 0x9) => format!("LD F, V{:X}", x),
+        (0xF, _, 0x3, 0x3) => format!("LD B, V{:X}", x),
+        (0xF, _, 0x5, 0x5) => format!("LD [I], V{:X}", x),
+        (0xF, _, 0x6, 0x5) => format!("LD V{:X}, [I]", x),
+        (_, _, _, _) => format!("DW {:#06x}", opcode),
+    }
+}
+
+/// Linear disassembly of `rom` as loaded at `PROGRAM_START`, one
+/// `address: opcode  mnemonic` line per word.
+pub fn disassemble(rom: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, word) in rom.chunks(2).enumerate() {
+        let addr = PROGRAM_START as usize + i * 2;
+        match *word {
+            [hi, lo] => {
+                let opcode = (hi as u16) << 8 | lo as u16;
+                out += &format!("{:03x}: {:04x}  {}\n", addr, opcode, mnemonic(opcode));
+            }
+            [byte] => out += &format!("{:03x}: {:02x}    DB {:#04x}\n", addr, byte, byte),
+            _ => unreachable!(),
+        }
+    }
+    out
+}
+
+/// How a label is referenced, which decides its generated name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum LabelKind {
+    Data,
+    Jump,
+    Sub,
+}
+
+/// Reads the big-endian word at `addr` of a ROM loaded at `PROGRAM_START`.
+pub(crate) fn word(rom: &[u8], addr: usize) -> u16 {
+    let i = addr - PROGRAM_START as usize;
+    (rom[i] as u16) << 8 | rom[i + 1] as u16
+}
+
+/// Finds the instructions reachable from `PROGRAM_START` by following
+/// `JP`, `CALL`, the skip instructions and `RET`, and the addresses they
+/// reference. XO-CHIP's four byte `LD I, long` is stepped over as a whole.
+pub(crate) fn reachable(rom: &[u8]) -> (BTreeSet<usize>, BTreeMap<usize, LabelKind>) {
+    let start = PROGRAM_START as usize;
+    let end = start + rom.len();
+    let long = |addr: usize| addr + 1 < end && word(rom, addr) == 0xF000;
+
+    let mut code = BTreeSet::new();
+    let mut labels: BTreeMap<usize, LabelKind> = BTreeMap::new();
+    let mut work = vec![start];
+
+    while let Some(addr) = work.pop() {
+        if addr < start || addr + 1 >= end || !code.insert(addr) {
+            continue;
+        }
+        let opcode = word(rom, addr);
+        let target = (opcode & 0xFFF) as usize;
+        let mut label = |target: usize, kind: LabelKind| {
+            let entry = labels.entry(target).or_insert(kind);
+            *entry = (*entry).max(kind);
+        };
+        match opcode & 0xF000 {
+            0x0000 if opcode == 0x00EE || opcode == 0x00FD => continue,
+            0x1000 => {
+                label(target, LabelKind::Jump);
+                work.push(target);
+                continue;
+            }
+            0x2000 => {
+                label(target, LabelKind::Sub);
+                work.push(target);
+            }
+            0xB000 => {
+                // the offset in V0 is unknown, so only the base is followed
+                label(target, LabelKind::Jump);
+                work.push(target);
+                continue;
+            }
+            0xA000 => label(target, LabelKind::Data),
+            0xF000 if opcode == 0xF000 => {
+                work.push(addr + 4);
+                continue;
+            }
+            0x3000 | 0x4000 | 0x5000 | 0x9000 | 0xE000 => {
+                work.push(if long(addr + 2) { addr + 6 } else { addr + 4 })
+            }
+            _ => (),
+        }
+        work.push(addr + 2);
+    }
+
+    (code, labels)
+}
+
+/// Disassembly that follows control flow from `PROGRAM_START`: `JP`, `CALL`,
+/// the skip instructions and `RET`. Bytes never reached as code, including
+/// those pointed at by `LD I`, are rendered as `db` lines with a sprite
+/// bitmap. The result is source for `asm::assemble` with generated labels.
+pub fn disassemble_flow(rom: &[u8]) -> String {
+    let start = PROGRAM_START as usize;
+    let end = start + rom.len();
+    let (code, mut labels) = reachable(rom);
+
+    // targets outside the ROM stay numeric
+    labels.retain(|addr, _| (start..end).contains(addr));
+    let name = |addr: usize| match labels.get(&addr) {
+        Some(LabelKind::Sub) => format!("sub_{:03x}", addr),
+        Some(LabelKind::Jump) => format!("label_{:03x}", addr),
+        Some(LabelKind::Data) => format!("data_{:03x}", addr),
+        None => format!("{:#05x}", addr),
+    };
+
+    let mut out = String::from("; disassembled by chip8, assemble with `chip8 asm`\n");
+    let mut addr = start;
+    while addr < end {
+        if labels.contains_key(&addr) {
+            out += &format!("\n{}:\n", name(addr));
+        }
+        // an instruction overlapping a label or another instruction is
+        // emitted as bytes so that every label starts a line
+        let overlaps = labels.contains_key(&(addr + 1)) || code.contains(&(addr + 1));
+        if code.contains(&addr) && !overlaps {
+            let opcode = word(rom, addr);
+            let target = (opcode & 0xFFF) as usize;
+            let line = match opcode & 0xF000 {
+                0x1000 => format!("JP {}", name(target)),
+                0x2000 => format!("CALL {}", name(target)),
+                0xA000 => format!("LD I, {}", name(target)),
+                0xB000 => format!("JP V0, {}", name(target)),
+                _ => mnemonic(opcode),
+            };
+            out += &format!("    {:<24}; {:03x}: {:04x}\n", line, addr, opcode);
+            addr += 2;
+        } else {
+            let byte = rom[addr - start];
+            let bitmap: String = (0..8)
+                .map(|i| if byte & (0x80 >> i) != 0 { '#' } else { '.' })
+                .collect();
+            out += &format!(
+                "    db {:#010b}           ; {:03x}: {}\n",
+                byte, addr, bitmap
+            );
+            addr += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, disassemble_flow, mnemonic};
+    use crate::asm::assemble;
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(mnemonic(0x00E0), "CLS");
+        assert_eq!(mnemonic(0x2ABC), "CALL 0xabc");
+        assert_eq!(mnemonic(0x61AA), "LD V1, 0xaa");
+        assert_eq!(mnemonic(0x8AB6), "SHR VA, VB");
+        assert_eq!(mnemonic(0xD125), "DRW V1, V2, 5");
+        assert_eq!(mnemonic(0xF255), "LD [I], V2");
+        assert_eq!(mnemonic(0xFFFF), "DW 0xffff");
+    }
+
+    #[test]
+    fn disassemble_odd_length() {
+        assert_eq!(
+            disassemble(&[0x00, 0xE0, 0x12]),
+            "200: 00e0  CLS\n202: 12    DB 0x12\n"
+        );
+    }
+
+    #[test]
+    fn flow_separates_code_from_data() {
+        let rom = assemble(
+            "
+                CALL draw
+            loop:
+                JP loop
+            draw:
+                LD I, sprite
+                SE V0, 0
+                DRW V0, V1, 2
+                RET
+            sprite:
+                db 0b00111100, 0b01000010
+            ",
+        )
+        .unwrap()
+        .rom;
+        let source = disassemble_flow(&rom);
+        assert!(source.contains("CALL sub_204"), "calls get labels");
+        assert!(source.contains("JP label_202"), "jumps get labels");
+        assert!(source.contains("LD I, data_20c"), "I references get labels");
+        assert!(source.contains("db 0b00111100"), "sprite rows are data");
+        assert!(source.contains("..####.."), "sprite rows are drawn");
+        assert_eq!(assemble(&source).unwrap().rom, rom, "source reassembles");
+    }
+
+    #[test]
+    fn flow_reassembles_bundled_roms() {
+        for rom in &[
+            &include_bytes!("../c8_test.c8")[..],
+            &include_bytes!("../sierpinski.ch8")[..],
+        ] {
+            let source = disassemble_flow(rom);
+            assert_eq!(&assemble(&source).unwrap().rom[..], *rom);
+        }
+    }
+}
@@ -3,14 +3,56 @@ pub const HEIGHT: usize = 32;
 pub const ON: u32 = 0xffffffff;
 pub const OFF: u32 = 0x00000000;
 
+/// Bounding box of the pixels changed since the frame was last presented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    fn full() -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: WIDTH,
+            height: HEIGHT,
+        }
+    }
+
+    fn union(self, x: usize, y: usize) -> Rect {
+        let x0 = self.x.min(x);
+        let y0 = self.y.min(y);
+        let x1 = (self.x + self.width).max(x + 1);
+        let y1 = (self.y + self.height).max(y + 1);
+        Rect {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Display {
     pub memory: [u32; WIDTH * HEIGHT],
+    dirty: Option<Rect>,
+}
+
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
 }
 
 impl Display {
     pub fn new() -> Display {
         Display {
             memory: [0; WIDTH * HEIGHT],
+            dirty: None,
         }
     }
 
@@ -19,22 +61,86 @@ impl Display {
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, val: bool) {
-        self.memory[x + WIDTH * y] = if val { ON } else { OFF };
+        let new = if val { ON } else { OFF };
+        if self.memory[x + WIDTH * y] != new {
+            self.memory[x + WIDTH * y] = new;
+            self.mark_dirty(x, y);
+        }
     }
 
-    pub fn cls(&mut self) {
-        for mut _cell in self.memory {
-            _cell = 0;
+    fn mark_dirty(&mut self, x: usize, y: usize) {
+        self.dirty = Some(match self.dirty {
+            Some(rect) => rect.union(x, y),
+            None => Rect {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            },
+        });
+    }
+
+    /// Whether anything changed since the last call to `take_dirty`.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.is_some()
+    }
+
+    /// Returns the region changed since the last call and clears it.
+    pub fn take_dirty(&mut self) -> Option<Rect> {
+        self.dirty.take()
+    }
+
+    /// Renders the screen as text, `#` for lit pixels and `.` otherwise.
+    pub fn to_ascii(&self) -> String {
+        let mut out = String::with_capacity((WIDTH + 1) * HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                out.push(if self.get_pixel(x, y) { '#' } else { '.' });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Encodes the screen as a plain PBM image, 1 for lit pixels.
+    pub fn to_pbm(&self) -> String {
+        let mut out = format!("P1\n{} {}\n", WIDTH, HEIGHT);
+        for y in 0..HEIGHT {
+            let row: Vec<&str> = (0..WIDTH)
+                .map(|x| if self.get_pixel(x, y) { "1" } else { "0" })
+                .collect();
+            out += &row.join(" ");
+            out.push('\n');
         }
+        out
+    }
+
+    pub fn cls(&mut self) {
+        self.memory = [OFF; WIDTH * HEIGHT];
+        self.dirty = Some(Rect::full());
     }
 
-    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+    /// XORs `sprite` onto the screen at (`x`, `y`) and returns whether any
+    /// lit pixel was turned off. The start coordinate always wraps around the
+    /// screen; pixels running off the edge are dropped when `clip` is set and
+    /// wrap to the opposite edge otherwise.
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[u8], clip: bool) -> bool {
         let mut collision = false;
+        let x = x % WIDTH;
+        let y = y % HEIGHT;
 
         for (j, row) in sprite.iter().enumerate() {
+            if clip && y + j >= HEIGHT {
+                break;
+            }
             for i in 0..8 {
                 let new_value = row >> (7 - i) & 0x01;
-                if new_value != 1 { continue }
+                if new_value != 1 {
+                    continue;
+                }
+                if clip && x + i >= WIDTH {
+                    break;
+                }
                 let xi = (x + i) % WIDTH;
                 let yj = (y + j) % HEIGHT;
                 let old_value = self.get_pixel(xi, yj);
@@ -67,3 +173,90 @@ pub static FONT_SET: [[u8; 5]; 16] = [
     [0xF0, 0x80, 0xF0, 0x80, 0xF0], // E
     [0xF0, 0x80, 0xF0, 0x80, 0x80], // F
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::{Display, Rect, HEIGHT, WIDTH};
+
+    fn lit_pixels(display: &Display) -> Vec<(usize, usize)> {
+        let mut lit = Vec::new();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if display.get_pixel(x, y) {
+                    lit.push((x, y));
+                }
+            }
+        }
+        lit
+    }
+
+    #[test]
+    fn draw_marks_changed_region() {
+        let mut display = Display::new();
+        assert!(!display.is_dirty(), "a new display is clean");
+
+        display.draw(10, 4, &[0x80, 0x40], false);
+        assert_eq!(
+            display.take_dirty(),
+            Some(Rect {
+                x: 10,
+                y: 4,
+                width: 2,
+                height: 2
+            }),
+            "the region covers the lit pixels"
+        );
+        assert!(!display.is_dirty(), "taking the region clears it");
+    }
+
+    #[test]
+    fn cls_marks_whole_screen() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0xFF], false);
+        display.take_dirty();
+
+        display.cls();
+        assert!(!display.get_pixel(0, 0), "the screen is cleared");
+        assert_eq!(
+            display.take_dirty(),
+            Some(Rect {
+                x: 0,
+                y: 0,
+                width: WIDTH,
+                height: HEIGHT
+            }),
+            "the whole screen is dirty"
+        );
+    }
+
+    #[test]
+    fn draw_clips_at_edges() {
+        let mut display = Display::new();
+        // a 2x2 block whose lower right corner runs off the screen
+        display.draw(WIDTH - 1, HEIGHT - 1, &[0xC0, 0xC0], true);
+        assert_eq!(lit_pixels(&display), vec![(63, 31)], "overhang is clipped");
+    }
+
+    #[test]
+    fn draw_wraps_at_edges() {
+        let mut display = Display::new();
+        display.draw(WIDTH - 1, HEIGHT - 1, &[0xC0, 0xC0], false);
+        assert_eq!(
+            lit_pixels(&display),
+            vec![(0, 0), (63, 0), (0, 31), (63, 31)],
+            "overhang wraps to the opposite edges"
+        );
+    }
+
+    #[test]
+    fn draw_wraps_start_coordinate() {
+        let mut clipped = Display::new();
+        let mut wrapped = Display::new();
+        clipped.draw(WIDTH + 2, HEIGHT + 3, &[0x81], true);
+        wrapped.draw(WIDTH + 2, HEIGHT + 3, &[0x81], false);
+
+        let expected = vec![(2, 3), (9, 3)];
+        assert_eq!(lit_pixels(&clipped), expected, "start wraps when clipping");
+        assert_eq!(lit_pixels(&wrapped), expected, "start wraps when wrapping");
+    }
+}
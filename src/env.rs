@@ -0,0 +1,467 @@
+//! A reinforcement learning environment in the style of OpenAI Gym: `reset`
+//! starts an episode and `step` plays an action for a few frames, returning
+//! the screen, the reward and whether the episode is over.
+//!
+//! Games don't report scores, so the reward and the end of an episode come
+//! from watching memory, typically a score and a lives counter found with
+//! the `debug` command's `search`. Actions are sets of held keys from an
+//! `ActionSpace`. Environments clone cheaply and are `Send`, so a batch of
+//! them can run on separate threads.
+
+use crate::cpu::Cpu;
+use crate::display::{HEIGHT, WIDTH};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Arc;
+
+/// The screen, one byte per pixel, 1 for lit, rows top to bottom.
+pub type Observation = Vec<u8>;
+
+/// How a watched value is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Byte,
+    /// A big-endian 16-bit number.
+    Word,
+    /// Decimal digits one per byte, most significant first, as `LD B, Vx`
+    /// stores them.
+    Digits(u8),
+}
+
+/// A number in memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watch {
+    pub address: u16,
+    pub encoding: Encoding,
+}
+
+impl Watch {
+    pub fn byte(address: u16) -> Watch {
+        Watch {
+            address,
+            encoding: Encoding::Byte,
+        }
+    }
+
+    pub fn digits(address: u16, count: u8) -> Watch {
+        Watch {
+            address,
+            encoding: Encoding::Digits(count),
+        }
+    }
+
+    pub fn read(&self, memory: &[u8]) -> i64 {
+        let byte = |i: usize| memory[(self.address as usize + i) & 0xFFF] as i64;
+        match self.encoding {
+            Encoding::Byte => byte(0),
+            Encoding::Word => byte(0) << 8 | byte(1),
+            Encoding::Digits(count) => (0..count as usize).fold(0, |n, i| n * 10 + byte(i)),
+        }
+    }
+}
+
+/// When a watched value ends the episode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Equals(i64),
+    AtMost(i64),
+    AtLeast(i64),
+    /// Lower than at the start of the episode, e.g. a life lost.
+    Decreased,
+}
+
+/// The actions an agent picks from, each a set of held keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActionSpace {
+    /// Bit n of each is key n.
+    actions: Vec<u16>,
+}
+
+impl ActionSpace {
+    /// Nothing held, then each of `keys` on its own.
+    pub fn single(keys: &[u8]) -> Result<ActionSpace, String> {
+        check_keys(keys)?;
+        let mut actions = vec![0];
+        actions.extend(keys.iter().map(|&key| 1 << key));
+        Ok(ActionSpace { actions })
+    }
+
+    /// Every subset of `keys`, starting with the empty one, for games that
+    /// need keys held together.
+    pub fn combinations(keys: &[u8]) -> Result<ActionSpace, String> {
+        check_keys(keys)?;
+        let actions = (0..1u32 << keys.len())
+            .map(|subset| {
+                keys.iter()
+                    .enumerate()
+                    .filter(|(i, _)| subset & 1 << i != 0)
+                    .fold(0, |mask, (_, &key)| mask | 1 << key)
+            })
+            .collect();
+        Ok(ActionSpace { actions })
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// The keypad for `action`.
+    pub fn keys(&self, action: usize) -> [bool; 16] {
+        let mask = self.actions[action];
+        let mut keys = [false; 16];
+        for (key, held) in keys.iter_mut().enumerate() {
+            *held = mask & 1 << key != 0;
+        }
+        keys
+    }
+}
+
+/// Fails unless `keys` are distinct keys 0 to 0xF, so no two actions are
+/// the same set of keys and there are at most 16 to combine.
+fn check_keys(keys: &[u8]) -> Result<(), String> {
+    let mut seen = 0u16;
+    for &key in keys {
+        if key > 0xF {
+            return Err(format!("no key {:#x}", key));
+        }
+        if seen & 1 << key != 0 {
+            return Err(format!("key {:X} is listed twice", key));
+        }
+        seen |= 1 << key;
+    }
+    Ok(())
+}
+
+pub struct Config {
+    pub actions: ActionSpace,
+    /// Each step's reward is the change in these values, times their
+    /// weights, summed.
+    pub reward: Vec<(Watch, f32)>,
+    /// The episode ends when any of these holds.
+    pub done: Vec<(Watch, Condition)>,
+    /// Frames each step plays its action for.
+    pub frame_skip: u32,
+    /// Chance that a frame repeats the previous frame's action instead of
+    /// the one asked for, which keeps agents from memorising a fixed
+    /// sequence of inputs.
+    pub sticky: f32,
+    /// Instructions per frame.
+    pub cycles_per_frame: usize,
+    /// Ends episodes that run this long.
+    pub max_frames: Option<u64>,
+}
+
+impl Config {
+    /// Four frames a step, no sticky actions, 500 instructions a second and
+    /// no watches.
+    pub fn new(actions: ActionSpace) -> Config {
+        Config {
+            actions,
+            reward: Vec::new(),
+            done: Vec::new(),
+            frame_skip: 4,
+            sticky: 0.0,
+            cycles_per_frame: 500 / 60,
+            max_frames: None,
+        }
+    }
+}
+
+pub struct Env {
+    config: Arc<Config>,
+    /// The machine every episode starts from.
+    start: Cpu,
+    cpu: Cpu,
+    /// For sticky actions and each episode's `RND` seed.
+    rng: StdRng,
+    previous_action: usize,
+    /// The reward watches' values after the last step.
+    scores: Vec<i64>,
+    /// The done watches' values at the start of the episode.
+    initial: Vec<i64>,
+    frames: u64,
+}
+
+impl Env {
+    /// An environment whose episodes start from `cpu`, which has the ROM
+    /// loaded and its quirks set.
+    pub fn new(cpu: Cpu, config: Config) -> Env {
+        let mut env = Env {
+            config: Arc::new(config),
+            start: cpu.clone(),
+            cpu,
+            rng: StdRng::from_entropy(),
+            previous_action: 0,
+            scores: Vec::new(),
+            initial: Vec::new(),
+            frames: 0,
+        };
+        env.reset();
+        env
+    }
+
+    /// Makes the episodes that follow reproducible. Clones start with a
+    /// random seed of their own, so seed each one for reproducible runs.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn actions(&self) -> &ActionSpace {
+        &self.config.actions
+    }
+
+    /// The machine, for looking at more than the observation.
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// Frames played this episode.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Starts a new episode and returns its first observation.
+    pub fn reset(&mut self) -> Observation {
+        self.cpu = self.start.clone();
+        self.cpu.rng = StdRng::seed_from_u64(self.rng.gen());
+        self.previous_action = 0;
+        self.frames = 0;
+        let memory = &self.cpu.memory;
+        self.scores = self
+            .config
+            .reward
+            .iter()
+            .map(|(watch, _)| watch.read(memory))
+            .collect();
+        self.initial = self
+            .config
+            .done
+            .iter()
+            .map(|(watch, _)| watch.read(memory))
+            .collect();
+        self.observation()
+    }
+
+    /// Plays `action` for `frame_skip` frames, or until the episode ends.
+    /// Panics if `action` isn't in the action space.
+    pub fn step(&mut self, action: usize) -> (Observation, f32, bool) {
+        assert!(action < self.config.actions.len(), "no action {}", action);
+        let mut done = self.done();
+        for _ in 0..self.config.frame_skip {
+            if done {
+                break;
+            }
+            let sticky = self.config.sticky > 0.0 && self.rng.gen::<f32>() < self.config.sticky;
+            if !sticky {
+                self.previous_action = action;
+            }
+            self.cpu.keypad.keys = self.config.actions.keys(self.previous_action);
+            self.cpu.run_frame(self.config.cycles_per_frame);
+            self.frames += 1;
+            done = self.done();
+        }
+
+        let mut reward = 0.0;
+        for ((watch, weight), score) in self.config.reward.iter().zip(self.scores.iter_mut()) {
+            let now = watch.read(&self.cpu.memory);
+            reward += (now - *score) as f32 * weight;
+            *score = now;
+        }
+        (self.observation(), reward, done)
+    }
+
+    pub fn observation(&self) -> Observation {
+        let display = &self.cpu.display;
+        let mut pixels = Vec::with_capacity(WIDTH * HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                pixels.push(display.get_pixel(x, y) as u8);
+            }
+        }
+        pixels
+    }
+
+    fn done(&self) -> bool {
+        let memory = &self.cpu.memory;
+        let ended =
+            self.config
+                .done
+                .iter()
+                .zip(&self.initial)
+                .any(|((watch, condition), &initial)| {
+                    let value = watch.read(memory);
+                    match *condition {
+                        Condition::Equals(n) => value == n,
+                        Condition::AtMost(n) => value <= n,
+                        Condition::AtLeast(n) => value >= n,
+                        Condition::Decreased => value < initial,
+                    }
+                });
+        ended || self.config.max_frames.is_some_and(|max| self.frames >= max)
+    }
+}
+
+/// Copies the environment mid-episode. The clone draws a fresh seed, so
+/// clones sent off to run in parallel don't all play the same episodes.
+impl Clone for Env {
+    fn clone(&self) -> Env {
+        Env {
+            config: self.config.clone(),
+            start: self.start.clone(),
+            cpu: self.cpu.clone(),
+            rng: StdRng::from_entropy(),
+            previous_action: self.previous_action,
+            scores: self.scores.clone(),
+            initial: self.initial.clone(),
+            frames: self.frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActionSpace, Condition, Config, Env, Watch};
+    use crate::asm::assemble;
+    use crate::cpu::Cpu;
+    use std::thread;
+
+    /// A game paced by the delay timer: the score at 0x300 goes up each
+    /// frame key 5 is held, and a life at 0x301 is lost every ten frames.
+    fn game(actions: ActionSpace, sticky: f32) -> Env {
+        let rom = assemble(
+            "
+                LD V1, 3
+                LD V4, 5
+            loop:
+                LD V5, 1
+                LD DT, V5
+            wait:
+                LD V5, DT
+                SE V5, 0
+                JP wait
+                SKNP V4
+                ADD V0, 1
+                ADD V2, 1
+                SE V2, 10
+                JP save
+                LD V2, 0
+                ADD V1, 0xFF
+            save:
+                LD I, 0x300
+                LD [I], V1
+                JP loop
+            ",
+        )
+        .unwrap()
+        .rom;
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.memory[0x301] = 3;
+
+        let mut config = Config::new(actions);
+        config.sticky = sticky;
+        config.reward.push((Watch::byte(0x300), 1.0));
+        config.done.push((Watch::byte(0x301), Condition::AtMost(0)));
+        config.cycles_per_frame = 20;
+        let mut env = Env::new(cpu, config);
+        env.seed(1);
+        env
+    }
+
+    /// Plays an episode and returns the rewards of its steps.
+    fn episode(env: &mut Env, action: usize) -> Vec<f32> {
+        env.reset();
+        let mut rewards = Vec::new();
+        loop {
+            let (observation, reward, done) = env.step(action);
+            assert_eq!(observation.len(), 64 * 32);
+            rewards.push(reward);
+            if done {
+                return rewards;
+            }
+        }
+    }
+
+    #[test]
+    fn rewards_and_episodes() {
+        let mut env = game(ActionSpace::single(&[5, 6]).unwrap(), 0.0);
+        assert_eq!(env.actions().len(), 3);
+        assert!(env.actions().keys(1)[5]);
+
+        let rewards = episode(&mut env, 1);
+        assert!(rewards.iter().all(|&reward| reward >= 0.0));
+        let total: f32 = rewards.iter().sum();
+        assert!(total > 20.0, "holding 5 scores most frames: {}", total);
+        assert!(
+            (28..=32).contains(&env.frames()),
+            "three lives last 30 frames"
+        );
+
+        assert_eq!(
+            episode(&mut env, 2).iter().sum::<f32>(),
+            0.0,
+            "6 doesn't score"
+        );
+        assert!(env.step(1).2, "steps after the end stay done");
+    }
+
+    #[test]
+    fn sticky_actions_repeat() {
+        let mut env = game(ActionSpace::combinations(&[5, 6]).unwrap(), 1.0);
+        assert_eq!(env.actions().len(), 4);
+        assert_eq!(
+            env.actions().keys(3)[..7],
+            [false, false, false, false, false, true, true]
+        );
+        // every frame repeats the first, with nothing held
+        assert_eq!(episode(&mut env, 3).iter().sum::<f32>(), 0.0);
+    }
+
+    #[test]
+    fn clones_run_in_parallel() {
+        let env = game(ActionSpace::single(&[5]).unwrap(), 0.5);
+        let play = |seeds: Vec<u64>| -> Vec<Vec<f32>> {
+            let handles: Vec<_> = seeds
+                .into_iter()
+                .map(|seed| {
+                    let mut env = env.clone();
+                    env.seed(seed);
+                    thread::spawn(move || {
+                        // alternate, so that sticky frames change the score
+                        env.reset();
+                        let mut rewards = Vec::new();
+                        for step in 0.. {
+                            let (_, reward, done) = env.step(step % 2);
+                            rewards.push(reward);
+                            if done {
+                                break;
+                            }
+                        }
+                        rewards
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        };
+        let results = play(vec![1, 2, 3, 4]);
+        assert!(
+            results.windows(2).any(|pair| pair[0] != pair[1]),
+            "each seed plays its own episode"
+        );
+        assert_eq!(play(vec![3])[0], results[2], "seeded clones reproduce");
+    }
+
+    #[test]
+    fn action_keys_are_checked() {
+        assert!(ActionSpace::single(&[5, 0x10]).is_err());
+        assert!(ActionSpace::combinations(&[5, 6, 5]).is_err());
+        let all: Vec<u8> = (0..16).collect();
+        assert_eq!(ActionSpace::combinations(&all).unwrap().len(), 1 << 16);
+        let too_many: Vec<u8> = (0..33).collect();
+        assert!(ActionSpace::combinations(&too_many).is_err());
+    }
+}
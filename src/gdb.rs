@@ -0,0 +1,535 @@
+//! A GDB remote serial protocol stub, so that GDB and other front-ends that
+//! speak the protocol can debug a running machine over TCP.
+//!
+//! Registers are numbered PC, I, V0-VF, SP, DT, ST, with PC and I 16 bits
+//! wide and the rest 8; `target.xml` describes them to the client. Values
+//! are big-endian like the machine's words. The address space is `memory`.
+//! Breakpoints (`Z0`/`Z1`) are kept by the stub rather than written into
+//! memory, and `continue` can be interrupted with Ctrl-C.
+
+use crate::cpu::Cpu;
+use crate::debugger::{Debugger, Stop};
+use crate::hexdump::parse_hex;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Frames run between checks for an interrupt while continuing.
+const SLICE_FRAMES: u64 = 1;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="0"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="v0" bitsize="8"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+/// Number of registers in the order of `g` packets.
+const REGISTERS: usize = 21;
+
+fn register_width(n: usize) -> usize {
+    if n < 2 {
+        2
+    } else {
+        1
+    }
+}
+
+fn read_register(cpu: &Cpu, n: usize) -> u16 {
+    match n {
+        0 => cpu.pc,
+        1 => cpu.i,
+        2..=17 => cpu.v[n - 2] as u16,
+        18 => cpu.sp as u16,
+        19 => cpu.dt as u16,
+        _ => cpu.st as u16,
+    }
+}
+
+fn write_register(cpu: &mut Cpu, n: usize, value: u16) {
+    match n {
+        0 => cpu.pc = value & 0xFFF,
+        1 => cpu.i = value,
+        2..=17 => cpu.v[n - 2] = value as u8,
+        18 => cpu.sp = value as u8 & 0xF,
+        19 => cpu.dt = value as u8,
+        _ => cpu.st = value as u8,
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses the `ADDR,LEN` that memory and breakpoint packets start with.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((
+        parse_hex(addr).ok()? as usize,
+        parse_hex(len).ok()? as usize,
+    ))
+}
+
+/// What the session does after a packet.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    Packet(String),
+    Step,
+    Continue,
+    /// Detach or kill: acknowledge with the packet, if any, and close.
+    Close(Option<String>),
+}
+
+/// Protocol state for one connection.
+pub struct Stub {
+    pub debugger: Debugger,
+    ack: bool,
+}
+
+impl Stub {
+    pub fn new(debugger: Debugger) -> Stub {
+        Stub {
+            debugger,
+            ack: true,
+        }
+    }
+
+    /// Handles every packet except the run control ones, which it returns
+    /// as `Reply::Step` and `Reply::Continue` after applying any resume
+    /// address.
+    pub fn handle(&mut self, cpu: &mut Cpu, packet: &str) -> Reply {
+        let ok = || Reply::Packet("OK".to_string());
+        let error = || Reply::Packet("E01".to_string());
+        let mut chars = packet.chars();
+        let kind = chars.next().unwrap_or(' ');
+        let rest = chars.as_str();
+        match kind {
+            '?' => Reply::Packet(format!("S{:02x}", SIGTRAP)),
+            'g' => Reply::Packet(
+                (0..REGISTERS)
+                    .map(|n| {
+                        let bytes = read_register(cpu, n).to_be_bytes();
+                        hex_bytes(&bytes[2 - register_width(n)..])
+                    })
+                    .collect(),
+            ),
+            'G' => {
+                let bytes = match parse_hex_bytes(rest) {
+                    Some(bytes)
+                        if bytes.len() == (0..REGISTERS).map(register_width).sum::<usize>() =>
+                    {
+                        bytes
+                    }
+                    _ => return error(),
+                };
+                let mut offset = 0;
+                for n in 0..REGISTERS {
+                    let width = register_width(n);
+                    let value = bytes[offset..offset + width]
+                        .iter()
+                        .fold(0, |value, &byte| value << 8 | byte as u16);
+                    write_register(cpu, n, value);
+                    offset += width;
+                }
+                ok()
+            }
+            'p' => match parse_hex(rest) {
+                Ok(n) if (n as usize) < REGISTERS => {
+                    let n = n as usize;
+                    let bytes = read_register(cpu, n).to_be_bytes();
+                    Reply::Packet(hex_bytes(&bytes[2 - register_width(n)..]))
+                }
+                _ => error(),
+            },
+            'P' => {
+                let parsed = rest.split_once('=').and_then(|(n, value)| {
+                    let n = parse_hex(n).ok()? as usize;
+                    let bytes = parse_hex_bytes(value)?;
+                    if n >= REGISTERS || bytes.len() != register_width(n) {
+                        return None;
+                    }
+                    Some((n, bytes.iter().fold(0, |v, &b| v << 8 | b as u16)))
+                });
+                match parsed {
+                    Some((n, value)) => {
+                        write_register(cpu, n, value);
+                        ok()
+                    }
+                    None => error(),
+                }
+            }
+            'm' => match parse_range(rest) {
+                Some((addr, len)) if addr < cpu.memory.len() => {
+                    let end = (addr + len).min(cpu.memory.len());
+                    Reply::Packet(hex_bytes(&cpu.memory[addr..end]))
+                }
+                _ => error(),
+            },
+            'M' => {
+                let parsed = rest.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let bytes = parse_hex_bytes(data)?;
+                    if bytes.len() != len || addr + len > cpu.memory.len() {
+                        return None;
+                    }
+                    Some((addr, bytes))
+                });
+                match parsed {
+                    Some((addr, bytes)) => {
+                        cpu.memory[addr..addr + bytes.len()].copy_from_slice(&bytes);
+                        cpu.flush_decoded();
+                        ok()
+                    }
+                    None => error(),
+                }
+            }
+            'Z' | 'z' => {
+                // software and hardware breakpoints are the same to us
+                let (breakpoint_type, range) = rest.split_at(rest.len().min(2));
+                if breakpoint_type != "0," && breakpoint_type != "1," {
+                    return Reply::Packet(String::new());
+                }
+                match parse_range(range) {
+                    Some((addr, _)) if addr < cpu.memory.len() => {
+                        if kind == 'Z' {
+                            self.debugger.breakpoints.insert(addr as u16);
+                        } else {
+                            self.debugger.breakpoints.remove(&(addr as u16));
+                        }
+                        ok()
+                    }
+                    _ => error(),
+                }
+            }
+            's' | 'c' => {
+                if !rest.is_empty() {
+                    match parse_hex(rest) {
+                        Ok(addr) => cpu.pc = addr as u16 & 0xFFF,
+                        Err(_) => return error(),
+                    }
+                }
+                if kind == 's' {
+                    Reply::Step
+                } else {
+                    Reply::Continue
+                }
+            }
+            'H' => ok(),
+            'k' => Reply::Close(None),
+            'D' => Reply::Close(Some("OK".to_string())),
+            _ => self.query(packet),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> Reply {
+        let reply = if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_string()
+        } else if packet == "QStartNoAckMode" {
+            self.ack = false;
+            "OK".to_string()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(range) {
+                Some((offset, len)) => {
+                    let start = offset.min(TARGET_XML.len());
+                    let end = (start + len).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[start..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else {
+            match packet {
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                // anything else is unsupported, which the empty reply says
+                _ => String::new(),
+            }
+        };
+        Reply::Packet(reply)
+    }
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next packet, acknowledging it if `ack`. Stray
+    /// acknowledgements and interrupts between packets are skipped.
+    fn packet(&mut self, ack: bool) -> io::Result<Option<String>> {
+        loop {
+            match self.byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum)?;
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&data));
+            if ack {
+                self.writer.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid || !ack {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        write!(self.writer, "${}#{:02x}", data, checksum(data))?;
+        self.writer.flush()
+    }
+
+    /// Whether the client sent Ctrl-C, without waiting for it.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if !self.reader.buffer().is_empty() {
+            return Ok(self.reader.buffer().contains(&0x03));
+        }
+        self.writer.set_nonblocking(true)?;
+        let mut byte = [0];
+        let read = self.writer.peek(&mut byte);
+        self.writer.set_nonblocking(false)?;
+        match read {
+            Ok(1) if byte[0] == 0x03 => {
+                self.byte()?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Serves one client on `stream` until it detaches, kills or disconnects.
+/// Returns whether it killed the target.
+pub fn session(cpu: &mut Cpu, stub: &mut Stub, stream: TcpStream) -> io::Result<bool> {
+    stream.set_nodelay(true)?;
+    stub.ack = true;
+    let mut connection = Connection {
+        reader: BufReader::new(stream.try_clone()?),
+        writer: stream,
+    };
+    while let Some(packet) = connection.packet(stub.ack)? {
+        match stub.handle(cpu, &packet) {
+            Reply::Packet(reply) => connection.send(&reply)?,
+            Reply::Step => {
+                stub.debugger.step(cpu);
+                connection.send(&format!("S{:02x}", SIGTRAP))?;
+            }
+            Reply::Continue => {
+                let mut stop = stub.debugger.resume(cpu, SLICE_FRAMES);
+                let signal = loop {
+                    if stop != Stop::Limit {
+                        break SIGTRAP;
+                    }
+                    if connection.interrupted()? {
+                        break SIGINT;
+                    }
+                    stop = stub.debugger.run(cpu, SLICE_FRAMES);
+                };
+                connection.send(&format!("S{:02x}", signal))?;
+            }
+            Reply::Close(None) => return Ok(true),
+            Reply::Close(Some(reply)) => {
+                connection.send(&reply)?;
+                break;
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Listens on `address` and serves clients one after another, keeping the
+/// machine and breakpoints between them, until one kills the target.
+pub fn serve(cpu: &mut Cpu, debugger: Debugger, address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("waiting for GDB on {}", listener.local_addr()?);
+    let mut stub = Stub::new(debugger);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        eprintln!("connection from {}", stream.peer_addr()?);
+        if session(cpu, &mut stub, stream)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, session, Reply, Stub};
+    use crate::cpu::Cpu;
+    use crate::debugger::Debugger;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn machine() -> Cpu {
+        let mut cpu = Cpu::new();
+        // LD V0, 0x12; ADD V0, 1; JP 0x202
+        cpu.load_rom(&[0x60, 0x12, 0x70, 0x01, 0x12, 0x02]).unwrap();
+        cpu
+    }
+
+    fn packet(reply: &str) -> Reply {
+        Reply::Packet(reply.to_string())
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut cpu = machine();
+        let mut stub = Stub::new(Debugger::new(10));
+        cpu.i = 0x345;
+        cpu.v[0xF] = 1;
+        let registers = format!("02000345{}01000000", "00".repeat(15));
+        assert_eq!(stub.handle(&mut cpu, "g"), packet(&registers));
+        assert_eq!(stub.handle(&mut cpu, "p11"), packet("01"), "VF");
+        assert_eq!(stub.handle(&mut cpu, "P2=7f"), packet("OK"));
+        assert_eq!(cpu.v[0], 0x7F);
+        assert_eq!(
+            stub.handle(&mut cpu, "P0=7f"),
+            packet("E01"),
+            "PC is 16 bits"
+        );
+
+        let registers = format!("02040000{}", "00".repeat(19));
+        assert_eq!(
+            stub.handle(&mut cpu, &format!("G{}", registers)),
+            packet("OK")
+        );
+        assert_eq!((cpu.pc, cpu.i, cpu.v[0]), (0x204, 0, 0));
+
+        assert_eq!(stub.handle(&mut cpu, "m200,4"), packet("60127001"));
+        assert_eq!(stub.handle(&mut cpu, "mffe,8"), packet("0000"));
+        assert_eq!(stub.handle(&mut cpu, "M202,2:7002"), packet("OK"));
+        assert_eq!(cpu.memory[0x203], 0x02);
+        assert_eq!(stub.handle(&mut cpu, "M202,2:70"), packet("E01"));
+
+        assert_eq!(stub.handle(&mut cpu, "Z0,204,2"), packet("OK"));
+        assert!(stub.debugger.breakpoints.contains(&0x204));
+        assert_eq!(stub.handle(&mut cpu, "Z2,300,1"), packet(""), "watchpoints");
+        assert_eq!(stub.handle(&mut cpu, "z0,204,2"), packet("OK"));
+        assert!(stub.debugger.breakpoints.is_empty());
+        assert_eq!(stub.handle(&mut cpu, "s200"), Reply::Step);
+        assert_eq!(cpu.pc, 0x200);
+        assert!(matches!(
+            stub.handle(&mut cpu, "qXfer:features:read:target.xml:0,20"),
+            Reply::Packet(reply) if reply.starts_with("m<?xml")
+        ));
+        assert_eq!(stub.handle(&mut cpu, "vMustReplyEmpty"), packet(""));
+    }
+
+    fn send(stream: &mut TcpStream, data: &str) {
+        write!(stream, "${}#{:02x}", data, checksum(data)).unwrap();
+    }
+
+    /// Reads until the end of the next reply packet.
+    fn receive(stream: &mut TcpStream) -> String {
+        let mut text = String::new();
+        let mut byte = [0];
+        while !text.contains('#') || text.len() < text.find('#').unwrap() + 3 {
+            stream.read_exact(&mut byte).unwrap();
+            text.push(byte[0] as char);
+        }
+        text
+    }
+
+    #[test]
+    fn session_breaks_and_interrupts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut cpu = machine();
+            let mut stub = Stub::new(Debugger::new(10));
+            let (stream, _) = listener.accept().unwrap();
+            let killed = session(&mut cpu, &mut stub, stream).unwrap();
+            (killed, cpu.cycles)
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        send(&mut client, "Z0,202,2");
+        assert_eq!(receive(&mut client), "+$OK#9a");
+        send(&mut client, "c");
+        assert_eq!(receive(&mut client), "+$S05#b8");
+        send(&mut client, "p2");
+        assert_eq!(receive(&mut client), "+$12#63");
+        send(&mut client, "s");
+        assert_eq!(receive(&mut client), "+$S05#b8");
+        send(&mut client, "z0,202,2");
+        assert_eq!(receive(&mut client), "+$OK#9a");
+        send(&mut client, "c");
+        let mut ack = [0];
+        client.read_exact(&mut ack).unwrap();
+        assert_eq!(&ack, b"+");
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!(receive(&mut client), "$S02#b5");
+        send(&mut client, "k");
+
+        let (killed, cycles) = server.join().unwrap();
+        assert!(killed);
+        // V0 wraps while running, the instruction count doesn't
+        assert!(cycles > 2, "kept running until the interrupt");
+    }
+}
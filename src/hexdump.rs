@@ -0,0 +1,198 @@
+//! Memory views for the debugger: hex dumps with an ASCII column, a sprite
+//! view with one byte per line drawn as pixels, and byte pattern search.
+
+use std::convert::TryFrom;
+use std::ops::Range;
+
+const ROW: usize = 8;
+
+fn ascii(byte: u8) -> char {
+    if byte.is_ascii_graphic() || byte == b' ' {
+        byte as char
+    } else {
+        '.'
+    }
+}
+
+fn bitmap(byte: u8) -> String {
+    (0..8)
+        .map(|i| if byte & (0x80 >> i) != 0 { '#' } else { '.' })
+        .collect()
+}
+
+/// How `highlight`ed bytes stand out: in reverse video, or enclosed in
+/// brackets that take the place of the spaces around them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    Ansi,
+}
+
+/// Hex dump of `len` bytes from `start`, eight per line, with an ASCII
+/// column. Addresses wrap at the end of `memory`.
+pub fn dump(
+    memory: &[u8],
+    start: usize,
+    len: usize,
+    highlight: &Range<usize>,
+    style: Style,
+) -> String {
+    let size = memory.len();
+    let lit = |addr: usize| highlight.contains(&addr) || highlight.contains(&(addr + size));
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset < len {
+        let row_start = (start + offset) % size;
+        let count = ROW.min(len - offset);
+        let addrs: Vec<usize> = (0..count).map(|i| (row_start + i) % size).collect();
+
+        out += &format!("{:03x}:", row_start);
+        for (i, &addr) in addrs.iter().enumerate() {
+            let byte = format!("{:02x}", memory[addr]);
+            match style {
+                Style::Ansi if lit(addr) => out += &format!(" \x1b[7m{}\x1b[0m", byte),
+                Style::Ansi => out += &format!(" {}", byte),
+                Style::Plain => {
+                    let opens = lit(addr) && (i == 0 || !lit(addrs[i - 1]));
+                    let closes_previous = i > 0 && lit(addrs[i - 1]) && !lit(addr);
+                    out.push(if opens {
+                        '['
+                    } else if closes_previous {
+                        ']'
+                    } else {
+                        ' '
+                    });
+                    out += &byte;
+                }
+            }
+        }
+        let closes_row = style == Style::Plain && lit(addrs[count - 1]);
+        out.push(if closes_row { ']' } else { ' ' });
+        out += &"   ".repeat(ROW - count);
+        out += " |";
+        out.extend(addrs.iter().map(|&addr| ascii(memory[addr])));
+        out += "|\n";
+        offset += count;
+    }
+    out
+}
+
+/// One byte per line with its ASCII character and bits drawn as pixels, so
+/// that sprites show up as pictures.
+pub fn dump_sprites(
+    memory: &[u8],
+    start: usize,
+    len: usize,
+    highlight: &Range<usize>,
+    style: Style,
+) -> String {
+    let size = memory.len();
+    let mut out = String::new();
+    for offset in 0..len {
+        let addr = (start + offset) % size;
+        let byte = memory[addr];
+        let lit = highlight.contains(&addr) || highlight.contains(&(addr + size));
+        let line = format!(
+            "{:03x}: {:02x} {} {}",
+            addr,
+            byte,
+            ascii(byte),
+            bitmap(byte)
+        );
+        out += &match (style, lit) {
+            (Style::Ansi, true) => format!("\x1b[7m{}\x1b[0m\n", line),
+            (Style::Plain, true) => format!("{}  <- I\n", line),
+            _ => format!("{}\n", line),
+        };
+    }
+    out
+}
+
+/// Parses hex bytes, with or without `0x`, or `"text"` for ASCII.
+pub fn parse_bytes(words: &[&str]) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    for word in words {
+        if let Some(text) = word.strip_prefix('"').and_then(|w| w.strip_suffix('"')) {
+            bytes.extend(text.bytes());
+        } else {
+            bytes.push(
+                parse_hex(word).and_then(|n| {
+                    u8::try_from(n).map_err(|_| format!("`{}` is not a byte", word))
+                })?,
+            );
+        }
+    }
+    Ok(bytes)
+}
+
+/// Parses a search pattern: bytes as for `parse_bytes`, `??` for any byte.
+pub fn parse_pattern(words: &[&str]) -> Result<Vec<Option<u8>>, String> {
+    let mut pattern = Vec::new();
+    for word in words {
+        if *word == "??" {
+            pattern.push(None);
+        } else {
+            pattern.extend(parse_bytes(&[word])?.into_iter().map(Some));
+        }
+    }
+    Ok(pattern)
+}
+
+/// Parses a hex number, with or without `0x` or `$`.
+pub fn parse_hex(word: &str) -> Result<u32, String> {
+    let digits = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix('$'))
+        .unwrap_or(word);
+    u32::from_str_radix(digits, 16).map_err(|_| format!("`{}` is not a hex number", word))
+}
+
+/// Addresses where `pattern` occurs in `memory`.
+pub fn find(memory: &[u8], pattern: &[Option<u8>]) -> Vec<usize> {
+    if pattern.is_empty() || pattern.len() > memory.len() {
+        return Vec::new();
+    }
+    (0..=memory.len() - pattern.len())
+        .filter(|&addr| {
+            pattern
+                .iter()
+                .zip(&memory[addr..])
+                .all(|(want, byte)| want.is_none_or(|want| want == *byte))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump, dump_sprites, find, parse_bytes, parse_pattern, Style};
+
+    #[test]
+    fn dump_marks_highlight() {
+        let memory: Vec<u8> = (0..16).map(|i| i * 0x11).collect();
+        assert_eq!(
+            dump(&memory, 0, 10, &(2..4), Style::Plain),
+            "000: 00 11[22 33]44 55 66 77  |..\"3DUfw|\n\
+             008: 88 99                    |..|\n"
+        );
+        assert_eq!(
+            dump(&memory, 4, 4, &(7..9), Style::Plain),
+            "004: 44 55 66[77]             |DUfw|\n"
+        );
+        assert_eq!(
+            dump_sprites(&memory, 3, 2, &(4..5), Style::Plain),
+            "003: 33 3 ..##..##\n004: 44 D .#...#..  <- I\n"
+        );
+    }
+
+    #[test]
+    fn search() {
+        let memory = parse_bytes(&["a2", "0x2a", "\"hi\"", "a2", "ff"]).unwrap();
+        assert_eq!(memory, vec![0xa2, 0x2a, b'h', b'i', 0xa2, 0xff]);
+        assert_eq!(
+            find(&memory, &parse_pattern(&["a2", "??"]).unwrap()),
+            vec![0, 4]
+        );
+        assert_eq!(find(&memory, &parse_pattern(&["\"hi\""]).unwrap()), vec![2]);
+        assert!(parse_bytes(&["100"]).is_err());
+    }
+}
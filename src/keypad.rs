@@ -1,9 +1,16 @@
 use minifb::Key;
 
+#[derive(Clone)]
 pub struct Keypad {
     pub keys: [bool; 16],
 }
 
+impl Default for Keypad {
+    fn default() -> Keypad {
+        Keypad::new()
+    }
+}
+
 impl Keypad {
     pub fn new() -> Keypad {
         Keypad { keys: [false; 16] }
@@ -14,7 +21,7 @@ impl Keypad {
     }
 }
 
-fn keymap(key: Key) -> Option<u8> {
+pub fn keymap(key: Key) -> Option<u8> {
     // takes a Key and returns u8 that corresponds to an index
     // in Keypad.keys
     Some(match key {
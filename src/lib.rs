@@ -0,0 +1,23 @@
+pub mod analyze;
+pub mod asm;
+pub mod bench;
+pub mod cheat;
+pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod display;
+pub mod env;
+pub mod gdb;
+pub mod hexdump;
+pub mod keypad;
+pub mod netplay;
+pub mod octo;
+pub mod patch;
+pub mod profile;
+pub mod quirks;
+pub mod romdb;
+pub mod rpc;
+pub mod script;
+pub mod timing;
+pub mod trace;
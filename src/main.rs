@@ -1,43 +1,630 @@
-mod cpu;
-mod display;
-mod keypad;
-
-use cpu::*;
-use display::{Display, HEIGHT, WIDTH};
-use keypad::Keypad;
+use chip8::analyze;
+use chip8::asm;
+use chip8::bench;
+use chip8::cheat;
+use chip8::cpu::*;
+use chip8::dap;
+use chip8::debugger::Debugger;
+use chip8::disasm;
+use chip8::display::{HEIGHT, ON, WIDTH};
+use chip8::gdb;
+use chip8::hexdump::Style;
+use chip8::keypad::keymap;
+use chip8::netplay;
+use chip8::octo;
+use chip8::patch;
+use chip8::profile::Profiler;
+use chip8::quirks::Platform;
+use chip8::romdb;
+use chip8::rpc;
+use chip8::script;
+use chip8::trace::{self, Tracer};
 
+use clap::{Args, Parser, Subcommand};
 use minifb::{Key, Scale, Window, WindowOptions};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use std::fs;
+use std::io::{self, BufRead, BufWriter, IsTerminal, Write};
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A CHIP-8 emulator.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a ROM in a window, or headless with `--headless`.
+    Run(RunArgs),
+    /// Disassemble a ROM into source that `asm` reassembles, following
+    /// control flow to tell code from data.
+    Disasm {
+        rom: PathBuf,
+        /// Decode every word in order instead, with addresses and opcodes.
+        #[arg(long)]
+        linear: bool,
+    },
+    /// Print information about a ROM.
+    Info { rom: PathBuf },
+    /// Scan a ROM's reachable code for extension opcodes and quirk-dependent
+    /// patterns, and recommend a platform and quirks.
+    Analyze { rom: PathBuf },
+    /// Assemble a source file into a ROM.
+    Asm {
+        source: PathBuf,
+        /// Output ROM [default: the source file with a `.ch8` extension].
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Also write a listing to this file.
+        #[arg(long)]
+        listing: Option<PathBuf>,
+    },
+    /// Run a ROM headless as fast as possible and report instructions and
+    /// frames per second, and where the time goes.
+    Bench {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Stop after this many frames [default: 10000].
+        #[arg(long, conflicts_with = "instructions")]
+        frames: Option<u64>,
+        /// Stop after this many instructions (rounded up to whole frames).
+        #[arg(long)]
+        instructions: Option<u64>,
+        /// Decode every instruction instead of using the decode cache.
+        #[arg(long)]
+        no_decode_cache: bool,
+    },
+    /// Run a ROM under a command-line debugger with breakpoints, stepping
+    /// and memory editing. Type `help` at the prompt for the commands.
+    Debug {
+        #[command(flatten)]
+        machine: MachineArgs,
+    },
+    /// Serve a ROM to GDB or another remote serial protocol client over TCP.
+    Gdb {
+        #[command(flatten)]
+        machine: MachineArgs,
+        /// Address to listen on.
+        #[arg(long, default_value = "127.0.0.1:1234")]
+        listen: String,
+    },
+    /// Serve the Debug Adapter Protocol on stdin and stdout, for editors.
+    /// The editor's launch configuration names the program.
+    Dap {
+        /// Accept one connection on this address instead of using stdio.
+        #[arg(long)]
+        listen: Option<String>,
+    },
+    /// Serve a JSON-RPC API for controlling the emulator from other
+    /// programs, one request per line, see `src/rpc.rs` for the methods.
+    Rpc {
+        /// Address to listen on.
+        #[arg(long, default_value = "127.0.0.1:7001")]
+        listen: String,
+    },
+    /// Run a Rhai script that drives the emulator, see `src/script.rs` for
+    /// the API. Exits with an error if the script fails.
+    Script { path: PathBuf },
+    /// Make an IPS or BPS patch that turns one ROM into another; `--patch`
+    /// applies it.
+    Patch {
+        original: PathBuf,
+        modified: PathBuf,
+        /// Output patch, `.ips` or `.bps`.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Generate the ROM database's community table from the chip-8-database
+    /// project's `database/programs.json`; rebuild to use it.
+    ImportRomdb {
+        programs: PathBuf,
+        /// Output source file.
+        #[arg(short, long, default_value = "src/romdb_community.rs")]
+        output: PathBuf,
+    },
+    /// Compile an Octo source file into a ROM. `run` also accepts `.8o`
+    /// files directly.
+    Octo {
+        source: PathBuf,
+        /// Output ROM [default: the source file with a `.ch8` extension].
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Options shared by the commands that run a ROM.
+#[derive(Args)]
+struct MachineArgs {
+    rom: PathBuf,
+
+    /// Instructions executed per second [default: from the ROM database,
+    /// otherwise 500].
+    #[arg(long)]
+    ips: Option<u32>,
+
+    /// Run at the speed of the COSMAC VIP interpreter, with per-instruction
+    /// timings instead of a flat `--ips`.
+    #[arg(long, conflicts_with = "ips")]
+    vip_timing: bool,
+
+    /// Quirk profile: vip, schip or xochip. Without it the ROM database's
+    /// recommendation is used, or the emulator's historical behaviour.
+    #[arg(long)]
+    platform: Option<Platform>,
+
+    /// Comma separated quirk overrides, e.g. `clip-sprites,no-vblank-wait`.
+    #[arg(long)]
+    quirks: Option<String>,
+
+    /// Seed for `RND`, for reproducible runs.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Apply an IPS or BPS patch to the ROM before loading it. Repeat to
+    /// apply several in order.
+    #[arg(long, value_name = "FILE")]
+    patch: Vec<PathBuf>,
+
+    /// Hold a memory byte at a value every frame, e.g. `2f0=03` (hex).
+    /// Repeat for more bytes.
+    #[arg(long, value_name = "ADDR=BYTE", value_parser = cheat::parse_freeze)]
+    freeze: Vec<(u16, u8)>,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    /// Window scale: 1, 2, 4, 8, 16 or 32.
+    #[arg(long, default_value_t = 16, value_parser = parse_scale)]
+    scale: u32,
+
+    /// Foreground and background colours as `RRGGBB,RRGGBB`.
+    #[arg(long, value_parser = parse_palette)]
+    palette: Option<(u32, u32)>,
+
+    /// Run without a window and print the final screen.
+    #[arg(long, requires = "frames")]
+    headless: bool,
+
+    /// Stop after this many 60 Hz frames.
+    #[arg(long)]
+    frames: Option<u64>,
+
+    /// Trace every executed instruction to stdout, same as `--trace -`.
+    #[arg(long)]
+    debug: bool,
+
+    /// Write an execution trace to this file (`-` for stdout).
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Only trace instructions in this address range, e.g. `0x200-0x2ff`.
+    #[arg(long, value_name = "RANGE", value_parser = trace::parse_address_range)]
+    trace_addr: Option<RangeInclusive<u16>>,
+
+    /// Only trace these frames, e.g. `60-120`.
+    #[arg(long, value_name = "RANGE", value_parser = trace::parse_range)]
+    trace_frames: Option<RangeInclusive<u64>>,
+
+    /// Profile execution and write the report to this file (`-` for stdout)
+    /// when the run ends.
+    #[arg(long, value_name = "FILE")]
+    profile: Option<PathBuf>,
+
+    /// Host a two-player netplay game: wait for the other player on this
+    /// address, e.g. `0.0.0.0:7000`.
+    #[arg(long, value_name = "ADDR")]
+    host: Option<String>,
+
+    /// Join the netplay game hosted at this address.
+    #[arg(long, value_name = "ADDR", conflicts_with = "host")]
+    connect: Option<String>,
+
+    /// The keys this netplay player controls, e.g. `1,4` or `0-7`.
+    #[arg(long, value_name = "KEYS", default_value = "0-f", value_parser = netplay::parse_keys)]
+    net_keys: u16,
+
+    /// Frames between a key press and its use in a netplay game; more hides
+    /// more network latency. The host's setting is used.
+    #[arg(long, default_value_t = 2)]
+    input_delay: u8,
+}
+
+fn parse_scale(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(scale @ (1 | 2 | 4 | 8 | 16 | 32)) => Ok(scale),
+        _ => Err("expected 1, 2, 4, 8, 16 or 32".to_string()),
+    }
+}
+
+fn parse_palette(s: &str) -> Result<(u32, u32), String> {
+    let colour = |c: &str| u32::from_str_radix(c.trim().trim_start_matches('#'), 16);
+    match s.split_once(',') {
+        Some((fg, bg)) => match (colour(fg), colour(bg)) {
+            (Ok(fg), Ok(bg)) => Ok((fg, bg)),
+            _ => Err("colours must be hex RRGGBB".to_string()),
+        },
+        None => Err("expected `RRGGBB,RRGGBB`".to_string()),
+    }
+}
+
+/// Reads a ROM, compiling it first if it is Octo source (`.8o`).
+fn read_rom(path: &Path) -> Vec<u8> {
+    if path.extension().is_some_and(|ext| ext == "8o") {
+        return octo::compile_file(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+    }
+    fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Unable to read {}: {}", path.display(), e);
+        process::exit(1);
+    })
+}
 
 fn main() {
+    match Cli::parse().command {
+        Command::Run(args) => run(args),
+        Command::Disasm { rom, linear } => {
+            let rom = read_rom(&rom);
+            if linear {
+                print!("{}", disasm::disassemble(&rom));
+            } else {
+                print!("{}", disasm::disassemble_flow(&rom));
+            }
+        }
+        Command::Info { rom } => info(&rom),
+        Command::Bench {
+            machine,
+            frames,
+            instructions,
+            no_decode_cache,
+        } => {
+            let limit = match instructions {
+                Some(instructions) => bench::Limit::Instructions(instructions),
+                None => bench::Limit::Frames(frames.unwrap_or(10_000)),
+            };
+            let (mut cpu, _, cycles_per_frame) = setup(&machine);
+            cpu.decode_cache = !no_decode_cache;
+            let report = bench::run(|| cpu.clone(), cycles_per_frame, limit);
+            print!("{}", report);
+        }
+        Command::Debug { machine } => debug(&machine),
+        Command::Gdb { machine, listen } => {
+            let (mut cpu, _, cycles_per_frame) = setup(&machine);
+            if let Err(e) = gdb::serve(&mut cpu, Debugger::new(cycles_per_frame), &listen) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        Command::Dap { listen } => {
+            let served = match listen {
+                Some(address) => TcpListener::bind(&address)
+                    .and_then(|listener| listener.accept())
+                    .and_then(|(stream, _)| dap::serve(stream.try_clone()?, stream)),
+                None => dap::serve(io::stdin(), io::stdout()),
+            };
+            if let Err(e) = served {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        Command::Rpc { listen } => {
+            if let Err(e) = rpc::serve(&listen) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        Command::Script { path } => {
+            if let Err(e) = script::run_file(&path) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        Command::Analyze { rom } => print!("{}", analyze::analyze(&read_rom(&rom))),
+        Command::Asm {
+            source,
+            output,
+            listing,
+        } => assemble(&source, output, listing),
+        Command::Patch {
+            original,
+            modified,
+            output,
+        } => make_patch(&original, &modified, &output),
+        Command::ImportRomdb { programs, output } => {
+            let json = fs::read_to_string(&programs).unwrap_or_else(|e| {
+                eprintln!("Unable to read {}: {}", programs.display(), e);
+                process::exit(1);
+            });
+            let source = romdb::import(&json).unwrap_or_else(|e| {
+                eprintln!("{}: {}", programs.display(), e);
+                process::exit(1);
+            });
+            fs::write(&output, &source).expect("Unable to write ROM database");
+            println!(
+                "{}: {} ROMs",
+                output.display(),
+                source.matches("RomInfo {").count()
+            );
+        }
+        Command::Octo { source, output } => {
+            let rom = read_rom(&source);
+            let output = output.unwrap_or_else(|| source.with_extension("ch8"));
+            fs::write(&output, &rom).expect("Unable to write ROM");
+            println!("{}: {} bytes", output.display(), rom.len());
+        }
+    }
+}
+
+fn make_patch(original: &Path, modified: &Path, output: &Path) {
+    let format = output
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(patch::Format::from_extension)
+        .unwrap_or_else(|| {
+            eprintln!("{}: expected a .ips or .bps file", output.display());
+            process::exit(2);
+        });
+    let data =
+        patch::create(format, &read_rom(original), &read_rom(modified)).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+    fs::write(output, &data).expect("Unable to write patch");
+    println!("{}: {} bytes", output.display(), data.len());
+}
+
+fn assemble(source: &Path, output: Option<PathBuf>, listing: Option<PathBuf>) {
+    let assembly = asm::assemble_file(source).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let output = output.unwrap_or_else(|| source.with_extension("ch8"));
+    fs::write(&output, &assembly.rom).expect("Unable to write ROM");
+    if let Some(listing) = listing {
+        fs::write(listing, &assembly.listing).expect("Unable to write listing");
+    }
+    println!("{}: {} bytes", output.display(), assembly.rom.len());
+}
+
+fn info(path: &Path) {
+    let rom = read_rom(path);
+    let end = PROGRAM_START as usize + rom.len();
+    println!("file:    {}", path.display());
+    println!("size:    {} bytes", rom.len());
+    println!(
+        "loads:   {:#05x}-{:#05x}",
+        PROGRAM_START,
+        end.saturating_sub(1)
+    );
+    println!("sha1:    {}", romdb::sha1(&rom));
+    if end > 4096 {
+        println!("warning: ROM does not fit in memory");
+    }
+
+    if let Some(info) = romdb::lookup(&rom) {
+        println!("title:   {}", info.title);
+        println!("author:  {}", info.author);
+        println!("quirks:  {:?}", info.quirks());
+        if let Some(ips) = info.ips {
+            println!("ips:     {}", ips);
+        }
+        if !info.keys.is_empty() {
+            println!("keys:    {}", info.keys);
+        }
+    }
+}
+
+fn debug(args: &MachineArgs) {
+    let (mut cpu, _, cycles_per_frame) = setup(args);
+    let mut debugger = Debugger::new(cycles_per_frame);
+    if io::stdout().is_terminal() {
+        debugger.style = Style::Ansi;
+    }
+    println!("{}", Debugger::status(&cpu));
+
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("(chip8) ");
+        io::stdout().flush().unwrap();
+        line.clear();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        match debugger.command(&mut cpu, &line) {
+            Some(out) => print!("{}", out),
+            None => break,
+        }
+    }
+}
+
+/// Builds a machine with the ROM loaded and configured from `args`, and
+/// returns it with the ROM's database entry and the instructions per frame.
+fn setup(args: &MachineArgs) -> (Cpu, Option<&'static romdb::RomInfo>, usize) {
     let mut cpu = Cpu::new();
-    //let filename = "c8_test.c8";
-    let filename = "sierpinski.ch8";
+    let rom = read_rom(&args.rom);
+    let patches: Vec<Vec<u8>> = args.patch.iter().map(|path| read_rom(path)).collect();
+    let info = cpu.load_patched_rom(&rom, &patches).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    if let Some(platform) = args.platform {
+        cpu.quirks = platform.quirks();
+    }
+    if let Some(spec) = &args.quirks {
+        if let Err(e) = cpu.quirks.apply(spec) {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    }
+    cpu.vip_timing = args.vip_timing;
+    if let Some(seed) = args.seed {
+        cpu.rng = StdRng::seed_from_u64(seed);
+    }
+
+    cpu.freezes.extend(args.freeze.iter().copied());
+
+    let ips = args.ips.or(info.and_then(|info| info.ips)).unwrap_or(500);
+    (cpu, info, (ips as usize / 60).max(1))
+}
+
+fn run(args: RunArgs) {
+    let (mut cpu, info, cycles_per_frame) = setup(&args.machine);
+    if let Some(info) = info {
+        println!("{} by {}", info.title, info.author);
+        if !info.keys.is_empty() {
+            println!("keys: {}", info.keys);
+        }
+    }
+
+    let mut session = start_netplay(&args);
+    if let Some(session) = &session {
+        cpu.rng = StdRng::seed_from_u64(session.seed);
+    }
+
+    let trace = match &args.trace {
+        Some(path) if path.as_os_str() != "-" => Some(Box::new(
+            fs::File::create(path).expect("Unable to create trace file"),
+        ) as Box<dyn Write + Send>),
+        Some(_) => Some(Box::new(io::stdout()) as Box<dyn Write + Send>),
+        None if args.debug => Some(Box::new(io::stdout()) as Box<dyn Write + Send>),
+        None => None,
+    };
+    if let Some(out) = trace {
+        let mut tracer = Tracer::new(Box::new(BufWriter::new(out)));
+        tracer.addresses = args.trace_addr;
+        tracer.frames = args.trace_frames;
+        cpu.tracer = Some(tracer);
+    }
 
-    let rom = fs::read(&filename).expect("Unable to read file");
-    cpu.load_rom(&rom);
+    if args.profile.is_some() {
+        cpu.profiler = Some(Profiler::new());
+    }
+
+    let mut run_frame = |cpu: &mut Cpu, keys: &[bool; 16]| match &mut session {
+        Some(session) => session
+            .run_frame(cpu, keys, cycles_per_frame)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            }),
+        None => {
+            cpu.keypad.keys = *keys;
+            cpu.run_frame(cycles_per_frame)
+        }
+    };
+
+    if args.headless {
+        for _ in 0..args.frames.unwrap_or(0) {
+            run_frame(&mut cpu, &[false; 16]);
+        }
+        // flush the trace before the screen
+        cpu.tracer = None;
+        print!("{}", cpu.display.to_ascii());
+        write_profile(&cpu, args.profile.as_deref());
+        return;
+    }
 
     // setup windows
+    let scale = match args.scale {
+        1 => Scale::X1,
+        2 => Scale::X2,
+        4 => Scale::X4,
+        8 => Scale::X8,
+        16 => Scale::X16,
+        _ => Scale::X32,
+    };
     let mut window = Window::new(
         "CHIP-8",
         WIDTH,
         HEIGHT,
         WindowOptions {
-            scale: Scale::X16,
+            scale,
             ..WindowOptions::default()
         },
     )
     .expect("Unable to open window");
+    window.limit_update_rate(Some(std::time::Duration::from_secs_f64(1.0 / 60.0)));
 
-    let display_refresh_rate: f64 = 500.0;
-    let runloop_timer_default: usize = (display_refresh_rate / 60.0) as usize;
-    window.limit_update_rate(Some(std::time::Duration::from_secs_f64(
-        1.0 / display_refresh_rate,
-    )));
+    let (fg, bg) = args
+        .palette
+        .or(info.and_then(|info| info.colours))
+        .unwrap_or((ON, 0));
+    let mut buffer = vec![bg; WIDTH * HEIGHT];
+    let mut frame = 0;
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        cpu.emulate_cycle();
+        if args.frames.is_some_and(|frames| frame >= frames) {
+            break;
+        }
+        frame += 1;
+
+        let mut keys = [false; 16];
+        for key in window.get_keys().unwrap_or_default() {
+            if let Some(index) = keymap(key) {
+                keys[index as usize] = true;
+            }
+        }
+
+        if run_frame(&mut cpu, &keys) {
+            cpu.display.take_dirty();
+            for (out, pixel) in buffer.iter_mut().zip(cpu.display.memory.iter()) {
+                *out = if *pixel == ON { fg } else { bg };
+            }
+            window
+                .update_with_buffer(&buffer, WIDTH, HEIGHT)
+                .expect("Unable to update window");
+        } else {
+            window.update();
+        }
+    }
+    write_profile(&cpu, args.profile.as_deref());
+}
+
+/// Connects to the other player when `--host` or `--connect` is given.
+fn start_netplay(args: &RunArgs) -> Option<netplay::Session> {
+    let config = netplay::Config {
+        keys: args.net_keys,
+        delay: args.input_delay,
+        seed: args.machine.seed.unwrap_or_else(rand::random),
+    };
+    let session = match (&args.host, &args.connect) {
+        (Some(address), _) => {
+            let listener = TcpListener::bind(address).unwrap_or_else(|e| {
+                eprintln!("Unable to listen on {}: {}", address, e);
+                process::exit(1);
+            });
+            println!("waiting for the other player on {}", address);
+            netplay::Session::accept(&listener, config)
+        }
+        (None, Some(address)) => netplay::Session::connect(address.as_str(), config),
+        (None, None) => return None,
+    };
+    Some(session.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    }))
+}
 
-        window.update_with_buffer(&cpu.display.memory, WIDTH, HEIGHT);
+fn write_profile(cpu: &Cpu, path: Option<&Path>) {
+    if let (Some(profiler), Some(path)) = (&cpu.profiler, path) {
+        let report = profiler.report(&cpu.memory);
+        if path.as_os_str() == "-" {
+            print!("{}", report);
+        } else {
+            fs::write(path, report).expect("Unable to write profile");
+        }
     }
 }
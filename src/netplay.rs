@@ -0,0 +1,344 @@
+//! Two-player netplay over TCP in lockstep: every frame both machines send
+//! their players' keys and a hash of their state, and neither runs the frame
+//! until it has the other's. Each player owns a subset of the keypad, and
+//! keys are applied `delay` frames after they are pressed so the other side's
+//! usually arrive before they are needed.
+//!
+//! The host picks the `RND` seed and input delay and the other side adopts
+//! them; everything else (ROM, quirks, speed) must match and a mismatch
+//! shows up as a desync on the first frame.
+
+use crate::cpu::Cpu;
+use crate::display::ON;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+const MAGIC: &[u8] = b"C8NP";
+const VERSION: u8 = 1;
+/// Magic, version, delay, keys and seed.
+const HELLO_LEN: usize = 16;
+/// Frame, keys and state hash.
+const INPUT_LEN: usize = 18;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The other side isn't speaking this protocol.
+    Protocol(String),
+    /// The machines' states differed at the start of this frame.
+    Desync {
+        frame: u64,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "netplay connection: {}", e),
+            Error::Protocol(message) => write!(f, "netplay: {}", message),
+            Error::Desync { frame: 0 } => write!(
+                f,
+                "netplay desync before the first frame: the ROM or settings differ"
+            ),
+            Error::Desync { frame } => write!(f, "netplay desync at frame {}", frame),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// One player's side of the session.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// The keys this player controls, bit n for key n.
+    pub keys: u16,
+    /// Frames between a key press and the frame it is applied in. Taken
+    /// from the host.
+    pub delay: u8,
+    /// Seed for `RND` on both machines. Taken from the host.
+    pub seed: u64,
+}
+
+pub struct Session {
+    stream: TcpStream,
+    keys: u16,
+    remote_keys: u16,
+    /// Keys for this frame and the `delay` frames after it.
+    queue: VecDeque<u16>,
+    frame: u64,
+    pub delay: u8,
+    /// The seed both machines must give `Cpu::rng`.
+    pub seed: u64,
+}
+
+impl Session {
+    /// Waits for the other player to connect to `listener` and hosts.
+    pub fn accept(listener: &TcpListener, config: Config) -> Result<Session, Error> {
+        let (stream, _) = listener.accept()?;
+        Session::start(stream, config, true)
+    }
+
+    /// Joins the game hosted at `address`.
+    pub fn connect(address: impl ToSocketAddrs, config: Config) -> Result<Session, Error> {
+        Session::start(TcpStream::connect(address)?, config, false)
+    }
+
+    fn start(mut stream: TcpStream, config: Config, host: bool) -> Result<Session, Error> {
+        stream.set_nodelay(true)?;
+        let mut hello = MAGIC.to_vec();
+        hello.push(VERSION);
+        hello.push(config.delay);
+        hello.extend_from_slice(&config.keys.to_be_bytes());
+        hello.extend_from_slice(&config.seed.to_be_bytes());
+        stream.write_all(&hello)?;
+
+        let mut reply = [0; HELLO_LEN];
+        stream.read_exact(&mut reply)?;
+        if &reply[..4] != MAGIC {
+            return Err(Error::Protocol(
+                "the other side is not a CHIP-8 netplay peer".to_string(),
+            ));
+        }
+        if reply[4] != VERSION {
+            return Err(Error::Protocol(format!(
+                "protocol version {} is not {}",
+                reply[4], VERSION
+            )));
+        }
+        let remote_keys = u16::from_be_bytes([reply[6], reply[7]]);
+        let shared = config.keys & remote_keys;
+        if shared != 0 {
+            return Err(Error::Protocol(format!(
+                "both players control keys {:#06x}",
+                shared
+            )));
+        }
+        let (delay, seed) = if host {
+            (config.delay, config.seed)
+        } else {
+            let mut seed = [0; 8];
+            seed.copy_from_slice(&reply[8..]);
+            (reply[5], u64::from_be_bytes(seed))
+        };
+
+        Ok(Session {
+            stream,
+            keys: config.keys,
+            remote_keys,
+            queue: (0..delay).map(|_| 0).collect(),
+            frame: 0,
+            delay,
+            seed,
+        })
+    }
+
+    /// Trades this frame's local keys and state hash for the other side's,
+    /// and returns the keypad to run the frame with.
+    pub fn exchange(&mut self, local: &[bool; 16], hash: u64) -> Result<[bool; 16], Error> {
+        let mut message = [0; INPUT_LEN];
+        message[..8].copy_from_slice(&self.frame.to_be_bytes());
+        message[8..10].copy_from_slice(&(to_mask(local) & self.keys).to_be_bytes());
+        message[10..].copy_from_slice(&hash.to_be_bytes());
+        self.stream.write_all(&message)?;
+
+        let mut reply = [0; INPUT_LEN];
+        self.stream.read_exact(&mut reply)?;
+        let mut word = [0; 8];
+        word.copy_from_slice(&reply[..8]);
+        if u64::from_be_bytes(word) != self.frame {
+            return Err(Error::Protocol(format!(
+                "expected input for frame {}",
+                self.frame
+            )));
+        }
+        word.copy_from_slice(&reply[10..]);
+        if u64::from_be_bytes(word) != hash {
+            return Err(Error::Desync { frame: self.frame });
+        }
+
+        let remote = u16::from_be_bytes([reply[8], reply[9]]) & self.remote_keys;
+        self.queue.push_back(to_mask(local) & self.keys | remote);
+        self.frame += 1;
+        let keys = self.queue.pop_front().unwrap_or(0);
+        Ok(std::array::from_fn(|key| keys & 1 << key != 0))
+    }
+
+    /// Runs one frame of `cpu` in step with the other side, with `local`
+    /// as this player's keys. Returns whether the screen changed, as
+    /// `Cpu::run_frame` does.
+    pub fn run_frame(
+        &mut self,
+        cpu: &mut Cpu,
+        local: &[bool; 16],
+        cycles: usize,
+    ) -> Result<bool, Error> {
+        cpu.keypad.keys = self.exchange(local, state_hash(cpu))?;
+        Ok(cpu.run_frame(cycles))
+    }
+}
+
+fn to_mask(keys: &[bool; 16]) -> u16 {
+    keys.iter()
+        .enumerate()
+        .filter(|(_, &down)| down)
+        .fold(0, |mask, (key, _)| mask | 1 << key)
+}
+
+/// FNV-1a over everything that decides what the machine does next.
+pub fn state_hash(cpu: &Cpu) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    feed(&cpu.memory);
+    feed(&cpu.v);
+    feed(&cpu.i.to_be_bytes());
+    feed(&cpu.pc.to_be_bytes());
+    for entry in cpu.stack {
+        feed(&entry.to_be_bytes());
+    }
+    feed(&[cpu.sp, cpu.dt, cpu.st, cpu.waiting_for_vblank as u8]);
+    let pixels: Vec<u8> = cpu
+        .display
+        .memory
+        .iter()
+        .map(|&pixel| (pixel == ON) as u8)
+        .collect();
+    feed(&pixels);
+    hash
+}
+
+/// Parses the keys a player controls: hex keys and ranges separated by
+/// commas, e.g. `1,4` or `0-7,c`.
+pub fn parse_keys(spec: &str) -> Result<u16, String> {
+    let key = |s: &str| match u8::from_str_radix(s.trim(), 16) {
+        Ok(key) if key < 16 => Ok(key),
+        _ => Err(format!("`{}` is not a key 0 to f", s)),
+    };
+    let mut mask = 0;
+    for part in spec.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (key(first)?, key(last)?),
+            None => (key(part)?, key(part)?),
+        };
+        if first > last {
+            return Err(format!("range `{}` ends before it starts", part));
+        }
+        for k in first..=last {
+            mask |= 1 << k;
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_keys, Config, Error, Session};
+    use crate::cpu::Cpu;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // V0 counts the instructions run with key 1 held, V1 with key c held
+    const ROM: &[u8] = &[
+        0x62, 0x01, 0x63, 0x0C, 0xE2, 0xA1, 0x70, 0x01, 0xE3, 0xA1, 0x71, 0x01, 0x12, 0x04,
+    ];
+
+    /// Plays `frames` frames holding `key`, and returns V0, V1 and the first
+    /// frame that saw a key held.
+    fn side(
+        session: Result<Session, Error>,
+        key: usize,
+        rom: &[u8],
+        frames: u64,
+    ) -> Result<[u64; 3], String> {
+        let mut session = session.map_err(|e| e.to_string())?;
+        let mut cpu = Cpu::new();
+        cpu.load_rom(rom)?;
+        let mut keys = [false; 16];
+        keys[key] = true;
+        let mut first = None;
+        for frame in 0..frames {
+            session
+                .run_frame(&mut cpu, &keys, 10)
+                .map_err(|e| e.to_string())?;
+            if first.is_none() && cpu.keypad.keys.contains(&true) {
+                first = Some(frame);
+            }
+        }
+        Ok([cpu.v[0] as u64, cpu.v[1] as u64, first.unwrap_or(frames)])
+    }
+
+    /// Runs a host holding key 1 against a guest holding key c and returns
+    /// what each side saw.
+    fn play(frames: u64, guest_rom: &'static [u8]) -> Vec<Result<[u64; 3], String>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let guest = thread::spawn(move || {
+            let config = Config {
+                keys: 0xF000,
+                delay: 0,
+                seed: 0,
+            };
+            side(Session::connect(address, config), 0xC, guest_rom, frames)
+        });
+        let config = Config {
+            keys: 0x000F,
+            delay: 2,
+            seed: 7,
+        };
+        let host = side(Session::accept(&listener, config), 1, ROM, frames);
+        vec![host, guest.join().unwrap()]
+    }
+
+    #[test]
+    fn lockstep_with_input_delay() {
+        let results = play(10, ROM);
+        assert_eq!(results[0], results[1], "both machines agree");
+        let [v0, v1, first] = results[0].clone().unwrap();
+        assert!(v0 > 0 && v1 > 0, "both players' keys reach both machines");
+        assert_eq!(first, 2, "keys arrive after the host's input delay");
+    }
+
+    #[test]
+    fn detects_desync() {
+        for result in play(3, &[0x12, 0x00]) {
+            let error = result.unwrap_err();
+            assert!(error.contains("before the first frame"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn parses_key_sets() {
+        assert_eq!(parse_keys("1,4"), Ok(0x0012));
+        assert_eq!(parse_keys("0-7,c"), Ok(0x10FF));
+        assert!(parse_keys("g").is_err());
+        assert!(parse_keys("7-0").is_err());
+    }
+
+    #[test]
+    fn rejects_shared_keys() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = |keys| Config {
+            keys,
+            delay: 0,
+            seed: 0,
+        };
+        let guest = thread::spawn(move || Session::connect(address, config(0x0003)).err());
+        let host = Session::accept(&listener, config(0x0006)).err();
+        for error in [host, guest.join().unwrap()] {
+            let error = error.expect("the handshake fails").to_string();
+            assert!(error.contains("0x0002"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,1022 @@
+//! Compiler for the Octo assembly language (https://github.com/JohnEarnest/Octo).
+//!
+//! Supports the CHIP-8 subset of the language: registers and `i` assignment
+//! with `:=`, `+=`, `-=`, `=-`, `|=`, `&=`, `^=`, `>>=`, `<<=`, structured
+//! `if ... then`, `if ... begin ... else ... end` and `loop ... while ...
+//! again`, labels, `:const`, `:alias`, `:calc`, `:macro`, `:byte`, `:org`,
+//! `:unpack` and sprite data written as bare numbers.
+//!
+//! As in Octo, `:calc` expressions are evaluated right to left without
+//! operator precedence, so use parentheses.
+
+use crate::asm::{AsmError, SourceLine};
+use crate::cpu::PROGRAM_START;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+
+const MEMORY_END: usize = 0x1000;
+/// Guards against macros that expand to themselves.
+const MAX_EXPANSIONS: usize = 10_000;
+
+/// Compiles Octo `source` into a ROM to be loaded at `PROGRAM_START`.
+pub fn compile(source: &str) -> Result<Vec<u8>, AsmError> {
+    Compiler::new(source, "<input>").run().map(|(rom, _)| rom)
+}
+
+pub fn compile_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    compile_file_with_lines(path).map(|(rom, _)| rom)
+}
+
+/// Compiles the file at `path`, also returning where the code of every
+/// source line starts in memory, in address order.
+pub fn compile_file_with_lines(path: &Path) -> Result<(Vec<u8>, Vec<SourceLine>), AsmError> {
+    let file = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: file.clone(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+    Compiler::new(&source, &file).run()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (i, line) in source.lines().enumerate() {
+        let mut start = None;
+        for (j, c) in line
+            .char_indices()
+            .chain(std::iter::once((line.len(), ' ')))
+        {
+            if c == '#' && start.is_none() {
+                break;
+            }
+            match (c.is_whitespace(), start) {
+                (false, None) => start = Some(j),
+                (true, Some(s)) => {
+                    tokens.push_back(Token {
+                        text: line[s..j].to_string(),
+                        line: i + 1,
+                        column: line[..s].chars().count() + 1,
+                    });
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn register(name: &str) -> Option<u16> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(d), None) | (Some('V'), Some(d), None) => d.to_digit(16).map(|d| d as u16),
+        _ => None,
+    }
+}
+
+/// A forward reference to a label, patched once the label is defined.
+struct Fixup {
+    addr: usize,
+    kind: FixupKind,
+    token: Token,
+}
+
+enum FixupKind {
+    /// The low 12 bits of the instruction at `addr`.
+    Addr,
+    /// The low nibble of the byte at `addr`, from `:unpack`.
+    UnpackHigh,
+    /// The byte at `addr`, from `:unpack`.
+    UnpackLow,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+enum Block {
+    If { jump: usize },
+    Loop { start: usize, exits: Vec<usize> },
+}
+
+enum Cond {
+    Eq(u16, Rhs),
+    Ne(u16, Rhs),
+    Key(u16),
+    NotKey(u16),
+}
+
+#[derive(Clone, Copy)]
+enum Rhs {
+    V(u16),
+    Byte(u8),
+}
+
+const VF: u16 = 0xF;
+
+struct Compiler {
+    file: String,
+    tokens: VecDeque<Token>,
+    memory: Vec<u8>,
+    here: usize,
+    end: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u16>,
+    macros: HashMap<String, Macro>,
+    fixups: HashMap<String, Vec<Fixup>>,
+    blocks: Vec<(Block, Token)>,
+    started: bool,
+    expansions: usize,
+    /// Where each run of bytes from one source line starts.
+    lines: Vec<SourceLine>,
+    /// The address after the last byte emitted.
+    emitted: usize,
+}
+
+impl Compiler {
+    fn new(source: &str, file: &str) -> Compiler {
+        Compiler {
+            file: file.to_string(),
+            tokens: tokenize(source),
+            memory: vec![0; MEMORY_END],
+            here: PROGRAM_START as usize,
+            end: PROGRAM_START as usize,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: HashMap::new(),
+            blocks: Vec::new(),
+            started: false,
+            expansions: 0,
+            lines: Vec::new(),
+            emitted: 0,
+        }
+    }
+
+    fn error(&self, token: &Token, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.file.clone(),
+            line: token.line,
+            column: token.column,
+            message: message.into(),
+        }
+    }
+
+    fn run(mut self) -> Result<(Vec<u8>, Vec<SourceLine>), AsmError> {
+        while let Some(token) = self.tokens.pop_front() {
+            self.statement(token)?;
+        }
+
+        if let Some((_, token)) = self.blocks.last() {
+            return Err(self.error(token, format!("`{}` is never closed", token.text)));
+        }
+        if let Some(fixup) = self.fixups.values().flatten().next() {
+            let message = format!("undefined label `{}`", fixup.token.text);
+            return Err(self.error(&fixup.token, message));
+        }
+
+        let rom = self.memory[PROGRAM_START as usize..self.end].to_vec();
+        // `:org` may have moved backwards
+        self.lines.sort_by_key(|line| line.address);
+        Ok((rom, self.lines))
+    }
+
+    /// Octo programs start at `main`: unless `main` is the very first thing
+    /// defined, the program opens with a jump to it.
+    fn start(&mut self, token: &Token) -> Result<(), AsmError> {
+        if !self.started {
+            self.started = true;
+            if token.text != "main" {
+                let main = Token {
+                    text: "main".to_string(),
+                    ..token.clone()
+                };
+                self.jump_to(0x1000, &main)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self, after: &Token) -> Result<Token, AsmError> {
+        self.tokens.pop_front().ok_or_else(|| {
+            self.error(
+                after,
+                format!("unexpected end of input after `{}`", after.text),
+            )
+        })
+    }
+
+    fn expect(&mut self, after: &Token, text: &str) -> Result<Token, AsmError> {
+        let token = self.next(after)?;
+        if token.text != text {
+            return Err(self.error(&token, format!("expected `{}`", text)));
+        }
+        Ok(token)
+    }
+
+    fn emit(&mut self, token: &Token, byte: u8) -> Result<(), AsmError> {
+        if self.here >= MEMORY_END {
+            return Err(self.error(token, "program does not fit in memory"));
+        }
+        let continues = self.here == self.emitted
+            && self
+                .lines
+                .last()
+                .is_some_and(|last| last.line == token.line);
+        if !continues {
+            self.lines.push(SourceLine {
+                address: self.here as u16,
+                file: self.file.clone(),
+                line: token.line,
+            });
+        }
+        self.memory[self.here] = byte;
+        self.here += 1;
+        self.emitted = self.here;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn inst(&mut self, token: &Token, opcode: u16) -> Result<(), AsmError> {
+        self.emit(token, (opcode >> 8) as u8)?;
+        self.emit(token, opcode as u8)
+    }
+
+    fn patch(&mut self, addr: usize, target: usize) {
+        self.memory[addr] = (self.memory[addr] & 0xF0) | (target >> 8) as u8;
+        self.memory[addr + 1] = target as u8;
+    }
+
+    /// Emits `op | addr` where `token` names the target, which may be a
+    /// label defined later.
+    fn jump_to(&mut self, op: u16, token: &Token) -> Result<(), AsmError> {
+        let addr = self.here;
+        match self.labels.get(&token.text) {
+            Some(&target) => self.inst(token, op | target as u16),
+            None if self.is_identifier(&token.text) => {
+                self.fixups
+                    .entry(token.text.clone())
+                    .or_default()
+                    .push(Fixup {
+                        addr,
+                        kind: FixupKind::Addr,
+                        token: token.clone(),
+                    });
+                self.inst(token, op)
+            }
+            None => {
+                let target = self.addr(token)?;
+                self.inst(token, op | target)
+            }
+        }
+    }
+
+    fn is_identifier(&self, text: &str) -> bool {
+        parse_number(text).is_none()
+            && !self.constants.contains_key(text)
+            && register(text).is_none()
+            && !self.aliases.contains_key(text)
+    }
+
+    fn define_label(&mut self, token: &Token) -> Result<(), AsmError> {
+        let name = self.next(token)?;
+        self.check_name(&name)?;
+        self.start(&name)?;
+        let addr = self.here;
+        self.labels.insert(name.text.clone(), addr);
+        for fixup in self.fixups.remove(&name.text).unwrap_or_default() {
+            match fixup.kind {
+                FixupKind::Addr => self.patch(fixup.addr, addr),
+                FixupKind::UnpackHigh => self.memory[fixup.addr] |= (addr >> 8) as u8,
+                FixupKind::UnpackLow => self.memory[fixup.addr] = addr as u8,
+            }
+        }
+        Ok(())
+    }
+
+    fn check_name(&self, name: &Token) -> Result<(), AsmError> {
+        let text = name.text.as_str();
+        if parse_number(text).is_some() || register(text).is_some() || text.starts_with(':') {
+            return Err(self.error(name, format!("`{}` cannot be used as a name", text)));
+        }
+        if self.labels.contains_key(text)
+            || self.constants.contains_key(text)
+            || self.aliases.contains_key(text)
+            || self.macros.contains_key(text)
+        {
+            return Err(self.error(name, format!("`{}` is already defined", text)));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        if let Some(m) = self.macros.get(&token.text) {
+            return self.expand(&token, m.args.clone(), m.body.clone());
+        }
+
+        match token.text.as_str() {
+            ":" => return self.define_label(&token),
+            ":const" => {
+                let name = self.next(&token)?;
+                self.check_name(&name)?;
+                let value = self.next(&name)?;
+                let value = self.value(&value)?;
+                self.constants.insert(name.text, value);
+                return Ok(());
+            }
+            ":calc" => {
+                let name = self.next(&token)?;
+                self.check_name(&name)?;
+                let value = self.calc(&name)?;
+                self.constants.insert(name.text, value);
+                return Ok(());
+            }
+            ":alias" => {
+                let name = self.next(&token)?;
+                self.check_name(&name)?;
+                let reg = self.next(&name)?;
+                let reg = self.reg(&reg)?;
+                self.aliases.insert(name.text, reg);
+                return Ok(());
+            }
+            ":macro" => return self.define_macro(&token),
+            ":org" => {
+                let addr = self.next(&token)?;
+                let value = self.numeric(&addr)?;
+                if !(PROGRAM_START as i64..MEMORY_END as i64).contains(&value) {
+                    return Err(self.error(&addr, "address out of range"));
+                }
+                self.start(&token)?;
+                self.here = value as usize;
+                return Ok(());
+            }
+            ":breakpoint" => {
+                self.next(&token)?;
+                return Ok(());
+            }
+            ":monitor" => {
+                self.next(&token)?;
+                self.next(&token)?;
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        self.start(&token)?;
+        match token.text.as_str() {
+            ":byte" => {
+                let value = self.next(&token)?;
+                let byte = self.byte(&value)?;
+                self.emit(&value, byte)?;
+            }
+            ":unpack" => {
+                let nibble = self.next(&token)?;
+                let nibble = match self.numeric(&nibble)? {
+                    n @ 0..=0xF => n as u16,
+                    _ => return Err(self.error(&nibble, "expected a nibble")),
+                };
+                let label = self.next(&token)?;
+                // v0 := nibble << 4 | high bits of label ; v1 := low bits
+                let hi = self.here + 1;
+                self.inst(&token, 0x6000 | nibble << 4)?;
+                let lo = self.here + 1;
+                self.inst(&token, 0x6100)?;
+                match self.labels.get(&label.text) {
+                    Some(&addr) => {
+                        self.memory[hi] |= (addr >> 8) as u8;
+                        self.memory[lo] = addr as u8;
+                    }
+                    None if self.is_identifier(&label.text) => {
+                        let fixups = self.fixups.entry(label.text.clone()).or_default();
+                        fixups.push(Fixup {
+                            addr: hi,
+                            kind: FixupKind::UnpackHigh,
+                            token: label.clone(),
+                        });
+                        fixups.push(Fixup {
+                            addr: lo,
+                            kind: FixupKind::UnpackLow,
+                            token: label,
+                        });
+                    }
+                    None => {
+                        let addr = self.addr(&label)? as usize;
+                        self.memory[hi] |= (addr >> 8) as u8;
+                        self.memory[lo] = addr as u8;
+                    }
+                }
+            }
+            "return" | ";" => self.inst(&token, 0x00EE)?,
+            "clear" => self.inst(&token, 0x00E0)?,
+            "bcd" => self.reg_op(&token, 0xF033)?,
+            "save" => self.reg_op(&token, 0xF055)?,
+            "load" => self.reg_op(&token, 0xF065)?,
+            "sprite" => {
+                let x = self.next(&token)?;
+                let x = self.reg(&x)?;
+                let y = self.next(&token)?;
+                let y = self.reg(&y)?;
+                let n = self.next(&token)?;
+                let n = match self.numeric(&n)? {
+                    n @ 0..=0xF => n as u16,
+                    _ => return Err(self.error(&n, "sprite height must be 0-15")),
+                };
+                self.inst(&token, 0xD000 | x << 8 | y << 4 | n)?;
+            }
+            "jump" => {
+                let target = self.next(&token)?;
+                self.jump_to(0x1000, &target)?;
+            }
+            "jump0" => {
+                let target = self.next(&token)?;
+                self.jump_to(0xB000, &target)?;
+            }
+            "native" => {
+                let target = self.next(&token)?;
+                self.jump_to(0x0000, &target)?;
+            }
+            "delay" | "buzzer" => {
+                self.expect(&token, ":=")?;
+                let reg = self.next(&token)?;
+                let x = self.reg(&reg)?;
+                let op = if token.text == "delay" {
+                    0xF015
+                } else {
+                    0xF018
+                };
+                self.inst(&token, op | x << 8)?;
+            }
+            "i" => self.assign_i(&token)?,
+            "if" => self.if_statement(&token)?,
+            "else" => match self.blocks.pop() {
+                Some((Block::If { jump }, _)) => {
+                    let end = self.here;
+                    self.inst(&token, 0x1000)?;
+                    self.patch(jump, self.here);
+                    self.blocks.push((Block::If { jump: end }, token));
+                }
+                _ => return Err(self.error(&token, "`else` without `if ... begin`")),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If { jump }, _)) => self.patch(jump, self.here),
+                _ => return Err(self.error(&token, "`end` without `if ... begin`")),
+            },
+            "loop" => {
+                let start = self.here;
+                self.blocks.push((
+                    Block::Loop {
+                        start,
+                        exits: Vec::new(),
+                    },
+                    token,
+                ));
+            }
+            "while" => {
+                if !self
+                    .blocks
+                    .iter()
+                    .any(|(b, _)| matches!(b, Block::Loop { .. }))
+                {
+                    return Err(self.error(&token, "`while` outside of `loop`"));
+                }
+                let cond = self.condition(&token)?;
+                self.skip(&token, cond, true)?;
+                let exit = self.here;
+                self.inst(&token, 0x1000)?;
+                for (block, _) in self.blocks.iter_mut().rev() {
+                    if let Block::Loop { exits, .. } = block {
+                        exits.push(exit);
+                        break;
+                    }
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, exits }, _)) => {
+                    self.inst(&token, 0x1000 | start as u16)?;
+                    for exit in exits {
+                        self.patch(exit, self.here);
+                    }
+                }
+                _ => return Err(self.error(&token, "`again` without `loop`")),
+            },
+            _ => {
+                if self.is_register(&token.text) && self.tokens.front().is_some() {
+                    return self.assign_v(&token);
+                }
+                // bare numbers and constants are data, e.g. sprite rows
+                let data =
+                    parse_number(&token.text).or_else(|| self.constants.get(&token.text).copied());
+                if let Some(value) = data {
+                    let byte = self.to_byte(&token, value)?;
+                    return self.emit(&token, byte);
+                }
+                if token.text.starts_with(':') || token.text.starts_with('{') {
+                    return Err(self.error(&token, format!("unknown directive `{}`", token.text)));
+                }
+                // a bare name calls the subroutine with that label
+                self.jump_to(0x2000, &token)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn define_macro(&mut self, token: &Token) -> Result<(), AsmError> {
+        let name = self.next(token)?;
+        self.check_name(&name)?;
+        let mut args = Vec::new();
+        loop {
+            let arg = self.next(&name)?;
+            if arg.text == "{" {
+                break;
+            }
+            args.push(arg.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let t = self.next(&name)?;
+            match t.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => (),
+            }
+            body.push(t);
+        }
+        self.macros.insert(name.text, Macro { args, body });
+        Ok(())
+    }
+
+    fn expand(
+        &mut self,
+        token: &Token,
+        args: Vec<String>,
+        body: Vec<Token>,
+    ) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(token, "too many macro expansions"));
+        }
+        let mut values = HashMap::new();
+        for arg in args {
+            let value = self.next(token)?;
+            values.insert(arg, value.text);
+        }
+        for t in body.into_iter().rev() {
+            let text = values.get(&t.text).cloned().unwrap_or(t.text);
+            // errors inside the expansion point at the invocation
+            self.tokens.push_front(Token {
+                text,
+                line: token.line,
+                column: token.column,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads a `{ ... }` expression following `after`.
+    fn calc(&mut self, after: &Token) -> Result<i64, AsmError> {
+        let open = self.expect(after, "{")?;
+        let mut expr = Vec::new();
+        loop {
+            let t = self.next(&open)?;
+            if t.text == "}" {
+                break;
+            }
+            expr.push(t);
+        }
+        if expr.is_empty() {
+            return Err(self.error(&open, "empty expression"));
+        }
+        let mut pos = 0;
+        let value = self.calc_expr(&expr, &mut pos)?;
+        if pos < expr.len() {
+            return Err(self.error(&expr[pos], "unexpected token in expression"));
+        }
+        Ok(value)
+    }
+
+    /// term (op expr)? -- Octo evaluates right to left without precedence.
+    fn calc_expr(&self, expr: &[Token], pos: &mut usize) -> Result<i64, AsmError> {
+        let left = self.calc_term(expr, pos)?;
+        let op = match expr.get(*pos) {
+            Some(op) if op.text != ")" => op,
+            _ => return Ok(left),
+        };
+        *pos += 1;
+        let right = self.calc_expr(expr, pos)?;
+        Ok(match op.text.as_str() {
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => return Err(self.error(op, "division by zero")),
+            "/" => left / right,
+            "%" => left % right,
+            "&" => left & right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            _ => return Err(self.error(op, format!("unknown operator `{}`", op.text))),
+        })
+    }
+
+    fn calc_term(&self, expr: &[Token], pos: &mut usize) -> Result<i64, AsmError> {
+        let t = match expr.get(*pos) {
+            Some(t) => t,
+            None => return Err(self.error(&expr[expr.len() - 1], "incomplete expression")),
+        };
+        *pos += 1;
+        match t.text.as_str() {
+            "(" => {
+                let value = self.calc_expr(expr, pos)?;
+                match expr.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error(t, "unclosed `(`")),
+                }
+            }
+            "-" => Ok(-self.calc_term(expr, pos)?),
+            "~" => Ok(!self.calc_term(expr, pos)?),
+            "!" => Ok((self.calc_term(expr, pos)? == 0) as i64),
+            "HERE" => Ok(self.here as i64),
+            _ => self.value(t),
+        }
+    }
+
+    fn lookup(&self, text: &str) -> Option<i64> {
+        parse_number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|&a| a as i64))
+    }
+
+    fn value(&self, token: &Token) -> Result<i64, AsmError> {
+        self.lookup(&token.text)
+            .ok_or_else(|| self.error(token, format!("undefined name `{}`", token.text)))
+    }
+
+    /// A number, constant or label, or an inline `{ ... }` expression.
+    fn numeric(&mut self, token: &Token) -> Result<i64, AsmError> {
+        if token.text == "{" {
+            self.tokens.push_front(token.clone());
+            let before = Token {
+                text: String::new(),
+                ..token.clone()
+            };
+            return self.calc(&before);
+        }
+        self.value(token)
+    }
+
+    fn to_byte(&self, token: &Token, value: i64) -> Result<u8, AsmError> {
+        match value {
+            -0x80..=0xFF => Ok(value as u8),
+            _ => Err(self.error(token, format!("{} does not fit in a byte", value))),
+        }
+    }
+
+    fn byte(&mut self, token: &Token) -> Result<u8, AsmError> {
+        let value = self.numeric(token)?;
+        self.to_byte(token, value)
+    }
+
+    fn addr(&self, token: &Token) -> Result<u16, AsmError> {
+        match self.value(token)? {
+            value @ 0..=0xFFF => Ok(value as u16),
+            value => Err(self.error(token, format!("address {:#x} out of range", value))),
+        }
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        register(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn reg(&self, token: &Token) -> Result<u16, AsmError> {
+        register(&token.text)
+            .or_else(|| self.aliases.get(&token.text).copied())
+            .ok_or_else(|| {
+                self.error(
+                    token,
+                    format!("expected a register, found `{}`", token.text),
+                )
+            })
+    }
+
+    fn reg_op(&mut self, token: &Token, op: u16) -> Result<(), AsmError> {
+        let reg = self.next(token)?;
+        let x = self.reg(&reg)?;
+        self.inst(token, op | x << 8)
+    }
+
+    fn rhs(&mut self, token: &Token) -> Result<Rhs, AsmError> {
+        if self.is_register(&token.text) {
+            return Ok(Rhs::V(self.reg(token)?));
+        }
+        Ok(Rhs::Byte(self.byte(token)?))
+    }
+
+    fn assign_i(&mut self, token: &Token) -> Result<(), AsmError> {
+        let op = self.next(token)?;
+        match op.text.as_str() {
+            ":=" => {
+                let value = self.next(&op)?;
+                if value.text == "hex" {
+                    let reg = self.next(&value)?;
+                    let x = self.reg(&reg)?;
+                    return self.inst(token, 0xF029 | x << 8);
+                }
+                if value.text == "{" {
+                    let addr = self.numeric(&value)?;
+                    if !(0..=0xFFF).contains(&addr) {
+                        return Err(self.error(&value, "address out of range"));
+                    }
+                    return self.inst(token, 0xA000 | addr as u16);
+                }
+                self.jump_to(0xA000, &value)
+            }
+            "+=" => {
+                let reg = self.next(&op)?;
+                let x = self.reg(&reg)?;
+                self.inst(token, 0xF01E | x << 8)
+            }
+            _ => Err(self.error(&op, format!("unsupported operator `{}` for `i`", op.text))),
+        }
+    }
+
+    fn assign_v(&mut self, token: &Token) -> Result<(), AsmError> {
+        let x = self.reg(token)?;
+        let op = self.next(token)?;
+        let src = self.next(&op)?;
+
+        let alu = |y: u16, n: u16| 0x8000 | x << 8 | y << 4 | n;
+        let opcode = match (op.text.as_str(), src.text.as_str()) {
+            (":=", "random") => {
+                let mask = self.next(&src)?;
+                0xC000 | x << 8 | self.byte(&mask)? as u16
+            }
+            (":=", "key") => 0xF00A | x << 8,
+            (":=", "delay") => 0xF007 | x << 8,
+            _ => match (op.text.as_str(), self.rhs(&src)?) {
+                (":=", Rhs::V(y)) => alu(y, 0x0),
+                ("|=", Rhs::V(y)) => alu(y, 0x1),
+                ("&=", Rhs::V(y)) => alu(y, 0x2),
+                ("^=", Rhs::V(y)) => alu(y, 0x3),
+                ("+=", Rhs::V(y)) => alu(y, 0x4),
+                ("-=", Rhs::V(y)) => alu(y, 0x5),
+                (">>=", Rhs::V(y)) => alu(y, 0x6),
+                ("=-", Rhs::V(y)) => alu(y, 0x7),
+                ("<<=", Rhs::V(y)) => alu(y, 0xE),
+                (":=", Rhs::Byte(b)) => 0x6000 | x << 8 | b as u16,
+                ("+=", Rhs::Byte(b)) => 0x7000 | x << 8 | b as u16,
+                ("-=", Rhs::Byte(b)) => 0x7000 | x << 8 | b.wrapping_neg() as u16,
+                _ => {
+                    let message = format!(
+                        "`{} {} {}` is not an instruction",
+                        token.text, op.text, src.text
+                    );
+                    return Err(self.error(&op, message));
+                }
+            },
+        };
+        self.inst(token, opcode)
+    }
+
+    fn condition(&mut self, token: &Token) -> Result<Cond, AsmError> {
+        let lhs = self.next(token)?;
+        let x = self.reg(&lhs)?;
+        let op = self.next(&lhs)?;
+        match op.text.as_str() {
+            "key" => return Ok(Cond::Key(x)),
+            "-key" => return Ok(Cond::NotKey(x)),
+            _ => (),
+        }
+        let rhs = self.next(&op)?;
+        let rhs = self.rhs(&rhs)?;
+        let load_vf = |rhs: Rhs| match rhs {
+            Rhs::V(y) => 0x8000 | VF << 8 | y << 4,
+            Rhs::Byte(b) => 0x6000 | VF << 8 | b as u16,
+        };
+        Ok(match op.text.as_str() {
+            "==" => Cond::Eq(x, rhs),
+            "!=" => Cond::Ne(x, rhs),
+            // vf := rhs ; vf =- vx leaves vf = 1 when vx >= rhs
+            "<" | ">=" => {
+                self.inst(token, load_vf(rhs))?;
+                self.inst(token, 0x8007 | VF << 8 | x << 4)?;
+                if op.text == "<" {
+                    Cond::Eq(VF, Rhs::Byte(0))
+                } else {
+                    Cond::Eq(VF, Rhs::Byte(1))
+                }
+            }
+            // vf := rhs ; vf -= vx leaves vf = 1 when rhs >= vx
+            ">" | "<=" => {
+                self.inst(token, load_vf(rhs))?;
+                self.inst(token, 0x8005 | VF << 8 | x << 4)?;
+                if op.text == ">" {
+                    Cond::Eq(VF, Rhs::Byte(0))
+                } else {
+                    Cond::Eq(VF, Rhs::Byte(1))
+                }
+            }
+            _ => return Err(self.error(&op, format!("unknown comparison `{}`", op.text))),
+        })
+    }
+
+    /// Emits an instruction that skips the next one when `cond` is `when`.
+    fn skip(&mut self, token: &Token, cond: Cond, when: bool) -> Result<(), AsmError> {
+        let cond = if when {
+            cond
+        } else {
+            match cond {
+                Cond::Eq(x, rhs) => Cond::Ne(x, rhs),
+                Cond::Ne(x, rhs) => Cond::Eq(x, rhs),
+                Cond::Key(x) => Cond::NotKey(x),
+                Cond::NotKey(x) => Cond::Key(x),
+            }
+        };
+        let opcode = match cond {
+            Cond::Eq(x, Rhs::Byte(b)) => 0x3000 | x << 8 | b as u16,
+            Cond::Ne(x, Rhs::Byte(b)) => 0x4000 | x << 8 | b as u16,
+            Cond::Eq(x, Rhs::V(y)) => 0x5000 | x << 8 | y << 4,
+            Cond::Ne(x, Rhs::V(y)) => 0x9000 | x << 8 | y << 4,
+            Cond::Key(x) => 0xE09E | x << 8,
+            Cond::NotKey(x) => 0xE0A1 | x << 8,
+        };
+        self.inst(token, opcode)
+    }
+
+    fn if_statement(&mut self, token: &Token) -> Result<(), AsmError> {
+        let cond = self.condition(token)?;
+        let form = self.next(token)?;
+        match form.text.as_str() {
+            "then" => {
+                // skip the guarded statement when the condition is false
+                self.skip(token, cond, false)?;
+                if self.tokens.is_empty() {
+                    return Err(self.error(&form, "`then` must be followed by a statement"));
+                }
+                Ok(())
+            }
+            "begin" => {
+                self.skip(token, cond, true)?;
+                let jump = self.here;
+                self.inst(token, 0x1000)?;
+                self.blocks.push((Block::If { jump }, token.clone()));
+                Ok(())
+            }
+            _ => Err(self.error(&form, "expected `then` or `begin`")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+
+    #[test]
+    fn main_first_needs_no_jump() {
+        assert_eq!(
+            compile(": main clear loop again").unwrap(),
+            vec![0x00, 0xE0, 0x12, 0x02]
+        );
+    }
+
+    #[test]
+    fn jumps_to_main_and_resolves_forward_calls() {
+        let rom = compile(
+            "
+            : draw  sprite v0 v1 5 ;
+            : main  i := hex v2  draw  fill  jump main
+            : fill  return
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![
+                0x12, 0x06, // jump main
+                0xD0, 0x15, 0x00, 0xEE, // draw
+                0xF2, 0x29, 0x22, 0x02, 0x22, 0x0E, 0x12, 0x06, // main
+                0x00, 0xEE, // fill
+            ]
+        );
+    }
+
+    #[test]
+    fn assignments() {
+        let rom = compile(
+            ": main
+             :alias score v3
+             :const STEP 3
+             score := 10  score += STEP  score -= 1  score := v4  v1 =- v2
+             v5 >>= v5  v6 := random 0x1F  v7 := key  v8 := delay
+             delay := v1  buzzer := v2  i += v9",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![
+                0x63, 0x0A, 0x73, 0x03, 0x73, 0xFF, 0x83, 0x40, 0x81, 0x27, 0x85, 0x56, 0xC6, 0x1F,
+                0xF7, 0x0A, 0xF8, 0x07, 0xF1, 0x15, 0xF2, 0x18, 0xF9, 0x1E,
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        let rom = compile(
+            ": main
+             loop
+               if v0 == 5 then v1 += 1
+               while v2 key
+               if v3 != v4 begin clear else return end
+             again",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![
+                0x40, 0x05, 0x71, 0x01, // if ... then
+                0xE2, 0x9E, 0x12, 0x14, // while: exit unless pressed
+                0x93, 0x40, 0x12, 0x10, 0x00, 0xE0, // if ... begin
+                0x12, 0x12, 0x00, 0xEE, // else ... end
+                0x12, 0x00, // again
+            ]
+        );
+    }
+
+    #[test]
+    fn comparisons_use_vf() {
+        assert_eq!(
+            compile(": main if v1 < 8 then clear").unwrap(),
+            vec![0x6F, 0x08, 0x8F, 0x17, 0x4F, 0x00, 0x00, 0xE0]
+        );
+    }
+
+    #[test]
+    fn macros_calc_and_sprites() {
+        let rom = compile(
+            ":macro set reg val { reg := val }
+             :calc SIZE { 2 * ( 1 + 2 ) }
+             : main set v1 SIZE  i := smile  :unpack 0xA smile
+             : smile 0b00100100 0x18 :byte { SIZE + 1 }",
+        )
+        .unwrap();
+        assert_eq!(
+            rom,
+            vec![0x61, 0x06, 0xA2, 0x08, 0x60, 0xA2, 0x61, 0x08, 0x24, 0x18, 0x07]
+        );
+    }
+
+    #[test]
+    fn errors_carry_position() {
+        let error = compile(": main\n  v1 := 300").unwrap_err();
+        assert_eq!(error.to_string(), "<input>:2:9: 300 does not fit in a byte");
+
+        let error = compile(": main missing").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 8));
+        assert_eq!(error.message, "undefined label `missing`");
+
+        let error = compile(": main loop").unwrap_err();
+        assert_eq!(error.message, "`loop` is never closed");
+    }
+}
@@ -0,0 +1,370 @@
+//! ROM patches in the IPS and BPS formats used for game hacks and
+//! translations, see `Cpu::load_patched_rom`.
+//!
+//! IPS is a list of byte runs to overwrite, with no way to tell whether it
+//! was made for the ROM it is applied to. BPS describes the new ROM in terms
+//! of the old one and carries CRC32s of the source, the target and the patch
+//! itself, which are all checked.
+
+use std::convert::TryFrom;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_END: &[u8] = b"EOF";
+/// Largest run an IPS record can hold.
+const IPS_RECORD: usize = 0xFFFF;
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ips,
+    Bps,
+}
+
+impl Format {
+    /// Picks the format from a file extension, `ips` or `bps`.
+    pub fn from_extension(ext: &str) -> Option<Format> {
+        match ext.to_ascii_lowercase().as_str() {
+            "ips" => Some(Format::Ips),
+            "bps" => Some(Format::Bps),
+            _ => None,
+        }
+    }
+}
+
+/// Applies `patch` to `rom`, telling the format from the patch's header.
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err("not an IPS or BPS patch".to_string())
+    }
+}
+
+/// Makes a patch that turns `old` into `new`.
+pub fn create(format: Format, old: &[u8], new: &[u8]) -> Result<Vec<u8>, String> {
+    match format {
+        Format::Ips => create_ips(old, new),
+        Format::Bps => Ok(create_bps(old, new)),
+    }
+}
+
+/// Reads the patch a byte at a time, failing at its end.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or("patch is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// A big-endian number of `len` bytes, as IPS stores them.
+    fn number(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |n, &byte| n << 8 | byte as usize))
+    }
+
+    /// BPS's variable length number: seven bits per byte, the last byte
+    /// flagged with the top bit, and each continuation adding one so that
+    /// every number has a single encoding.
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.bytes(1)?[0];
+            value = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|n| n.checked_add(value))
+                .ok_or("number in patch is too large")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_mul(0x80)
+                .ok_or("number in patch is too large")?;
+            value = value
+                .checked_add(shift)
+                .ok_or("number in patch is too large")?;
+        }
+    }
+
+    /// A varint whose lowest bit is the sign.
+    fn signed(&mut self) -> Result<isize, String> {
+        let n = self.varint()?;
+        let magnitude = (n >> 1) as isize;
+        Ok(if n & 1 != 0 { -magnitude } else { magnitude })
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader {
+        data: patch,
+        pos: IPS_MAGIC.len(),
+    };
+    loop {
+        if reader.bytes(3)? == IPS_END {
+            break;
+        }
+        reader.pos -= 3;
+        let offset = reader.number(3)?;
+        let (len, fill) = match reader.number(2)? {
+            // a run of one repeated byte
+            0 => (reader.number(2)?, Some(reader.bytes(1)?[0])),
+            len => (len, None),
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match fill {
+            Some(byte) => out[offset..offset + len].iter_mut().for_each(|b| *b = byte),
+            None => out[offset..offset + len].copy_from_slice(reader.bytes(len)?),
+        }
+    }
+    // an extension to the format: the length to truncate the result to
+    if let Ok(len) = reader.number(3) {
+        out.truncate(len);
+    }
+    Ok(out)
+}
+
+fn create_ips(old: &[u8], new: &[u8]) -> Result<Vec<u8>, String> {
+    if new.len() > 0xFFFFFF {
+        return Err("ROM is too large for IPS".to_string());
+    }
+    let mut out = IPS_MAGIC.to_vec();
+    let differs = |i: usize| old.get(i) != Some(&new[i]);
+    let mut i = 0;
+    while i < new.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        let mut start = i;
+        // a record at this offset would read as the end marker
+        if start == 0x454F46 {
+            start -= 1;
+        }
+        let mut end = i;
+        while end < new.len() && end - start < IPS_RECORD && differs(end) {
+            end += 1;
+        }
+        out.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        out.extend_from_slice(&new[start..end]);
+        i = end;
+    }
+    out.extend_from_slice(IPS_END);
+    if new.len() < old.len() {
+        out.extend_from_slice(&(new.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(out)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < BPS_MAGIC.len() + 12 {
+        return Err("patch is truncated".to_string());
+    }
+    let footer = patch.len() - 12;
+    let crc = |i: usize| {
+        u32::from_le_bytes([
+            patch[footer + i],
+            patch[footer + i + 1],
+            patch[footer + i + 2],
+            patch[footer + i + 3],
+        ])
+    };
+    if crc32(&patch[..footer + 8]) != crc(8) {
+        return Err("patch is corrupt: checksum mismatch".to_string());
+    }
+    if crc32(rom) != crc(0) {
+        return Err("patch was made for a different ROM: checksum mismatch".to_string());
+    }
+
+    let mut reader = Reader {
+        data: &patch[..footer],
+        pos: BPS_MAGIC.len(),
+    };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata = reader.varint()?;
+    reader.bytes(metadata)?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "patch was made for a {} byte ROM, not {} bytes",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    let mut out = Vec::new();
+    let (mut source_offset, mut target_offset) = (0isize, 0isize);
+    while reader.pos < footer {
+        let command = reader.varint()?;
+        let len = (command >> 2) + 1;
+        if out.len() + len > target_size {
+            return Err("patch writes past the end of the ROM".to_string());
+        }
+        match command & 3 {
+            // source read: the bytes of the old ROM at the same position
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(
+                    rom.get(start..start + len)
+                        .ok_or("patch reads past the end of the ROM")?,
+                );
+            }
+            // target read: new bytes stored in the patch
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // source copy: bytes from anywhere in the old ROM
+            2 => {
+                source_offset += reader.signed()?;
+                let start = usize::try_from(source_offset)
+                    .map_err(|_| "patch reads before the start of the ROM")?;
+                out.extend_from_slice(
+                    rom.get(start..start + len)
+                        .ok_or("patch reads past the end of the ROM")?,
+                );
+                source_offset += len as isize;
+            }
+            // target copy: bytes already written, which may overlap the
+            // ones being written to repeat a pattern
+            _ => {
+                target_offset += reader.signed()?;
+                for _ in 0..len {
+                    let byte = usize::try_from(target_offset)
+                        .ok()
+                        .and_then(|i| out.get(i).copied())
+                        .ok_or("patch copies from outside the ROM")?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size {
+        return Err("patch ends before the ROM is complete".to_string());
+    }
+    if crc32(&out) != crc(4) {
+        return Err("patched ROM does not match the patch's checksum".to_string());
+    }
+    Ok(out)
+}
+
+/// Makes a BPS patch that keeps the bytes `old` and `new` share in place
+/// and stores the rest. CHIP-8 ROMs are small, so there's no searching for
+/// moved blocks.
+fn create_bps(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = BPS_MAGIC.to_vec();
+    varint(&mut out, old.len());
+    varint(&mut out, new.len());
+    varint(&mut out, 0);
+
+    let same = |i: usize| old.get(i) == Some(&new[i]);
+    let mut i = 0;
+    while i < new.len() {
+        let kept = same(i);
+        let start = i;
+        while i < new.len() && same(i) == kept {
+            i += 1;
+        }
+        if kept {
+            varint(&mut out, (i - start - 1) << 2);
+        } else {
+            varint(&mut out, (i - start - 1) << 2 | 1);
+            out.extend_from_slice(&new[start..i]);
+        }
+    }
+
+    out.extend_from_slice(&crc32(old).to_le_bytes());
+    out.extend_from_slice(&crc32(new).to_le_bytes());
+    let crc = crc32(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+fn varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let low = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(0x80 | low);
+            return;
+        }
+        out.push(low);
+        value -= 1;
+    }
+}
+
+/// The CRC-32 of zip and PNG.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, crc32, create, Format};
+
+    const OLD: &[u8] = &[0x60, 0x01, 0x61, 0x02, 0x12, 0x00, 0xAA, 0xBB];
+
+    #[test]
+    fn patches_round_trip() {
+        let longer = [0x60, 0x05, 0x61, 0x02, 0x12, 0x00, 0xAA, 0xBB, 0xCC];
+        let shorter = [0x60, 0x01, 0x61, 0x09];
+        for format in [Format::Ips, Format::Bps] {
+            for new in [&longer[..], &shorter[..], OLD] {
+                let patch = create(format, OLD, new).unwrap();
+                assert_eq!(apply(OLD, &patch).unwrap(), new, "{:?}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn ips_fills_runs() {
+        // five bytes of 0xEE at offset 2, then truncate to 4 bytes
+        let patch = b"PATCH\x00\x00\x02\x00\x00\x00\x05\xEEEOF\x00\x00\x04";
+        assert_eq!(apply(OLD, patch).unwrap(), [0x60, 0x01, 0xEE, 0xEE]);
+        assert!(
+            apply(OLD, b"PATCH\x00\x00\x02\x00\x04\x01").is_err(),
+            "truncated"
+        );
+    }
+
+    #[test]
+    fn bps_checks_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let new = [0x60, 0x05, 0x61, 0x02];
+        let mut patch = create(Format::Bps, OLD, &new).unwrap();
+        let error = apply(&new, &patch).unwrap_err();
+        assert!(error.contains("different ROM"), "{}", error);
+
+        let byte = patch.len() - 13;
+        patch[byte] ^= 1;
+        let error = apply(OLD, &patch).unwrap_err();
+        assert!(error.contains("corrupt"), "{}", error);
+        assert!(apply(OLD, b"nonsense").is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! Execution profiles: instruction counts per address and per instruction
+//! kind, a call graph built from `CALL`/`RET` with inclusive and exclusive
+//! counts per subroutine, and the hottest loops.
+//!
+//! Subroutines are named by their entry address; everything not called is
+//! attributed to the entry point at `PROGRAM_START`. Loops are backward
+//! `JP`s, and their cost is the instructions executed at the addresses from
+//! the target to the jump; subroutines they call are listed separately.
+
+use crate::cpu::{Cpu, Instruction, Op, PROGRAM_START};
+use crate::disasm::mnemonic;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// How many entries each section of the report lists.
+const TOP: usize = 10;
+
+#[derive(Clone, Default)]
+struct Function {
+    inclusive: u64,
+    exclusive: u64,
+}
+
+#[derive(Clone)]
+pub struct Profiler {
+    per_address: Vec<u64>,
+    per_op: BTreeMap<&'static str, u64>,
+    functions: BTreeMap<u16, Function>,
+    /// Calls per (caller, callee) pair.
+    calls: BTreeMap<(u16, u16), u64>,
+    /// Backward jumps per (target, jump address) pair.
+    loops: BTreeMap<(u16, u16), u64>,
+    /// Entry addresses of the subroutines being executed, outermost first,
+    /// after the entry point. Holds as many calls as the CPU's stack.
+    stack: Vec<u16>,
+    instructions: u64,
+    first_frame: Option<u64>,
+    frames: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+/// Names instruction kinds the way `disasm::mnemonic` writes them, with
+/// operands as placeholders.
+fn class(op: Op) -> &'static str {
+    match op {
+        Op::Cls => "CLS",
+        Op::Ret => "RET",
+        Op::Jp => "JP addr",
+        Op::Call => "CALL addr",
+        Op::SeByte => "SE Vx, byte",
+        Op::SneByte => "SNE Vx, byte",
+        Op::SeReg => "SE Vx, Vy",
+        Op::LdByte => "LD Vx, byte",
+        Op::AddByte => "ADD Vx, byte",
+        Op::LdReg => "LD Vx, Vy",
+        Op::Or => "OR Vx, Vy",
+        Op::And => "AND Vx, Vy",
+        Op::Xor => "XOR Vx, Vy",
+        Op::AddReg => "ADD Vx, Vy",
+        Op::Sub => "SUB Vx, Vy",
+        Op::Shr => "SHR Vx, Vy",
+        Op::Subn => "SUBN Vx, Vy",
+        Op::Shl => "SHL Vx, Vy",
+        Op::SneReg => "SNE Vx, Vy",
+        Op::LdI => "LD I, addr",
+        Op::JpV0 => "JP V0, addr",
+        Op::Rnd => "RND Vx, byte",
+        Op::Drw => "DRW Vx, Vy, n",
+        Op::Skp => "SKP Vx",
+        Op::Sknp => "SKNP Vx",
+        Op::LdVxDt => "LD Vx, DT",
+        Op::LdVxK => "LD Vx, K",
+        Op::LdDtVx => "LD DT, Vx",
+        Op::LdStVx => "LD ST, Vx",
+        Op::AddIVx => "ADD I, Vx",
+        Op::LdFVx => "LD F, Vx",
+        Op::LdBVx => "LD B, Vx",
+        Op::Store => "LD [I], Vx",
+        Op::Load => "LD Vx, [I]",
+        Op::Unknown => "DW",
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            per_address: vec![0; 4096],
+            per_op: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            calls: BTreeMap::new(),
+            loops: BTreeMap::new(),
+            stack: vec![PROGRAM_START],
+            instructions: 0,
+            first_frame: None,
+            frames: 0,
+        }
+    }
+
+    /// Counts `instruction`, about to execute on `cpu`.
+    pub fn record(&mut self, cpu: &Cpu, instruction: Instruction) {
+        let pc = cpu.pc & 0xFFF;
+        self.instructions += 1;
+        self.per_address[pc as usize] += 1;
+        *self.per_op.entry(class(instruction.op)).or_insert(0) += 1;
+        let first_frame = *self.first_frame.get_or_insert(cpu.frame);
+        self.frames = cpu.frame - first_frame + 1;
+
+        let current = *self.stack.last().unwrap();
+        self.functions.entry(current).or_default().exclusive += 1;
+        // recursive subroutines are only charged once per instruction
+        for (depth, entry) in self.stack.iter().enumerate() {
+            if !self.stack[..depth].contains(entry) {
+                self.functions.entry(*entry).or_default().inclusive += 1;
+            }
+        }
+
+        match instruction.op {
+            Op::Call => {
+                *self.calls.entry((current, instruction.addr)).or_insert(0) += 1;
+                self.stack.push(instruction.addr);
+                // the CPU's stack wraps and overwrites the oldest return
+                // address, so forget the oldest call too
+                if self.stack.len() > cpu.stack.len() + 1 {
+                    self.stack.remove(1);
+                }
+            }
+            Op::Ret if self.stack.len() > 1 => {
+                self.stack.pop();
+            }
+            Op::Jp if instruction.addr <= pc => {
+                *self.loops.entry((instruction.addr, pc)).or_insert(0) += 1;
+            }
+            _ => (),
+        }
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Writes the report, disassembling instructions from `memory`.
+    pub fn report(&self, memory: &[u8]) -> String {
+        let total = self.instructions.max(1) as f64;
+        let percent = |count: u64| count as f64 / total * 100.0;
+        let per_frame = |count: u64| count as f64 / self.frames.max(1) as f64;
+        let word = |addr: u16| {
+            let addr = addr as usize & 0xFFF;
+            (memory[addr] as u16) << 8 | memory[(addr + 1) & 0xFFF] as u16
+        };
+        let name = |addr: u16| {
+            if addr == PROGRAM_START {
+                "(entry)".to_string()
+            } else {
+                format!("sub_{:03x}", addr)
+            }
+        };
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{} instructions over {} frames, {:.1} per frame",
+            self.instructions,
+            self.frames,
+            per_frame(self.instructions)
+        )
+        .unwrap();
+
+        writeln!(out, "\nhottest addresses:").unwrap();
+        let mut addresses: Vec<_> = (0..4096u16)
+            .filter(|&addr| self.per_address[addr as usize] > 0)
+            .collect();
+        addresses.sort_by_key(|&addr| std::cmp::Reverse(self.per_address[addr as usize]));
+        for &addr in addresses.iter().take(TOP) {
+            let count = self.per_address[addr as usize];
+            writeln!(
+                out,
+                "  {:03x}  {:5.1}%  {:>10}  {}",
+                addr,
+                percent(count),
+                count,
+                mnemonic(word(addr))
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\ninstruction kinds:").unwrap();
+        let mut ops: Vec<_> = self.per_op.iter().collect();
+        ops.sort_by_key(|(_, &count)| std::cmp::Reverse(count));
+        for (class, &count) in ops {
+            writeln!(
+                out,
+                "  {:<14} {:5.1}%  {:>10}",
+                class,
+                percent(count),
+                count
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nsubroutines:      inclusive   exclusive   per frame").unwrap();
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by_key(|(_, f)| std::cmp::Reverse(f.inclusive));
+        for (&entry, function) in functions.iter().take(TOP) {
+            writeln!(
+                out,
+                "  {:<12} {:>9.1}%  {:>9.1}%  {:>10.1}",
+                name(entry),
+                percent(function.inclusive),
+                percent(function.exclusive),
+                per_frame(function.inclusive)
+            )
+            .unwrap();
+        }
+
+        if !self.calls.is_empty() {
+            writeln!(out, "\ncall graph:").unwrap();
+            for (&(caller, callee), count) in &self.calls {
+                writeln!(
+                    out,
+                    "  {:<12} -> {:<12} {:>10} calls",
+                    name(caller),
+                    name(callee),
+                    count
+                )
+                .unwrap();
+            }
+        }
+
+        if !self.loops.is_empty() {
+            writeln!(out, "\nhottest loops:").unwrap();
+            let mut loops: Vec<_> = self
+                .loops
+                .iter()
+                .map(|(&(start, end), &iterations)| {
+                    let body: u64 = self.per_address[start as usize..=end as usize].iter().sum();
+                    (start, end, iterations, body)
+                })
+                .collect();
+            loops.sort_by_key(|&(_, _, _, body)| std::cmp::Reverse(body));
+            for (start, end, iterations, body) in loops.into_iter().take(TOP) {
+                writeln!(
+                    out,
+                    "  {:03x}-{:03x}  {:5.1}%  {:>10} iterations",
+                    start,
+                    end,
+                    percent(body),
+                    iterations
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profiler;
+    use crate::asm::assemble;
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn counts_calls_and_loops() {
+        let rom = assemble(
+            "
+            loop:
+                CALL work
+                JP loop
+            work:
+                LD V0, 1
+                RET
+            ",
+        )
+        .unwrap()
+        .rom;
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.profiler = Some(Profiler::new());
+        for _ in 0..400 {
+            cpu.emulate_cycle();
+        }
+        let profiler = cpu.profiler.take().unwrap();
+        assert_eq!(profiler.instructions(), 400);
+        assert_eq!(profiler.per_address[0x204], 100);
+        assert_eq!(profiler.calls[&(0x200, 0x204)], 100);
+        assert_eq!(profiler.loops[&(0x200, 0x202)], 100);
+
+        let main = &profiler.functions[&0x200];
+        let work = &profiler.functions[&0x204];
+        assert_eq!((main.inclusive, main.exclusive), (400, 200));
+        assert_eq!((work.inclusive, work.exclusive), (200, 200));
+
+        let report = profiler.report(&cpu.memory);
+        assert!(report.contains("(entry)      -> sub_204             100 calls"));
+        assert!(report.contains("200-202   50.0%         100 iterations"));
+    }
+
+    #[test]
+    fn call_stack_is_bounded() {
+        // recursion that never returns, as a CPU stack overflow does
+        let rom = assemble("deeper: CALL deeper").unwrap().rom;
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.profiler = Some(Profiler::new());
+        for _ in 0..100 {
+            cpu.emulate_cycle();
+        }
+        let profiler = cpu.profiler.take().unwrap();
+        assert_eq!(profiler.stack.len(), 17, "entry point and 16 calls");
+        assert_eq!(profiler.functions[&0x200].exclusive, 100);
+    }
+}
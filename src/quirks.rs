@@ -0,0 +1,133 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// The machines whose interpreter behaviour a quirk profile reproduces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    CosmacVip,
+    SuperChip,
+    XoChip,
+}
+
+impl Platform {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::CosmacVip => Quirks {
+                vblank_wait: true,
+                clip_sprites: true,
+                shift_vy: true,
+                load_store_increment: true,
+            },
+            Platform::SuperChip => Quirks {
+                vblank_wait: false,
+                clip_sprites: true,
+                shift_vy: false,
+                load_store_increment: false,
+            },
+            Platform::XoChip => Quirks {
+                vblank_wait: false,
+                clip_sprites: false,
+                shift_vy: true,
+                load_store_increment: true,
+            },
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Platform, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" | "chip8" | "chip-8" => Ok(Platform::CosmacVip),
+            "schip" | "superchip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!("unknown platform `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Platform::CosmacVip => "vip",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        })
+    }
+}
+
+/// Behaviours that differ between CHIP-8 interpreters. The default profile
+/// keeps the behaviour this emulator always had.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// `DRW` halts the CPU until the next 60 Hz vertical blank.
+    pub vblank_wait: bool,
+    /// `DRW` drops sprite pixels past the screen edge instead of wrapping.
+    pub clip_sprites: bool,
+    /// `SHR`/`SHL` shift Vy into Vx instead of shifting Vx in place.
+    pub shift_vy: bool,
+    /// `LD [I], Vx` and `LD Vx, [I]` leave I pointing past the last register.
+    pub load_store_increment: bool,
+}
+
+impl Quirks {
+    /// The `apply` spec that turns `base` into these quirks.
+    pub fn spec(&self, base: &Quirks) -> String {
+        let flags = [
+            ("vblank-wait", self.vblank_wait, base.vblank_wait),
+            ("clip-sprites", self.clip_sprites, base.clip_sprites),
+            ("shift-vy", self.shift_vy, base.shift_vy),
+            (
+                "load-store-increment",
+                self.load_store_increment,
+                base.load_store_increment,
+            ),
+        ];
+        let changed: Vec<String> = flags
+            .iter()
+            .filter(|(_, on, base)| on != base)
+            .map(|(name, on, _)| format!("{}{}", if *on { "" } else { "no-" }, name))
+            .collect();
+        changed.join(",")
+    }
+
+    /// Applies a comma separated list of quirk names, each optionally
+    /// prefixed with `no-` to turn it off, e.g. `clip-sprites,no-vblank-wait`.
+    pub fn apply(&mut self, spec: &str) -> Result<(), String> {
+        for name in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, on) = match name.strip_prefix("no-") {
+                Some(name) => (name, false),
+                None => (name, true),
+            };
+            match name {
+                "vblank-wait" => self.vblank_wait = on,
+                "clip-sprites" => self.clip_sprites = on,
+                "shift-vy" => self.shift_vy = on,
+                "load-store-increment" => self.load_store_increment = on,
+                _ => return Err(format!("unknown quirk `{}`", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Platform, Quirks};
+
+    #[test]
+    fn apply_overrides_profile() {
+        let mut quirks = "vip".parse::<Platform>().unwrap().quirks();
+        quirks.apply("no-vblank-wait, clip-sprites").unwrap();
+        assert!(!quirks.vblank_wait, "vblank wait was turned off");
+        assert!(quirks.clip_sprites, "clipping stays on");
+
+        assert!(Quirks::default().apply("bogus").is_err(), "unknown quirk");
+
+        let base = Platform::SuperChip.quirks();
+        let mut parsed = base;
+        parsed.apply(&quirks.spec(&base)).unwrap();
+        assert_eq!(parsed, quirks, "spec round-trips");
+    }
+}
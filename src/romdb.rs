@@ -0,0 +1,227 @@
+use crate::quirks::{Platform, Quirks};
+use serde_json::Value;
+
+/// What is known about a ROM, as in the community chip-8-database
+/// (https://github.com/chip-8/chip-8-database): enough to run it without
+/// picking quirks by hand.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RomInfo {
+    pub sha1: &'static str,
+    pub title: &'static str,
+    pub author: &'static str,
+    pub platform: Platform,
+    /// Overrides on top of the platform profile, see `Quirks::apply`.
+    pub quirks: &'static str,
+    /// Recommended instructions per second.
+    pub ips: Option<u32>,
+    /// How the game uses the keypad.
+    pub keys: &'static str,
+    /// Foreground and background colours.
+    pub colours: Option<(u32, u32)>,
+}
+
+impl RomInfo {
+    pub fn quirks(&self) -> Quirks {
+        let mut quirks = self.platform.quirks();
+        quirks
+            .apply(self.quirks)
+            .expect("ROM database entries use known quirk names");
+        quirks
+    }
+}
+
+/// Known ROMs, keyed by the SHA-1 of the file. These take precedence over
+/// the imported `COMMUNITY` entries.
+pub static ROMS: &[RomInfo] = &[
+    RomInfo {
+        sha1: "a0073e944d5ae9ca14324543fdf818907de80449",
+        title: "Sierpinski",
+        author: "Sergey Naydenov",
+        platform: Platform::CosmacVip,
+        quirks: "",
+        ips: Some(500),
+        keys: "",
+        colours: None,
+    },
+    RomInfo {
+        sha1: "8e592d3620481e00ea36d29765b95287c7349a70",
+        title: "c8int test",
+        author: "Skosulor",
+        platform: Platform::CosmacVip,
+        quirks: "",
+        ips: None,
+        keys: "",
+        colours: None,
+    },
+];
+
+pub fn sha1(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+// The entries imported from the community database by `import`.
+include!("romdb_community.rs");
+
+pub fn lookup(rom: &[u8]) -> Option<&'static RomInfo> {
+    let hash = sha1(rom);
+    ROMS.iter().chain(COMMUNITY).find(|info| info.sha1 == hash)
+}
+
+/// The platform profile closest to a community database platform id.
+fn platform(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" | "chip8x" => Some(Platform::CosmacVip),
+        "chip48" | "superchip1" | "superchip" | "megachip8" => Some(Platform::SuperChip),
+        "modernChip8" | "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+/// The quirk overrides for a ROM's `quirkyPlatforms` entry, relative to
+/// `platform`'s profile.
+fn quirk_overrides(platform: Platform, quirky: &Value) -> String {
+    let defaults = platform.quirks();
+    let mut quirks = defaults;
+    // the database names the quirks after the deviation from the VIP
+    let flag = |name: &str| quirky[name].as_bool();
+    if let Some(on) = flag("vblank") {
+        quirks.vblank_wait = on;
+    }
+    if let Some(on) = flag("wrap") {
+        quirks.clip_sprites = !on;
+    }
+    if let Some(on) = flag("shift") {
+        quirks.shift_vy = !on;
+    }
+    if let Some(on) = flag("memoryLeaveIUnchanged") {
+        quirks.load_store_increment = !on;
+    }
+    quirks.spec(&defaults)
+}
+
+fn colour(value: &Value) -> Option<u32> {
+    u32::from_str_radix(value.as_str()?.trim_start_matches('#'), 16).ok()
+}
+
+/// Turns the community database's `programs.json` into the source of
+/// `src/romdb_community.rs`. ROMs only for platforms this emulator has no
+/// profile for are left out.
+pub fn import(programs: &str) -> Result<String, String> {
+    let programs: Value = serde_json::from_str(programs).map_err(|e| e.to_string())?;
+    let programs = programs.as_array().ok_or("expected an array of programs")?;
+
+    let mut out = String::from(
+        "// Generated by `chip8 import-romdb programs.json` from the community\n\
+         // chip-8-database. Do not edit by hand.\n\n\
+         /// ROMs from the community database, keyed by the SHA-1 of the file.\n\
+         pub static COMMUNITY: &[RomInfo] = &[\n",
+    );
+    for program in programs {
+        let title = program["title"].as_str().unwrap_or("");
+        let authors: Vec<&str> = program["authors"]
+            .as_array()
+            .map(|authors| authors.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let roms = match program["roms"].as_object() {
+            Some(roms) => roms,
+            None => continue,
+        };
+        for (hash, rom) in roms {
+            let found = rom["platforms"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .find_map(|id| platform(id).map(|platform| (id, platform)));
+            let (id, platform) = match found {
+                Some(found) if hash.len() == 40 => found,
+                _ => continue,
+            };
+            let quirks = quirk_overrides(platform, &rom["quirkyPlatforms"][id]);
+            let ips = rom["tickrate"]
+                .as_u64()
+                .map_or("None".to_string(), |rate| format!("Some({})", rate * 60));
+            let mut keys: Vec<String> = rom["keys"]
+                .as_object()
+                .map(|keys| {
+                    keys.iter()
+                        .filter_map(|(name, key)| Some(format!("{} {:X}", name, key.as_u64()?)))
+                        .collect()
+                })
+                .unwrap_or_default();
+            keys.sort();
+            let pixels = &rom["colors"]["pixels"];
+            let colours = match (colour(&pixels[1]), colour(&pixels[0])) {
+                (Some(fg), Some(bg)) => format!("Some(({:#08x}, {:#08x}))", fg, bg),
+                _ => "None".to_string(),
+            };
+            out += &format!(
+                "    RomInfo {{\n        sha1: {:?},\n        title: {:?},\n        \
+                 author: {:?},\n        platform: Platform::{:?},\n        quirks: {:?},\n        \
+                 ips: {},\n        keys: {:?},\n        colours: {},\n    }},\n",
+                hash.to_ascii_lowercase(),
+                title,
+                authors.join(", "),
+                platform,
+                quirks,
+                ips,
+                keys.join(", "),
+                colours,
+            );
+        }
+    }
+    out += "];\n";
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import, lookup, sha1, COMMUNITY, ROMS};
+
+    #[test]
+    fn sha1_of_empty_rom() {
+        assert_eq!(sha1(&[]), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    }
+
+    #[test]
+    fn entries_are_valid() {
+        for info in ROMS.iter().chain(COMMUNITY) {
+            assert_eq!(info.sha1.len(), 40, "{} has a full hash", info.title);
+            info.quirks();
+        }
+        assert_eq!(lookup(&[0x12, 0x00]), None, "unknown ROM");
+    }
+
+    #[test]
+    fn imports_community_entries() {
+        let programs = r##"[{
+            "title": "Pong",
+            "authors": ["Paul Vervalin"],
+            "roms": {
+                "0123456789abcdef0123456789abcdef01234567": {
+                    "platforms": ["originalChip8"],
+                    "quirkyPlatforms": { "originalChip8": { "vblank": false, "shift": true } },
+                    "tickrate": 15,
+                    "keys": { "player1Up": 1, "player2Up": 12 },
+                    "colors": { "pixels": ["#000000", "#33ff66"] }
+                },
+                "ffffffffffffffffffffffffffffffffffffffff": { "platforms": ["megachip8x"] }
+            }
+        }]"##;
+        let source = import(programs).unwrap();
+        assert_eq!(
+            source.matches("RomInfo {").count(),
+            1,
+            "unknown platforms are skipped"
+        );
+        assert!(
+            source.contains(r#"quirks: "no-vblank-wait,no-shift-vy","#),
+            "{}",
+            source
+        );
+        assert!(source.contains("ips: Some(900),"));
+        assert!(source.contains(r#"keys: "player1Up 1, player2Up C","#));
+        assert!(source.contains("colours: Some((0x33ff66, 0x000000)),"));
+        assert!(import("{}").is_err());
+    }
+}
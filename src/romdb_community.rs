@@ -0,0 +1,6 @@
+// Generated by `chip8 import-romdb programs.json` from the community
+// chip-8-database. Do not edit by hand.
+
+/// ROMs from the community database, keyed by the SHA-1 of the file.
+pub static COMMUNITY: &[RomInfo] = &[
+];
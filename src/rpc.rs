@@ -0,0 +1,553 @@
+//! A JSON-RPC 2.0 server for driving the emulator from other programs.
+//! Requests and responses are one JSON object per line on a TCP connection,
+//! and the machine runs at 60 frames a second between requests unless it
+//! is paused.
+//!
+//! Methods, with their parameters:
+//!
+//! - `load {path, platform?, quirks?, ips?, paused?}`: a ROM, `.asm` or
+//!   `.8o` file. Returns the ROM database entry's title, if any.
+//! - `pause`, `resume`
+//! - `step {count?}`: instructions, then returns `getRegisters`
+//! - `runFrames {count?}`: whole frames, paused or not
+//! - `setKeys {keys}`: the keys held from now on, e.g. `[1, 12]`
+//! - `getRegisters`: `pc`, `i`, `sp`, `dt`, `st`, `v`, `stack`, `cycles`,
+//!   `frame` and `paused`
+//! - `readMemory {address, length, encoding?}`, `writeMemory {address, data}`
+//! - `getFramebuffer {encoding?}`: one bit per pixel, eight to a byte with
+//!   the leftmost in the top bit, rows top to bottom
+//! - `subscribe {framebuffer?}`, `unsubscribe`: `frame` notifications with
+//!   `frame` and `changed` (and `framebuffer`) after every frame
+//!
+//! Bytes are base64 strings, or arrays of numbers with `encoding: "bytes"`.
+
+use crate::asm;
+use crate::cpu::Cpu;
+use crate::debugger::Debugger;
+use crate::display::{HEIGHT, WIDTH};
+use crate::octo;
+use crate::quirks::Platform;
+use crate::romdb::RomInfo;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Requests that need a machine before `load`.
+const NO_MACHINE: i64 = -32000;
+
+type Error = (i64, String);
+
+fn invalid_params(message: impl Into<String>) -> Error {
+    (INVALID_PARAMS, message.into())
+}
+
+struct Machine {
+    cpu: Cpu,
+    debugger: Debugger,
+}
+
+#[derive(Default)]
+pub struct Server {
+    machine: Option<Machine>,
+    pub paused: bool,
+    /// Whether `frame` notifications include the framebuffer, if
+    /// subscribed.
+    subscription: Option<bool>,
+    /// Notifications waiting to be sent.
+    events: Vec<Value>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Whether frames run between requests.
+    pub fn running(&self) -> bool {
+        self.machine.is_some() && !self.paused
+    }
+
+    /// Handles one line of input: a request, or a batch of them. Returns the
+    /// response line, if any.
+    pub fn handle_line(&mut self, line: &str) -> Option<String> {
+        let response = match serde_json::from_str::<Value>(line) {
+            Ok(Value::Array(batch)) if !batch.is_empty() => {
+                let responses: Vec<Value> = batch
+                    .iter()
+                    .filter_map(|request| self.handle(request))
+                    .collect();
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            Ok(request) => self.handle(&request),
+            Err(e) => Some(error_response(Value::Null, (PARSE_ERROR, e.to_string()))),
+        };
+        response.map(|response| response.to_string())
+    }
+
+    /// Handles a request and returns its response, or `None` for a
+    /// notification.
+    pub fn handle(&mut self, request: &Value) -> Option<Value> {
+        let id = request.get("id").cloned();
+        let result = match request["method"].as_str() {
+            Some(method) if request["jsonrpc"] == "2.0" => {
+                self.dispatch(method, &request["params"])
+            }
+            _ => Err((INVALID_REQUEST, "not a JSON-RPC 2.0 request".to_string())),
+        };
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    /// Notifications produced since the last call.
+    pub fn take_events(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Runs `count` frames, queueing a notification after each for
+    /// subscribers.
+    pub fn run_frames(&mut self, count: u64) {
+        let machine = match &mut self.machine {
+            Some(machine) => machine,
+            None => return,
+        };
+        for _ in 0..count {
+            machine.debugger.run_frames(&mut machine.cpu, 1);
+            let changed = machine.cpu.display.take_dirty().is_some();
+            if let Some(with_framebuffer) = self.subscription {
+                let mut params = json!({ "frame": machine.cpu.frame, "changed": changed });
+                if with_framebuffer {
+                    params["framebuffer"] = json!(STANDARD.encode(framebuffer(&machine.cpu)));
+                }
+                self.events
+                    .push(json!({ "jsonrpc": "2.0", "method": "frame", "params": params }));
+            }
+        }
+    }
+
+    fn dispatch(&mut self, method: &str, params: &Value) -> Result<Value, Error> {
+        let count = || -> Result<u64, Error> {
+            match &params["count"] {
+                Value::Null => Ok(1),
+                count => count
+                    .as_u64()
+                    .ok_or_else(|| invalid_params("`count` must be a number")),
+            }
+        };
+        match method {
+            "load" => {
+                let (machine, info) = load(params)?;
+                self.paused = params["paused"].as_bool().unwrap_or(false);
+                self.machine = Some(machine);
+                return Ok(json!({ "title": info.map(|info| info.title) }));
+            }
+            "subscribe" => {
+                self.subscription = Some(params["framebuffer"].as_bool().unwrap_or(false));
+                return Ok(json!(true));
+            }
+            "unsubscribe" => {
+                self.subscription = None;
+                return Ok(json!(true));
+            }
+            _ => {}
+        }
+
+        let machine = self
+            .machine
+            .as_mut()
+            .ok_or((NO_MACHINE, "no ROM loaded".to_string()))?;
+        let cpu = &mut machine.cpu;
+        Ok(match method {
+            "pause" => {
+                self.paused = true;
+                json!(true)
+            }
+            "resume" => {
+                self.paused = false;
+                json!(true)
+            }
+            "step" => {
+                for _ in 0..count()? {
+                    machine.debugger.step(cpu);
+                }
+                registers(cpu, self.paused)
+            }
+            "runFrames" => {
+                let count = count()?;
+                self.run_frames(count);
+                json!(self.machine.as_ref().map(|machine| machine.cpu.frame))
+            }
+            "setKeys" => {
+                let mut keys = [false; 16];
+                let held = params["keys"]
+                    .as_array()
+                    .ok_or_else(|| invalid_params("`keys` must be an array"))?;
+                for key in held {
+                    match key.as_u64() {
+                        Some(key) if key < 16 => keys[key as usize] = true,
+                        _ => return Err(invalid_params("keys are 0 to 15")),
+                    }
+                }
+                cpu.keypad.keys = keys;
+                json!(true)
+            }
+            "getRegisters" => registers(cpu, self.paused),
+            "readMemory" => {
+                let address = address(params)?;
+                let length = params["length"]
+                    .as_u64()
+                    .ok_or_else(|| invalid_params("`length` must be a number"))?
+                    as usize;
+                let end = address.saturating_add(length).min(cpu.memory.len());
+                encode(&cpu.memory[address..end], params)?
+            }
+            "writeMemory" => {
+                let address = address(params)?;
+                let data = decode(&params["data"])?;
+                if address + data.len() > cpu.memory.len() {
+                    return Err(invalid_params("data runs past the end of memory"));
+                }
+                cpu.memory[address..address + data.len()].copy_from_slice(&data);
+                cpu.flush_decoded();
+                json!(data.len())
+            }
+            "getFramebuffer" => json!({
+                "width": WIDTH,
+                "height": HEIGHT,
+                "data": encode(&framebuffer(cpu), params)?,
+            }),
+            _ => return Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+        })
+    }
+}
+
+fn error_response(id: Value, (code, message): Error) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn load(params: &Value) -> Result<(Machine, Option<&'static RomInfo>), Error> {
+    let path = params["path"]
+        .as_str()
+        .map(Path::new)
+        .ok_or_else(|| invalid_params("`path` must be a string"))?;
+    let rom = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("asm") => asm::assemble_file(path)
+            .map(|assembly| assembly.rom)
+            .map_err(|e| e.to_string()),
+        Some("8o") => octo::compile_file(path).map_err(|e| e.to_string()),
+        _ => fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e)),
+    }
+    .map_err(invalid_params)?;
+
+    let mut cpu = Cpu::new();
+    let info = cpu.load_rom(&rom).map_err(invalid_params)?;
+    if let Some(platform) = params["platform"].as_str() {
+        cpu.quirks = platform
+            .parse::<Platform>()
+            .map_err(invalid_params)?
+            .quirks();
+    }
+    if let Some(spec) = params["quirks"].as_str() {
+        cpu.quirks.apply(spec).map_err(invalid_params)?;
+    }
+    let ips = params["ips"]
+        .as_u64()
+        .or_else(|| info.and_then(|info| info.ips).map(u64::from))
+        .unwrap_or(500);
+    let machine = Machine {
+        cpu,
+        debugger: Debugger::new((ips / 60).max(1) as usize),
+    };
+    Ok((machine, info))
+}
+
+fn registers(cpu: &Cpu, paused: bool) -> Value {
+    json!({
+        "pc": cpu.pc,
+        "i": cpu.i,
+        "sp": cpu.sp,
+        "dt": cpu.dt,
+        "st": cpu.st,
+        "v": cpu.v,
+        "stack": cpu.stack[..cpu.sp as usize & 0xF],
+        "cycles": cpu.cycles,
+        "frame": cpu.frame,
+        "paused": paused,
+    })
+}
+
+fn address(params: &Value) -> Result<usize, Error> {
+    match params["address"].as_u64() {
+        Some(address) if address < 0x1000 => Ok(address as usize),
+        _ => Err(invalid_params("`address` must be 0 to 4095")),
+    }
+}
+
+/// The screen packed eight pixels to a byte.
+fn framebuffer(cpu: &Cpu) -> Vec<u8> {
+    let mut bytes = vec![0; WIDTH * HEIGHT / 8];
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            if cpu.display.get_pixel(x, y) {
+                bytes[(y * WIDTH + x) / 8] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+    bytes
+}
+
+fn encode(bytes: &[u8], params: &Value) -> Result<Value, Error> {
+    match params["encoding"].as_str() {
+        None | Some("base64") => Ok(json!(STANDARD.encode(bytes))),
+        Some("bytes") => Ok(json!(bytes)),
+        Some(other) => Err(invalid_params(format!("unknown encoding `{}`", other))),
+    }
+}
+
+fn decode(data: &Value) -> Result<Vec<u8>, Error> {
+    match data {
+        Value::String(text) => STANDARD
+            .decode(text)
+            .map_err(|e| invalid_params(e.to_string())),
+        Value::Array(bytes) => bytes
+            .iter()
+            .map(|byte| match byte.as_u64() {
+                Some(byte) if byte <= 0xFF => Ok(byte as u8),
+                _ => Err(invalid_params("bytes are 0 to 255")),
+            })
+            .collect(),
+        _ => Err(invalid_params("`data` must be base64 or an array of bytes")),
+    }
+}
+
+/// Serves one client until it disconnects, running frames on time while
+/// the machine isn't paused.
+pub fn session(server: &mut Server, stream: TcpStream) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let input = stream.try_clone()?;
+    thread::spawn(move || {
+        for line in BufReader::new(input).lines() {
+            if line.ok().is_none_or(|line| sender.send(line).is_err()) {
+                break;
+            }
+        }
+    });
+
+    let mut out = stream;
+    let mut next_frame = Instant::now();
+    loop {
+        let line = if server.running() {
+            match receiver.recv_timeout(next_frame.saturating_duration_since(Instant::now())) {
+                Ok(line) => Some(line),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match receiver.recv() {
+                Ok(line) => Some(line),
+                Err(_) => return Ok(()),
+            }
+        };
+
+        if let Some(line) = line {
+            if let Some(response) = server.handle_line(&line) {
+                writeln!(out, "{}", response)?;
+            }
+        }
+        let now = Instant::now();
+        if !server.running() {
+            next_frame = now;
+        } else if now >= next_frame {
+            server.run_frames(1);
+            // a slow client costs frames rather than a burst to catch up
+            next_frame = (next_frame + FRAME).max(now);
+        }
+        for event in server.take_events() {
+            writeln!(out, "{}", event)?;
+        }
+    }
+}
+
+/// Serves clients on `address` one after another; the machine carries over
+/// from one to the next.
+pub fn serve(address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("listening on {}", listener.local_addr()?);
+    let mut server = Server::new();
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| session(&mut server, stream)) {
+            eprintln!("{}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{session, Server};
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::thread;
+
+    /// A ROM that draws the top of the `0` glyph at (0, 0) and then counts
+    /// V1 up while key 5 is held.
+    fn rom(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chip8-rpc-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("count.ch8");
+        // LD I, 0; DRW V0, V0, 1; LD V2, 5; SKNP V2; ADD V1, 1; JP 0x206
+        let rom = [
+            0xA0, 0x00, 0xD0, 0x01, 0x62, 0x05, 0xE2, 0xA1, 0x71, 0x01, 0x12, 0x06,
+        ];
+        fs::write(&path, rom).unwrap();
+        path
+    }
+
+    fn call(server: &mut Server, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let response: Value =
+            serde_json::from_str(&server.handle_line(&request.to_string()).unwrap()).unwrap();
+        response
+    }
+
+    #[test]
+    fn controls_the_machine() {
+        let mut server = Server::new();
+        let error = call(&mut server, "step", json!({}));
+        assert_eq!(error["error"]["code"], -32000, "nothing is loaded yet");
+
+        let path = rom("control");
+        call(&mut server, "load", json!({ "path": path, "paused": true }));
+        assert!(!server.running());
+        let registers = call(&mut server, "step", json!({ "count": 2 }))["result"].clone();
+        assert_eq!(registers["pc"], 0x204);
+        assert_eq!(registers["paused"], true);
+
+        let memory = call(
+            &mut server,
+            "readMemory",
+            json!({ "address": 0, "length": 5 }),
+        );
+        assert_eq!(memory["result"], "8JCQkPA=", "the font's 0 in base64");
+        call(
+            &mut server,
+            "writeMemory",
+            json!({ "address": 0x300, "data": [1, 2] }),
+        );
+        let memory = call(
+            &mut server,
+            "readMemory",
+            json!({ "address": 0x300, "length": 2, "encoding": "bytes" }),
+        );
+        assert_eq!(memory["result"], json!([1, 2]));
+        let memory = call(
+            &mut server,
+            "readMemory",
+            json!({ "address": 0xFFE, "length": u64::MAX, "encoding": "bytes" }),
+        );
+        assert_eq!(
+            memory["result"],
+            json!([0, 0]),
+            "stops at the end of memory"
+        );
+
+        let screen = call(
+            &mut server,
+            "getFramebuffer",
+            json!({ "encoding": "bytes" }),
+        );
+        let data = screen["result"]["data"].as_array().unwrap();
+        assert_eq!(
+            (data.len(), &data[0], &data[1]),
+            (256, &json!(0xF0), &json!(0))
+        );
+
+        call(&mut server, "setKeys", json!({ "keys": [5] }));
+        assert_eq!(
+            call(&mut server, "runFrames", json!({ "count": 2 }))["result"],
+            2
+        );
+        let registers = call(&mut server, "getRegisters", json!(null))["result"].clone();
+        assert!(
+            registers["v"][1].as_u64().unwrap() > 0,
+            "the held key is seen"
+        );
+
+        let unknown = call(&mut server, "fly", json!({}));
+        assert_eq!(unknown["error"]["code"], -32601);
+        let batch = r#"[{"jsonrpc": "2.0", "method": "pause"}, {"jsonrpc": "2.0", "id": 7, "method": "resume"}]"#;
+        assert_eq!(
+            server.handle_line(batch).unwrap(),
+            r#"[{"id":7,"jsonrpc":"2.0","result":true}]"#,
+            "notifications get no response"
+        );
+        assert!(server.running());
+        assert!(server.handle_line("{").unwrap().contains("-32700"));
+
+        let too_big = path.with_file_name("big.ch8");
+        fs::write(&too_big, vec![0; 0x1000]).unwrap();
+        let error = call(&mut server, "load", json!({ "path": too_big }));
+        assert_eq!(error["error"]["code"], -32602, "{}", error);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn streams_frame_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = session(&mut Server::new(), stream);
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        let path = rom("events");
+        for (id, method, params) in [
+            (1, "subscribe", json!({ "framebuffer": true })),
+            (2, "load", json!({ "path": path })),
+        ] {
+            let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+            writeln!(stream, "{}", request).unwrap();
+        }
+
+        // the machine runs on its own once loaded
+        let mut frames = Vec::new();
+        while frames.len() < 3 {
+            let message: Value = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            if message["method"] == "frame" {
+                frames.push(message["params"].clone());
+            }
+        }
+        assert_eq!(frames[0]["frame"], 1);
+        assert_eq!(frames[0]["changed"], true, "the first frame draws");
+        assert_eq!(frames[1]["changed"], false);
+        assert!(frames[2]["framebuffer"]
+            .as_str()
+            .unwrap()
+            .starts_with("8AAA"));
+
+        drop(lines);
+        drop(stream);
+        server.join().unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+//! Scripting with [Rhai](https://rhai.rs) for automation and test scenarios.
+//!
+//! Scripts drive one machine:
+//!
+//! - `load(path)` loads a ROM, `.asm` or `.8o` file, relative to the script;
+//!   `load_asm(source)` assembles and loads source text. `platform(name)`,
+//!   `quirks(spec)`, `ips(n)` and `seed(n)` configure it after loading.
+//! - `press(key)`, `release(key)` and `release_all()` set the keypad.
+//! - `run(frames)` runs whole frames and `step()` one instruction.
+//! - `pc()`, `i()`, `sp()`, `dt()`, `st()`, `v(x)`, `peek(addr)`,
+//!   `cycles()` and `frame()` read the machine; `set_v(x, value)` and
+//!   `poke(addr, byte)` change it.
+//! - `pixel(x, y)`, `screen()` (as `Display::to_ascii` draws it) and
+//!   `screenshot(path)` (a PBM image) look at the display.
+//! - `assert(condition, message)` and `assert_pixel(x, y, lit)` fail the
+//!   script.
+//! - `on_frame(|frame| ...)` runs after every frame of `run`, and
+//!   `on_break(addr, |addr| ...)` before the instruction at `addr`.
+
+use crate::asm;
+use crate::cpu::Cpu;
+use crate::debugger::{Debugger, Stop};
+use crate::display::{HEIGHT, WIDTH};
+use crate::octo;
+use crate::quirks::Platform;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, NativeCallContext, INT};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+type Result<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+/// Instructions per second when neither `ips` nor the ROM database says.
+const DEFAULT_IPS: u32 = 500;
+
+struct State {
+    cpu: Cpu,
+    debugger: Debugger,
+    frame_hooks: Vec<FnPtr>,
+    break_hooks: BTreeMap<u16, Vec<FnPtr>>,
+    /// Where paths in the script are relative to.
+    dir: PathBuf,
+}
+
+impl State {
+    fn load(&mut self, rom: &[u8]) -> Result<()> {
+        let mut cpu = Cpu::new();
+        let info = cpu.load_rom(rom)?;
+        self.cpu = cpu;
+        let ips = info.and_then(|info| info.ips).unwrap_or(DEFAULT_IPS);
+        self.debugger.cycles_per_frame = (ips as usize / 60).max(1);
+        Ok(())
+    }
+}
+
+fn key(key: INT) -> Result<usize> {
+    if (0..16).contains(&key) {
+        Ok(key as usize)
+    } else {
+        Err(format!("no key {:#x}", key).into())
+    }
+}
+
+fn address(addr: INT) -> Result<usize> {
+    if (0..0x1000).contains(&addr) {
+        Ok(addr as usize)
+    } else {
+        Err(format!("address {:#x} is outside memory", addr).into())
+    }
+}
+
+fn register(x: INT) -> Result<usize> {
+    if (0..16).contains(&x) {
+        Ok(x as usize)
+    } else {
+        Err(format!("no register V{}", x).into())
+    }
+}
+
+/// Runs `frames` frames, calling breakpoint hooks before their instruction
+/// and frame hooks after every frame. No borrow of `state` is held while
+/// hooks run, so they can use the whole API.
+fn run(context: &NativeCallContext, state: &Rc<RefCell<State>>, frames: INT) -> Result<()> {
+    for _ in 0..frames.max(0) {
+        let end = state.borrow().cpu.frame + 1;
+        loop {
+            let stop = {
+                let state = &mut *state.borrow_mut();
+                let frames = end.saturating_sub(state.cpu.frame);
+                state.debugger.run(&mut state.cpu, frames)
+            };
+            let addr = match stop {
+                Stop::Breakpoint(addr) => addr,
+                Stop::Condition | Stop::Limit => break,
+            };
+            let hooks = state.borrow().break_hooks.get(&addr).cloned();
+            for hook in hooks.unwrap_or_default() {
+                let _ = hook.call_within_context::<Dynamic>(context, (addr as INT,))?;
+            }
+            let state = &mut *state.borrow_mut();
+            state.debugger.step(&mut state.cpu);
+            if state.cpu.frame >= end {
+                break;
+            }
+        }
+
+        let (hooks, frame) = {
+            let state = state.borrow();
+            (state.frame_hooks.clone(), state.cpu.frame)
+        };
+        for hook in hooks {
+            let _ = hook.call_within_context::<Dynamic>(context, (frame as INT,))?;
+        }
+    }
+    Ok(())
+}
+
+/// A script engine with the machine API registered.
+pub struct Script {
+    engine: Engine,
+    state: Rc<RefCell<State>>,
+}
+
+impl Script {
+    /// Creates an engine whose scripts resolve paths relative to `dir`.
+    pub fn new(dir: &Path) -> Script {
+        let state = Rc::new(RefCell::new(State {
+            cpu: Cpu::new(),
+            debugger: Debugger::new(DEFAULT_IPS as usize / 60),
+            frame_hooks: Vec::new(),
+            break_hooks: BTreeMap::new(),
+            dir: dir.to_path_buf(),
+        }));
+        let mut engine = Engine::new();
+
+        let s = state.clone();
+        engine.register_fn("load", move |path: &str| -> Result<()> {
+            let path = s.borrow().dir.join(path);
+            let rom = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("asm") => asm::assemble_file(&path)
+                    .map(|assembly| assembly.rom)
+                    .map_err(|e| e.to_string()),
+                Some("8o") => octo::compile_file(&path).map_err(|e| e.to_string()),
+                _ => {
+                    fs::read(&path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))
+                }
+            }?;
+            s.borrow_mut().load(&rom)
+        });
+        let s = state.clone();
+        engine.register_fn("load_asm", move |source: &str| -> Result<()> {
+            let assembly = asm::assemble(source).map_err(|e| e.to_string())?;
+            s.borrow_mut().load(&assembly.rom)
+        });
+        let s = state.clone();
+        engine.register_fn("platform", move |name: &str| -> Result<()> {
+            s.borrow_mut().cpu.quirks = name.parse::<Platform>()?.quirks();
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("quirks", move |spec: &str| -> Result<()> {
+            Ok(s.borrow_mut().cpu.quirks.apply(spec)?)
+        });
+        let s = state.clone();
+        engine.register_fn("ips", move |ips: INT| {
+            s.borrow_mut().debugger.cycles_per_frame = (ips / 60).max(1) as usize;
+        });
+        let s = state.clone();
+        engine.register_fn("seed", move |seed: INT| {
+            s.borrow_mut().cpu.rng = StdRng::seed_from_u64(seed as u64);
+        });
+
+        let s = state.clone();
+        engine.register_fn("press", move |k: INT| -> Result<()> {
+            s.borrow_mut().cpu.keypad.keys[key(k)?] = true;
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("release", move |k: INT| -> Result<()> {
+            s.borrow_mut().cpu.keypad.keys[key(k)?] = false;
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("release_all", move || {
+            s.borrow_mut().cpu.keypad.keys = [false; 16];
+        });
+
+        let s = state.clone();
+        engine.register_fn("run", move |context: NativeCallContext, frames: INT| {
+            run(&context, &s, frames)
+        });
+        let s = state.clone();
+        engine.register_fn("step", move || {
+            let state = &mut *s.borrow_mut();
+            state.debugger.step(&mut state.cpu);
+        });
+
+        let s = state.clone();
+        engine.register_fn("pc", move || s.borrow().cpu.pc as INT);
+        let s = state.clone();
+        engine.register_fn("i", move || s.borrow().cpu.i as INT);
+        let s = state.clone();
+        engine.register_fn("sp", move || s.borrow().cpu.sp as INT);
+        let s = state.clone();
+        engine.register_fn("dt", move || s.borrow().cpu.dt as INT);
+        let s = state.clone();
+        engine.register_fn("st", move || s.borrow().cpu.st as INT);
+        let s = state.clone();
+        engine.register_fn("cycles", move || s.borrow().cpu.cycles as INT);
+        let s = state.clone();
+        engine.register_fn("frame", move || s.borrow().cpu.frame as INT);
+        let s = state.clone();
+        engine.register_fn("v", move |x: INT| -> Result<INT> {
+            Ok(s.borrow().cpu.v[register(x)?] as INT)
+        });
+        let s = state.clone();
+        engine.register_fn("set_v", move |x: INT, value: INT| -> Result<()> {
+            s.borrow_mut().cpu.v[register(x)?] = value as u8;
+            Ok(())
+        });
+        let s = state.clone();
+        engine.register_fn("peek", move |addr: INT| -> Result<INT> {
+            Ok(s.borrow().cpu.memory[address(addr)?] as INT)
+        });
+        let s = state.clone();
+        engine.register_fn("poke", move |addr: INT, byte: INT| -> Result<()> {
+            let cpu = &mut s.borrow_mut().cpu;
+            cpu.memory[address(addr)?] = byte as u8;
+            cpu.flush_decoded();
+            Ok(())
+        });
+
+        let s = state.clone();
+        engine.register_fn("pixel", move |x: INT, y: INT| -> Result<bool> {
+            pixel(&s.borrow().cpu, x, y)
+        });
+        let s = state.clone();
+        engine.register_fn("screen", move || s.borrow().cpu.display.to_ascii());
+        let s = state.clone();
+        engine.register_fn("screenshot", move |path: &str| -> Result<()> {
+            let state = s.borrow();
+            let path = state.dir.join(path);
+            fs::write(&path, state.cpu.display.to_pbm())
+                .map_err(|e| format!("Unable to write {}: {}", path.display(), e).into())
+        });
+
+        engine.register_fn("assert", |condition: bool, message: &str| -> Result<()> {
+            if condition {
+                Ok(())
+            } else {
+                Err(format!("assertion failed: {}", message).into())
+            }
+        });
+        let s = state.clone();
+        engine.register_fn(
+            "assert_pixel",
+            move |x: INT, y: INT, lit: bool| -> Result<()> {
+                if pixel(&s.borrow().cpu, x, y)? == lit {
+                    Ok(())
+                } else {
+                    let state = if lit { "lit" } else { "dark" };
+                    Err(format!("assertion failed: pixel ({}, {}) is not {}", x, y, state).into())
+                }
+            },
+        );
+
+        let s = state.clone();
+        engine.register_fn("on_frame", move |hook: FnPtr| {
+            s.borrow_mut().frame_hooks.push(hook);
+        });
+        let s = state.clone();
+        engine.register_fn("on_break", move |addr: INT, hook: FnPtr| -> Result<()> {
+            let addr = address(addr)? as u16;
+            let state = &mut *s.borrow_mut();
+            state.debugger.breakpoints.insert(addr);
+            state.break_hooks.entry(addr).or_default().push(hook);
+            Ok(())
+        });
+
+        Script { engine, state }
+    }
+
+    /// Runs `source`, returning the error and its position if it fails.
+    pub fn run(&self, source: &str) -> std::result::Result<(), String> {
+        self.engine.run(source).map_err(|e| e.to_string())
+    }
+
+    /// The machine, for inspecting it after a script ran.
+    pub fn cpu(&self) -> std::cell::Ref<'_, Cpu> {
+        std::cell::Ref::map(self.state.borrow(), |state| &state.cpu)
+    }
+}
+
+fn pixel(cpu: &Cpu, x: INT, y: INT) -> Result<bool> {
+    if (0..WIDTH as INT).contains(&x) && (0..HEIGHT as INT).contains(&y) {
+        Ok(cpu.display.get_pixel(x as usize, y as usize))
+    } else {
+        Err(format!("pixel ({}, {}) is off the screen", x, y).into())
+    }
+}
+
+/// Runs the script at `path`, resolving its paths relative to its directory.
+pub fn run_file(path: &Path) -> std::result::Result<(), String> {
+    let source = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    Script::new(dir)
+        .run(&source)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Script;
+    use std::fs;
+    use std::path::Path;
+
+    const COUNTER: &str = r#"
+        load_asm(`
+            loop:
+                LD F, V0
+                DRW V1, V1, 5
+                ADD V0, 1
+                LD V2, K
+                JP loop
+        `);
+    "#;
+
+    #[test]
+    fn drives_the_machine() {
+        let script = Script::new(Path::new("."));
+        let source = format!(
+            "{}{}",
+            COUNTER,
+            r#"
+            let frames = [];
+            let hits = 0;
+            on_frame(|frame| frames.push(frame));
+            on_break(0x204, |addr| { hits += 1; assert(addr == 0x204, "address"); });
+            run(3);
+            assert(frames == [1, 2, 3], "frame hooks: " + frames);
+            assert(hits == 1, "break hooks: " + hits);
+            assert(v(0) == 1 && pc() == 0x206, "waits for a key");
+            assert_pixel(1, 0, true);
+            assert(screen().starts_with(`####....`), screen());
+
+            press(7);
+            step();
+            release_all();
+            run(1);
+            assert(v(2) == 7 && v(0) == 2 && hits == 2, "key read");
+            "#
+        );
+        script.run(&source).unwrap();
+        assert_eq!(script.cpu().cycles, 32);
+    }
+
+    #[test]
+    fn failures_have_positions() {
+        let script = Script::new(Path::new("."));
+        let error = script
+            .run(&format!(
+                "{}\n{}",
+                COUNTER, "run(1);\nassert_pixel(0, 4, false);"
+            ))
+            .unwrap_err();
+        assert!(error.contains("pixel (0, 4) is not dark"), "{}", error);
+        assert!(error.contains("line 12"), "{}", error);
+        assert!(script.run("press(16);").is_err());
+        assert!(script.run("load(\"missing.ch8\");").is_err());
+
+        let dir = std::env::temp_dir().join(format!("chip8-script-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("big.ch8"), vec![0; 0x1000]).unwrap();
+        let error = Script::new(&dir).run("load(\"big.ch8\");").unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.contains("too large"), "{}", error);
+    }
+}
@@ -0,0 +1,83 @@
+//! Approximate COSMAC VIP instruction timings, for running ROMs at the speed
+//! of the original interpreter instead of a flat number of instructions per
+//! frame.
+//!
+//! The VIP's 1802 ran at 1.76 MHz with eight clocks per machine cycle, giving
+//! 3668 machine cycles per 60 Hz frame. Display DMA and the frame interrupt
+//! take part of that; the rest goes to the interpreter, whose costs below
+//! are machine cycles including fetch and dispatch. The figures follow
+//! published disassemblies of the interpreter and are averages: the real
+//! `DRW` for example also depends on how the sprite straddles bytes.
+
+use crate::cpu::{Cpu, Instruction, Op};
+
+/// Machine cycles per frame left to the interpreter after display DMA and
+/// the interrupt routine.
+pub const VIP_CYCLES_PER_FRAME: i32 = 3668 - 1024 - 108;
+
+/// Machine cycles spent fetching and dispatching every instruction.
+const DISPATCH: u32 = 40;
+
+/// Machine cycles `instruction` takes on the VIP, given the state it is
+/// about to execute in.
+pub fn vip_cycles(cpu: &Cpu, instruction: Instruction) -> u32 {
+    let vx = cpu.v[instruction.x as usize];
+    let vy = cpu.v[instruction.y as usize];
+    let skip = |taken: bool| if taken { 4 } else { 0 };
+
+    DISPATCH
+        + match instruction.op {
+            Op::Cls => 24 + 4 * 256,
+            Op::Ret => 10,
+            Op::Jp => 12,
+            Op::Call => 26,
+            Op::SeByte => 10 + skip(vx == instruction.byte),
+            Op::SneByte => 10 + skip(vx != instruction.byte),
+            Op::SeReg => 14 + skip(vx == vy),
+            Op::SneReg => 14 + skip(vx != vy),
+            Op::LdByte => 6,
+            Op::AddByte => 10,
+            Op::LdReg | Op::Or | Op::And | Op::Xor => 44,
+            Op::AddReg | Op::Sub | Op::Shr | Op::Subn | Op::Shl => 44,
+            Op::LdI => 12,
+            Op::JpV0 => 22,
+            Op::Rnd => 36,
+            Op::Drw => {
+                // unaligned sprites are shifted across two bytes per row
+                let rows = instruction.n as u32;
+                let shifted = if !vx.is_multiple_of(8) { 20 * rows } else { 0 };
+                68 + 46 * rows + shifted
+            }
+            Op::Skp => 14 + skip(cpu.keypad.is_pressed(vx & 0xF)),
+            Op::Sknp => 14 + skip(!cpu.keypad.is_pressed(vx & 0xF)),
+            Op::LdVxDt | Op::LdDtVx | Op::LdStVx => 10,
+            // one pass of the key polling loop
+            Op::LdVxK => 18,
+            Op::AddIVx | Op::LdFVx => 16,
+            Op::LdBVx => 80 + 16 * (vx / 100 + vx / 10 % 10 + vx % 10) as u32,
+            Op::Store | Op::Load => 14 + 14 * (instruction.x as u32 + 1),
+            Op::Unknown => 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{vip_cycles, VIP_CYCLES_PER_FRAME};
+    use crate::cpu::{decode, Cpu};
+
+    #[test]
+    fn slow_instructions_cost_more() {
+        let mut cpu = Cpu::new();
+        let ld = vip_cycles(&cpu, decode(0x6001));
+        let drw = vip_cycles(&cpu, decode(0xD015));
+        assert!(drw > 5 * ld);
+        cpu.v[0] = 3;
+        assert!(
+            vip_cycles(&cpu, decode(0xD015)) > drw,
+            "unaligned sprites are slower"
+        );
+        cpu.v[1] = 199;
+        assert!(vip_cycles(&cpu, decode(0xF133)) > vip_cycles(&cpu, decode(0xF033)));
+        assert!(vip_cycles(&cpu, decode(0x00E0)) < VIP_CYCLES_PER_FRAME as u32);
+    }
+}
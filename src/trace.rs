@@ -0,0 +1,159 @@
+//! Per-instruction execution traces, for diffing against other emulators.
+//!
+//! Every traced instruction produces one line recording the machine state
+//! before it executes:
+//!
+//! ```text
+//! V0:00 V1:00 ... VF:00 I:0000 SP:0 PC:0206 O:a22a ; cyc:12 frm:0 dt:00 st:00 LD I, 0x22a
+//! ```
+//!
+//! Up to the `;` this is the state line of the trace written by Cadmium
+//! (https://github.com/gulrak/cadmium), in lower case hex, so the two
+//! traces diff cleanly after `cut -d';' -f1`. The cycle and frame counters
+//! after it are decimal.
+
+use crate::cpu::Cpu;
+use crate::disasm::mnemonic;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    /// Only trace instructions at these addresses.
+    pub addresses: Option<RangeInclusive<u16>>,
+    /// Only trace instructions executed during these frames.
+    pub frames: Option<RangeInclusive<u64>>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>) -> Tracer {
+        Tracer {
+            out,
+            addresses: None,
+            frames: None,
+        }
+    }
+
+    pub fn wants(&self, cpu: &Cpu) -> bool {
+        self.addresses.as_ref().is_none_or(|r| r.contains(&cpu.pc))
+            && self.frames.as_ref().is_none_or(|r| r.contains(&cpu.frame))
+    }
+
+    /// Writes the record for `opcode`, about to execute on `cpu`.
+    pub fn record(&mut self, cpu: &Cpu, opcode: u16) -> io::Result<()> {
+        if self.wants(cpu) {
+            writeln!(self.out, "{}", line(cpu, opcode))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn line(cpu: &Cpu, opcode: u16) -> String {
+    let mut line = String::new();
+    for (i, v) in cpu.v.iter().enumerate() {
+        write!(line, "V{:X}:{:02x} ", i, v).unwrap();
+    }
+    write!(
+        line,
+        "I:{:04x} SP:{:1x} PC:{:04x} O:{:04x} ; cyc:{} frm:{} dt:{:02x} st:{:02x} {}",
+        cpu.i,
+        cpu.sp,
+        cpu.pc,
+        opcode,
+        cpu.cycles,
+        cpu.frame,
+        cpu.dt,
+        cpu.st,
+        mnemonic(opcode)
+    )
+    .unwrap();
+    line
+}
+
+/// Parses `start-end` or a single value, in decimal or `0x` hex. A range
+/// that ends before it starts is an error rather than an empty filter.
+pub fn parse_range(s: &str) -> Result<RangeInclusive<u64>, String> {
+    let number = |s: &str| {
+        let s = s.trim();
+        match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => s.parse(),
+        }
+        .map_err(|_| format!("invalid number `{}`", s))
+    };
+    match s.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (number(start)?, number(end)?);
+            if start > end {
+                return Err(format!("range `{}` ends before it starts", s));
+            }
+            Ok(start..=end)
+        }
+        None => {
+            let n = number(s)?;
+            Ok(n..=n)
+        }
+    }
+}
+
+/// Parses an address range as `parse_range` does, rejecting addresses
+/// outside the 4K of memory.
+pub fn parse_address_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let range = parse_range(s)?;
+    match (*range.start(), *range.end()) {
+        (start, end) if start <= 0xFFF && end <= 0xFFF => Ok(start as u16..=end as u16),
+        (start, end) => Err(format!(
+            "{:#x} is outside memory",
+            if start > 0xFFF { start } else { end }
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line, parse_address_range, parse_range, Tracer};
+    use crate::cpu::Cpu;
+    use std::io;
+
+    #[test]
+    fn line_format() {
+        let mut cpu = Cpu::new();
+        cpu.v[0xA] = 0x3C;
+        cpu.i = 0x22A;
+        cpu.cycles = 12;
+        assert_eq!(
+            line(&cpu, 0xA22A),
+            "V0:00 V1:00 V2:00 V3:00 V4:00 V5:00 V6:00 V7:00 \
+             V8:00 V9:00 VA:3c VB:00 VC:00 VD:00 VE:00 VF:00 \
+             I:022a SP:0 PC:0200 O:a22a ; cyc:12 frm:0 dt:00 st:00 LD I, 0x22a"
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let mut tracer = Tracer::new(Box::new(io::sink()));
+        tracer.addresses = Some(0x300..=0x3FF);
+        tracer.frames = Some(parse_range("2-3").unwrap());
+
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x300;
+        assert!(!tracer.wants(&cpu), "frame 0 is filtered out");
+        cpu.frame = 2;
+        assert!(tracer.wants(&cpu), "address and frame in range");
+        cpu.pc = 0x200;
+        assert!(!tracer.wants(&cpu), "address is filtered out");
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("0x200-0x2ff"), Ok(0x200..=0x2FF));
+        assert_eq!(parse_range("7"), Ok(7..=7));
+        assert!(parse_range("x-1").is_err());
+        assert!(parse_range("100-50").is_err(), "reversed");
+
+        assert_eq!(parse_address_range("0x200-0xfff"), Ok(0x200..=0xFFF));
+        assert!(parse_address_range("0x10000").is_err(), "would truncate");
+        assert!(parse_address_range("0x200-0x1000").is_err());
+    }
+}
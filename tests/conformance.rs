@@ -0,0 +1,167 @@
+//! Runs the test ROMs in `tests/roms`, and the third-party `c8_test.c8`,
+//! headless for a fixed number of frames and compares the final screen with
+//! the golden images in `tests/golden`.
+//!
+//! The ROMs in `tests/roms` were written for this emulator, so their golden
+//! images only record what it drew when they were checked in by eye.
+//! `c8_test.c8` grades itself, drawing OK or the number of the first failed
+//! check, which makes its golden images independent of this emulator.
+//!
+//! After an intended change to what a ROM draws, run with `BLESS=1` to
+//! rewrite the golden images, and check the new ones in by eye.
+
+use chip8::asm;
+use chip8::cpu::Cpu;
+use chip8::quirks::Platform;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+struct Run {
+    /// Relative to the crate root; `.asm` files are assembled first.
+    rom: &'static str,
+    platform: Platform,
+    cycles_per_frame: usize,
+    frames: u64,
+    /// Keys held from the given frame on, until the next entry.
+    input: &'static [(u64, &'static [u8])],
+}
+
+fn check(golden: &str, run: Run) {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let path = root.join(run.rom);
+    let rom = if run.rom.ends_with(".asm") {
+        asm::assemble_file(&path)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .rom
+    } else {
+        fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    };
+
+    let mut cpu = Cpu::new();
+    cpu.load_rom(&rom).unwrap();
+    cpu.quirks = run.platform.quirks();
+    for frame in 0..run.frames {
+        if let Some((_, keys)) = run.input.iter().rev().find(|(at, _)| *at <= frame) {
+            cpu.keypad.keys = [false; 16];
+            for &key in keys.iter() {
+                cpu.keypad.keys[key as usize] = true;
+            }
+        }
+        cpu.run_frame(run.cycles_per_frame);
+    }
+    let screen = cpu.display.to_ascii();
+
+    let path = root.join("tests/golden").join(format!("{}.txt", golden));
+    if env::var_os("BLESS").is_some() {
+        fs::write(&path, &screen).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {} (run with BLESS=1 to create it)", path.display(), e));
+    assert!(
+        screen == expected,
+        "{} differs from {}\nexpected:\n{}\nactual:\n{}",
+        run.rom,
+        path.display(),
+        expected,
+        screen
+    );
+}
+
+#[test]
+fn logo() {
+    check(
+        "logo",
+        Run {
+            rom: "tests/roms/logo.asm",
+            platform: Platform::CosmacVip,
+            cycles_per_frame: 100,
+            frames: 20,
+            input: &[],
+        },
+    );
+}
+
+#[test]
+fn opcodes() {
+    check(
+        "opcodes",
+        Run {
+            rom: "tests/roms/opcodes.asm",
+            platform: Platform::CosmacVip,
+            cycles_per_frame: 100,
+            frames: 60,
+            input: &[],
+        },
+    );
+}
+
+#[test]
+fn flags() {
+    check(
+        "flags",
+        Run {
+            rom: "tests/roms/flags.asm",
+            platform: Platform::CosmacVip,
+            cycles_per_frame: 100,
+            frames: 60,
+            input: &[],
+        },
+    );
+}
+
+#[test]
+fn quirks() {
+    for platform in &[Platform::CosmacVip, Platform::SuperChip, Platform::XoChip] {
+        check(
+            &format!("quirks-{}", platform),
+            Run {
+                rom: "tests/roms/quirks.asm",
+                platform: *platform,
+                cycles_per_frame: 100,
+                frames: 30,
+                input: &[],
+            },
+        );
+    }
+}
+
+#[test]
+fn keypad() {
+    check(
+        "keypad",
+        Run {
+            rom: "tests/roms/keypad.asm",
+            platform: Platform::CosmacVip,
+            cycles_per_frame: 100,
+            frames: 40,
+            input: &[
+                (5, &[0x5]),
+                (8, &[]),
+                (12, &[0xA]),
+                (15, &[]),
+                (18, &[0x3]),
+                (21, &[]),
+                (25, &[0xF]),
+            ],
+        },
+    );
+}
+
+/// Skosulor's c8int test, written for an interpreter that shifts Vx in
+/// place and leaves I alone in `LD [I], Vx`. It checks that the delay timer
+/// ticked within its first hundred or so instructions, so runs slowly.
+#[test]
+fn c8_test() {
+    check(
+        "c8_test",
+        Run {
+            rom: "c8_test.c8",
+            platform: Platform::SuperChip,
+            cycles_per_frame: 10,
+            frames: 60,
+            input: &[],
+        },
+    );
+}
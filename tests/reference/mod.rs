@@ -0,0 +1,322 @@
+//! A deliberately plain reference model of the CHIP-8 machine, written from
+//! the instruction set description rather than from `Cpu`, for differential
+//! testing. It favours obviousness over speed: every instruction is decoded
+//! from scratch and the screen is a grid of booleans.
+//!
+//! Conventions shared with `Cpu` that the instruction set leaves open:
+//! addresses wrap at 4K, the stack has 16 entries and wraps, I is 16 bits
+//! and wraps, only the low nibble of Vx selects a key or a font glyph, and
+//! `LD Vx, K` takes the highest held key.
+
+#![allow(dead_code)]
+
+use chip8::cpu::{Cpu, PROGRAM_START};
+use chip8::display::FONT_SET;
+use chip8::quirks::Quirks;
+
+pub const W: usize = 64;
+pub const H: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Machine {
+    pub memory: Vec<u8>,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub dt: u8,
+    pub st: u8,
+    pub keys: [bool; 16],
+    pub screen: Vec<bool>,
+    pub waiting: bool,
+    pub quirks: Quirks,
+}
+
+impl Machine {
+    pub fn new(quirks: Quirks) -> Machine {
+        let mut memory = vec![0; 4096];
+        for (glyph, rows) in FONT_SET.iter().enumerate() {
+            memory[glyph * 5..glyph * 5 + 5].copy_from_slice(rows);
+        }
+        Machine {
+            memory,
+            v: [0; 16],
+            i: 0,
+            pc: PROGRAM_START,
+            sp: 0,
+            stack: [0; 16],
+            dt: 0,
+            st: 0,
+            keys: [false; 16],
+            screen: vec![false; W * H],
+            waiting: false,
+            quirks,
+        }
+    }
+
+    /// Captures the state of `cpu` in the model's terms.
+    pub fn of(cpu: &Cpu) -> Machine {
+        Machine {
+            memory: cpu.memory.to_vec(),
+            v: cpu.v,
+            i: cpu.i,
+            pc: cpu.pc,
+            sp: cpu.sp,
+            stack: cpu.stack,
+            dt: cpu.dt,
+            st: cpu.st,
+            keys: cpu.keypad.keys,
+            screen: (0..W * H)
+                .map(|p| cpu.display.get_pixel(p % W, p / W))
+                .collect(),
+            waiting: cpu.waiting_for_vblank,
+            quirks: cpu.quirks,
+        }
+    }
+
+    /// Puts this state into `cpu`.
+    pub fn store(&self, cpu: &mut Cpu) {
+        cpu.memory.copy_from_slice(&self.memory);
+        cpu.v = self.v;
+        cpu.i = self.i;
+        cpu.pc = self.pc;
+        cpu.sp = self.sp;
+        cpu.stack = self.stack;
+        cpu.dt = self.dt;
+        cpu.st = self.st;
+        cpu.keypad.keys = self.keys;
+        for (p, &lit) in self.screen.iter().enumerate() {
+            cpu.display.set_pixel(p % W, p / W, lit);
+        }
+        cpu.waiting_for_vblank = self.waiting;
+        cpu.quirks = self.quirks;
+    }
+
+    fn read(&self, addr: usize) -> u8 {
+        self.memory[addr % 4096]
+    }
+
+    pub fn opcode(&self) -> u16 {
+        u16::from_be_bytes([self.read(self.pc as usize), self.read(self.pc as usize + 1)])
+    }
+
+    /// Runs the instruction at PC unless a `DRW` is waiting for the vertical
+    /// blank. `random` is the byte `RND` masks.
+    pub fn cycle(&mut self, random: u8) {
+        if !self.waiting {
+            self.step(random);
+        }
+    }
+
+    pub fn vblank(&mut self) {
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
+        self.waiting = false;
+    }
+
+    fn step(&mut self, random: u8) {
+        let op = self.opcode();
+        let x = (op >> 8 & 0xF) as usize;
+        let y = (op >> 4 & 0xF) as usize;
+        let n = op & 0xF;
+        let nn = op as u8;
+        let nnn = op & 0xFFF;
+        let (vx, vy) = (self.v[x], self.v[y]);
+
+        let mut next = (self.pc + 2) % 4096;
+        let skip = |next: u16, cond: bool| if cond { (next + 2) % 4096 } else { next };
+
+        match op >> 12 {
+            0x0 if op == 0x00E0 => self.screen = vec![false; W * H],
+            0x0 if op == 0x00EE => {
+                self.sp = (self.sp + 15) % 16;
+                next = self.stack[self.sp as usize];
+            }
+            0x1 => next = nnn,
+            0x2 => {
+                self.stack[self.sp as usize % 16] = next;
+                self.sp = (self.sp + 1) % 16;
+                next = nnn;
+            }
+            0x3 => next = skip(next, vx == nn),
+            0x4 => next = skip(next, vx != nn),
+            0x5 if n == 0 => next = skip(next, vx == vy),
+            0x6 => self.v[x] = nn,
+            0x7 => self.v[x] = ((vx as u16 + nn as u16) % 256) as u8,
+            0x8 => {
+                // flag results are written after the result, so VF as the
+                // destination ends up holding the flag
+                let (result, flag) = match n {
+                    0x0 => (vy, None),
+                    0x1 => (vx | vy, None),
+                    0x2 => (vx & vy, None),
+                    0x3 => (vx ^ vy, None),
+                    0x4 => {
+                        let sum = vx as u16 + vy as u16;
+                        ((sum % 256) as u8, Some((sum > 255) as u8))
+                    }
+                    0x5 => (vx.wrapping_sub(vy), Some((vx >= vy) as u8)),
+                    0x7 => (vy.wrapping_sub(vx), Some((vy >= vx) as u8)),
+                    0x6 => {
+                        let src = if self.quirks.shift_vy { vy } else { vx };
+                        (src / 2, Some(src % 2))
+                    }
+                    0xE => {
+                        let src = if self.quirks.shift_vy { vy } else { vx };
+                        (((src as u16 * 2) % 256) as u8, Some(src / 128))
+                    }
+                    _ => (vx, None),
+                };
+                self.v[x] = result;
+                if let Some(flag) = flag {
+                    self.v[0xF] = flag;
+                }
+            }
+            0x9 if n == 0 => next = skip(next, vx != vy),
+            0xA => self.i = nnn,
+            0xB => next = (nnn + self.v[0] as u16) % 4096,
+            0xC => self.v[x] = random & nn,
+            0xD => {
+                let x0 = vx as usize % W;
+                let y0 = vy as usize % H;
+                let mut hit = false;
+                for row in 0..n as usize {
+                    let bits = self.read(self.i as usize + row);
+                    for col in 0..8 {
+                        if bits & (0x80 >> col) == 0 {
+                            continue;
+                        }
+                        let (px, py) = (x0 + col, y0 + row);
+                        if self.quirks.clip_sprites && (px >= W || py >= H) {
+                            continue;
+                        }
+                        let p = (py % H) * W + px % W;
+                        hit |= self.screen[p];
+                        self.screen[p] = !self.screen[p];
+                    }
+                }
+                self.v[0xF] = hit as u8;
+                self.waiting = self.quirks.vblank_wait;
+            }
+            0xE if nn == 0x9E => next = skip(next, self.keys[vx as usize % 16]),
+            0xE if nn == 0xA1 => next = skip(next, !self.keys[vx as usize % 16]),
+            0xF => match nn {
+                0x07 => self.v[x] = self.dt,
+                0x0A => match (0..16).rev().find(|&k| self.keys[k]) {
+                    Some(key) => self.v[x] = key as u8,
+                    None => next = self.pc,
+                },
+                0x15 => self.dt = vx,
+                0x18 => self.st = vx,
+                0x1E => self.i = self.i.wrapping_add(vx as u16),
+                0x29 => self.i = (vx % 16) as u16 * 5,
+                0x33 => {
+                    for (k, digit) in [vx / 100, vx / 10 % 10, vx % 10].iter().enumerate() {
+                        self.memory[(self.i as usize + k) % 4096] = *digit;
+                    }
+                }
+                0x55 | 0x65 => {
+                    for r in 0..=x {
+                        let addr = (self.i as usize + r) % 4096;
+                        if nn == 0x55 {
+                            self.memory[addr] = self.v[r];
+                        } else {
+                            self.v[r] = self.memory[addr];
+                        }
+                    }
+                    if self.quirks.load_store_increment {
+                        self.i = self.i.wrapping_add(x as u16 + 1);
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+        self.pc = next;
+    }
+}
+
+/// Names the first part of the state where `cpu` and `model` disagree.
+pub fn difference(cpu: &Cpu, model: &Machine) -> Option<String> {
+    let actual = Machine::of(cpu);
+    let fields: [(&str, bool); 11] = [
+        ("pc", actual.pc == model.pc),
+        ("i", actual.i == model.i),
+        ("v", actual.v == model.v),
+        ("sp", actual.sp == model.sp),
+        ("stack", actual.stack == model.stack),
+        ("dt", actual.dt == model.dt),
+        ("st", actual.st == model.st),
+        ("memory", actual.memory == model.memory),
+        ("screen", actual.screen == model.screen),
+        ("waiting", actual.waiting == model.waiting),
+        ("keys", actual.keys == model.keys),
+    ];
+    let (name, _) = fields.iter().find(|(_, same)| !same)?;
+    Some(match *name {
+        "pc" => format!("pc: cpu {:#05x}, model {:#05x}", actual.pc, model.pc),
+        "i" => format!("i: cpu {:#06x}, model {:#06x}", actual.i, model.i),
+        "v" => format!("v: cpu {:02x?}, model {:02x?}", actual.v, model.v),
+        "sp" => format!("sp: cpu {}, model {}", actual.sp, model.sp),
+        "stack" => format!(
+            "stack: cpu {:03x?}, model {:03x?}",
+            actual.stack, model.stack
+        ),
+        "memory" => {
+            let addr = (0..4096)
+                .find(|&a| actual.memory[a] != model.memory[a])
+                .unwrap();
+            format!(
+                "memory[{:#05x}]: cpu {:#04x}, model {:#04x}",
+                addr, actual.memory[addr], model.memory[addr]
+            )
+        }
+        "screen" => {
+            let p = (0..W * H)
+                .find(|&p| actual.screen[p] != model.screen[p])
+                .unwrap();
+            format!(
+                "pixel ({}, {}): cpu {}, model {}",
+                p % W,
+                p / W,
+                actual.screen[p],
+                model.screen[p]
+            )
+        }
+        name => format!("{} differs", name),
+    })
+}
+
+/// Runs one cycle on both `cpu` and `model` and panics with a description
+/// of the instruction if they disagree afterwards or `cpu` panics.
+pub fn step_both(cpu: &mut Cpu, model: &mut Machine) {
+    let op = model.opcode();
+    let context = format!(
+        "opcode {:04X} at {:#05x} with I={:#06x} V={:02x?} SP={} quirks {:?}",
+        op, model.pc, model.i, model.v, model.sp, model.quirks
+    );
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cpu.emulate_cycle()));
+    if result.is_err() {
+        panic!("cpu panicked on {}", context);
+    }
+
+    // RND is checked for its mask here and the value handed to the model
+    let mut random = 0;
+    if !model.waiting && op >> 12 == 0xC {
+        let x = (op >> 8 & 0xF) as usize;
+        assert_eq!(
+            cpu.v[x] & !(op as u8),
+            0,
+            "RND ignored its mask on {}",
+            context
+        );
+        random = cpu.v[x];
+    }
+    model.cycle(random);
+
+    if let Some(difference) = difference(cpu, model) {
+        panic!("{} after {}", difference, context);
+    }
+}
@@ -0,0 +1,164 @@
+; VF after arithmetic, including VF as the destination, where the flag must
+; win over the result. See report.asm for the result grid.
+
+    LD V9, 0
+    LD VA, 0
+    LD VB, 0
+
+; 0: ADD Vx, Vy carry
+    LD VE, 1
+    LD V0, 200
+    LD V1, 55
+    ADD V0, V1
+    SE VF, 0
+    LD VE, 0
+    LD V1, 57
+    ADD V0, V1
+    SE VF, 1
+    LD VE, 0
+    SE V0, 56
+    LD VE, 0
+    CALL report
+
+; 1: ADD VF, Vy
+    LD VE, 1
+    LD VF, 0x10
+    LD V1, 0x20
+    ADD VF, V1
+    SE VF, 0
+    LD VE, 0
+    LD VF, 0xF0
+    ADD VF, V1
+    SE VF, 1
+    LD VE, 0
+    CALL report
+
+; 2: SUB Vx, Vy borrow
+    LD VE, 1
+    LD V0, 5
+    LD V1, 3
+    SUB V0, V1
+    SE VF, 1
+    LD VE, 0
+    SUB V0, V1
+    SE VF, 0
+    LD VE, 0
+    LD V0, 3
+    SUB V0, V1
+    SE VF, 1
+    LD VE, 0
+    CALL report
+
+; 3: SUB VF, Vy
+    LD VE, 1
+    LD VF, 5
+    LD V1, 3
+    SUB VF, V1
+    SE VF, 1
+    LD VE, 0
+    LD VF, 2
+    SUB VF, V1
+    SE VF, 0
+    LD VE, 0
+    CALL report
+
+; 4: SUBN Vx, Vy borrow
+    LD VE, 1
+    LD V0, 3
+    LD V1, 5
+    SUBN V0, V1
+    SE VF, 1
+    LD VE, 0
+    LD V0, 6
+    SUBN V0, V1
+    SE VF, 0
+    LD VE, 0
+    CALL report
+
+; 5: SUBN VF, Vy
+    LD VE, 1
+    LD VF, 3
+    LD V1, 5
+    SUBN VF, V1
+    SE VF, 1
+    LD VE, 0
+    LD VF, 6
+    SUBN VF, V1
+    SE VF, 0
+    LD VE, 0
+    CALL report
+
+; 6: SHR Vx shifts the low bit into VF
+    LD VE, 1
+    LD V0, 3
+    SHR V0, V0
+    SE VF, 1
+    LD VE, 0
+    SHR V0, V0
+    SE VF, 1
+    LD VE, 0
+    SHR V0, V0
+    SE VF, 0
+    LD VE, 0
+    LD VF, 2
+    SHR VF, VF
+    SE VF, 0
+    LD VE, 0
+    CALL report
+
+; 7: SHL Vx shifts the high bit into VF
+    LD VE, 1
+    LD V0, 0xC0
+    SHL V0, V0
+    SE VF, 1
+    LD VE, 0
+    SHL V0, V0
+    SE VF, 1
+    LD VE, 0
+    SHL V0, V0
+    SE VF, 0
+    LD VE, 0
+    LD VF, 0x40
+    SHL VF, VF
+    SE VF, 0
+    LD VE, 0
+    CALL report
+
+; 8: ADD Vx, byte leaves VF alone
+    LD VE, 1
+    LD VF, 1
+    LD V0, 0x10
+    ADD V0, 0x10
+    SE VF, 1
+    LD VE, 0
+    LD VF, 0
+    LD V0, 0xF0
+    ADD V0, 0x20
+    SE VF, 0
+    LD VE, 0
+    CALL report
+
+; 9: DRW reports collisions in VF, and erases by drawing again
+    LD VE, 1
+    LD V0, 40
+    LD V1, 26
+    LD I, block
+    DRW V0, V1, 2
+    SE VF, 0
+    LD VE, 0
+    DRW V0, V1, 2
+    SE VF, 1
+    LD VE, 0
+    DRW V0, V1, 2
+    SE VF, 0
+    LD VE, 0
+    DRW V0, V1, 2
+    CALL report
+
+done:
+    JP done
+
+block:
+    db 0b11110000, 0b11110000
+
+include "report.asm"
@@ -0,0 +1,36 @@
+; Waits for three keys with LD Vx, K and shows them along the top row, each
+; time waiting for the key to be released. Then waits for F to be held with
+; SKP and shows a tick below.
+
+    LD V8, 3
+    LD VA, 0
+    LD VB, 0
+next:
+    LD V0, K
+    LD F, V0
+    DRW VA, VB, 5
+    ADD VA, 6
+release:
+    SKNP V0
+    JP release
+    ADD V8, 0xFF
+    SE V8, 0
+    JP next
+
+    LD V1, 0xF
+hold:
+    SKP V1
+    JP hold
+    LD VB, 8
+    LD I, tick
+    DRW VB, VB, 5
+
+done:
+    JP done
+
+tick:
+    db 0b00000001
+    db 0b00000010
+    db 0b10000100
+    db 0b01001000
+    db 0b00110000
@@ -0,0 +1,45 @@
+; Straight-line drawing in the manner of the IBM logo ROM: clear the screen,
+; then point I at each letter and draw it. Exercises CLS, LD, ADD, LD I and
+; DRW without any branches.
+
+    CLS
+    LD V0, 4
+    LD V1, 12
+    LD I, letter_c
+    DRW V0, V1, 7
+    ADD V0, 10
+    LD I, letter_h
+    DRW V0, V1, 7
+    ADD V0, 10
+    LD I, letter_i
+    DRW V0, V1, 7
+    ADD V0, 10
+    LD I, letter_p
+    DRW V0, V1, 7
+    ADD V0, 10
+    LD I, dash
+    DRW V0, V1, 7
+    ADD V0, 10
+    LD I, digit_8
+    DRW V0, V1, 7
+done:
+    JP done
+
+letter_c:
+    db 0b01111110, 0b11000011, 0b11000000, 0b11000000
+    db 0b11000000, 0b11000011, 0b01111110
+letter_h:
+    db 0b11000011, 0b11000011, 0b11000011, 0b11111111
+    db 0b11000011, 0b11000011, 0b11000011
+letter_i:
+    db 0b01111110, 0b00011000, 0b00011000, 0b00011000
+    db 0b00011000, 0b00011000, 0b01111110
+letter_p:
+    db 0b11111110, 0b11000011, 0b11000011, 0b11111110
+    db 0b11000000, 0b11000000, 0b11000000
+dash:
+    db 0b00000000, 0b00000000, 0b00000000, 0b01111110
+    db 0b00000000, 0b00000000, 0b00000000
+digit_8:
+    db 0b01111110, 0b11000011, 0b11000011, 0b01111110
+    db 0b11000011, 0b11000011, 0b01111110
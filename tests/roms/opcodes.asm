@@ -0,0 +1,220 @@
+; One check per instruction group, in the spirit of corax+'s opcode test.
+; Every check starts with VE = 1 and clears it on a wrong result; the grid
+; shows the test number and a tick or a cross (see report.asm).
+
+    LD V9, 0
+    LD VA, 0
+    LD VB, 0
+
+; 0: SE Vx, byte
+    LD VE, 1
+    LD V0, 0x42
+    SE V0, 0x42
+    LD VE, 0
+    LD V1, 0
+    SE V0, 0x41
+    LD V1, 1
+    SE V1, 1
+    LD VE, 0
+    CALL report
+
+; 1: SNE Vx, byte
+    LD VE, 1
+    LD V0, 0x42
+    SNE V0, 0x41
+    LD VE, 0
+    LD V1, 0
+    SNE V0, 0x42
+    LD V1, 1
+    SE V1, 1
+    LD VE, 0
+    CALL report
+
+; 2: SE Vx, Vy
+    LD VE, 1
+    LD V0, 0x17
+    LD V1, 0x17
+    LD V2, 0x18
+    SE V0, V1
+    LD VE, 0
+    LD V3, 0
+    SE V0, V2
+    LD V3, 1
+    SE V3, 1
+    LD VE, 0
+    CALL report
+
+; 3: SNE Vx, Vy
+    LD VE, 1
+    LD V0, 0x17
+    LD V1, 0x17
+    LD V2, 0x18
+    SNE V0, V2
+    LD VE, 0
+    LD V3, 0
+    SNE V0, V1
+    LD V3, 1
+    SE V3, 1
+    LD VE, 0
+    CALL report
+
+; 4: ADD Vx, byte wraps and leaves VF alone
+    LD VE, 1
+    LD VF, 7
+    LD V0, 0xFF
+    ADD V0, 2
+    SE V0, 1
+    LD VE, 0
+    SE VF, 7
+    LD VE, 0
+    CALL report
+
+; 5: LD, OR, AND, XOR Vx, Vy
+    LD VE, 1
+    LD V1, 0b01101100
+    LD V0, V1
+    SE V0, 0b01101100
+    LD VE, 0
+    LD V2, 0b11001110
+    OR V0, V2
+    SE V0, 0b11101110
+    LD VE, 0
+    AND V0, V1
+    SE V0, 0b01101100
+    LD VE, 0
+    XOR V0, V2
+    SE V0, 0b10100010
+    LD VE, 0
+    CALL report
+
+; 6: ADD Vx, Vy
+    LD VE, 1
+    LD V0, 100
+    LD V1, 10
+    ADD V0, V1
+    SE V0, 110
+    LD VE, 0
+    LD V1, 250
+    ADD V0, V1
+    SE V0, 104
+    LD VE, 0
+    CALL report
+
+; 7: SUB Vx, Vy
+    LD VE, 1
+    LD V0, 50
+    LD V1, 8
+    SUB V0, V1
+    SE V0, 42
+    LD VE, 0
+    LD V1, 43
+    SUB V0, V1
+    SE V0, 0xFF
+    LD VE, 0
+    CALL report
+
+; 8: SUBN Vx, Vy
+    LD VE, 1
+    LD V0, 8
+    LD V1, 50
+    SUBN V0, V1
+    SE V0, 42
+    LD VE, 0
+    LD V0, 51
+    SUBN V0, V1
+    SE V0, 0xFF
+    LD VE, 0
+    CALL report
+
+; 9: SHR Vx
+    LD VE, 1
+    LD V0, 0b10000011
+    SHR V0, V0
+    SE V0, 0b01000001
+    LD VE, 0
+    CALL report
+
+; 10: SHL Vx
+    LD VE, 1
+    LD V0, 0b10000011
+    SHL V0, V0
+    SE V0, 0b00000110
+    LD VE, 0
+    CALL report
+
+; 11: LD B, Vx then LD Vx, [I] and LD [I], Vx
+    LD VE, 1
+    LD V3, 234
+    LD I, scratch
+    LD B, V3
+    LD I, scratch
+    LD V2, [I]
+    SE V0, 2
+    LD VE, 0
+    SE V1, 3
+    LD VE, 0
+    SE V2, 4
+    LD VE, 0
+    LD V0, 9
+    LD I, scratch
+    LD [I], V0
+    LD I, scratch
+    LD V1, [I]
+    SE V0, 9
+    LD VE, 0
+    SE V1, 3
+    LD VE, 0
+    CALL report
+
+; 12: ADD I, Vx
+    LD VE, 1
+    LD I, scratch
+    LD V0, 2
+    ADD I, V0
+    LD V0, [I]
+    SE V0, 4
+    LD VE, 0
+    CALL report
+
+; 13: CALL and RET
+    LD VE, 1
+    LD V0, 0
+    CALL increment
+    CALL increment
+    SE V0, 2
+    LD VE, 0
+    CALL report
+
+; 14: JP V0, addr
+    LD VE, 1
+    LD V0, 2
+    JP V0, jump_table
+jump_table:
+    LD VE, 0
+    CALL report
+
+; 15: LD DT, Vx, LD Vx, DT and LD F, Vx
+    LD VE, 1
+    LD V0, 10
+    LD DT, V0
+    LD V1, DT
+    SE V1, 10
+    LD VE, 0
+    LD V0, 1
+    LD F, V0
+    LD V0, [I]
+    SE V0, 0x20
+    LD VE, 0
+    CALL report
+
+done:
+    JP done
+
+increment:
+    ADD V0, 1
+    RET
+
+scratch:
+    db 0, 0, 0
+
+include "report.asm"
@@ -0,0 +1,72 @@
+; Reports which quirks are in effect: a tick means the quirk's behaviour was
+; observed. Run it once per platform, see report.asm for the result grid.
+
+    LD V9, 0
+    LD VA, 0
+    LD VB, 0
+
+; 0: vblank-wait, two DRWs take two frames off the delay timer
+    LD V0, 1
+    LD DT, V0
+sync:
+    LD V1, DT
+    SE V1, 0
+    JP sync
+    LD V0, 3
+    LD DT, V0
+    LD I, empty
+    DRW V0, V0, 1
+    DRW V0, V0, 1
+    LD V1, DT
+    LD VE, 0
+    SE V1, 3
+    LD VE, 1
+    CALL report
+
+; 1: clip-sprites, a sprite at x = 60 does not reach x = 0
+    LD V0, 60
+    LD V1, 20
+    LD V2, 0
+    LD I, wide
+    DRW V0, V1, 1
+    LD I, dot
+    DRW V2, V1, 1
+    LD VE, 0
+    SE VF, 1
+    LD VE, 1
+    DRW V2, V1, 1
+    LD I, wide
+    DRW V0, V1, 1
+    CALL report
+
+; 2: shift-vy, SHR shifts Vy into Vx
+    LD V0, 1
+    LD V1, 4
+    SHR V0, V1
+    LD VE, 1
+    SE V0, 2
+    LD VE, 0
+    CALL report
+
+; 3: load-store-increment, a second LD V0, [I] reads the next byte
+    LD I, bytes
+    LD V0, [I]
+    LD V0, [I]
+    LD VE, 1
+    SE V0, 2
+    LD VE, 0
+    CALL report
+
+done:
+    JP done
+
+empty:
+    db 0
+wide:
+    db 0b11111111
+dot:
+    db 0b10000000
+bytes:
+    db 1, 2
+
+include "report.asm"
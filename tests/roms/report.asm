@@ -0,0 +1,37 @@
+; Shared result grid for the conformance ROMs, four results per row.
+;
+; Callers keep V9 = test number, VA/VB = grid position (start both at 0) and
+; set VE to 1 for a pass before calling report, which draws the test number
+; followed by a tick or a cross. VC, VD and VF are clobbered.
+
+report:
+    LD F, V9
+    DRW VA, VB, 5
+    LD VC, VA
+    ADD VC, 5
+    LD I, cross
+    SE VE, 1
+    JP report_draw
+    LD I, tick
+report_draw:
+    DRW VC, VB, 5
+    ADD V9, 1
+    ADD VA, 16
+    SE VA, 64
+    RET
+    LD VA, 0
+    ADD VB, 6
+    RET
+
+tick:
+    db 0b00000001
+    db 0b00000010
+    db 0b10000100
+    db 0b01001000
+    db 0b00110000
+cross:
+    db 0b10001000
+    db 0b01010000
+    db 0b00100000
+    db 0b01010000
+    db 0b10001000
@@ -0,0 +1,21 @@
+//! Runs the scenario scripts in `tests/scripts`. The scripting API is
+//! described in `src/script.rs`.
+
+use chip8::script;
+use std::fs;
+use std::path::PathBuf;
+
+#[test]
+fn scenarios() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rhai"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scripts in {}", dir.display());
+    for path in paths {
+        script::run_file(&path).unwrap_or_else(|e| panic!("{}", e));
+    }
+}